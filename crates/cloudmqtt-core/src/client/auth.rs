@@ -0,0 +1,79 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Enhanced authentication, as described in 4.12
+//!
+//! An [`Authenticator`] provides the authentication data of the CONNECT packet, answers the
+//! challenges the server sends in AUTH packets and checks the data the server sends once it
+//! accepted the authentication.
+
+#[cfg(feature = "scram")]
+mod scram;
+
+#[cfg(feature = "scram")]
+pub use self::scram::ScramSha256;
+
+/// One side of an enhanced authentication exchange
+pub trait Authenticator {
+    /// The authentication method, sent in the CONNECT and every AUTH packet
+    fn method(&self) -> &'static str;
+
+    /// Start authenticating, returning the data to send along with the CONNECT packet, or the
+    /// AUTH packet when re-authenticating
+    fn start(&mut self) -> Option<&[u8]>;
+
+    /// Answer a challenge of the server
+    fn challenge(&mut self, data: Option<&[u8]>) -> Result<Option<&[u8]>, AuthenticationError>;
+
+    /// Check the data the server sent along with its success
+    fn finish(&mut self, data: Option<&[u8]>) -> Result<(), AuthenticationError>;
+}
+
+/// Why an enhanced authentication exchange failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthenticationError {
+    /// The server sent an AUTH packet, but no authenticator was given to answer it
+    MissingAuthenticator,
+
+    /// The server used a different authentication method
+    MethodMismatch,
+
+    /// The server sent an AUTH packet while no authentication was going on
+    Unexpected,
+
+    /// The authentication data of the server could not be understood
+    InvalidServerData,
+
+    /// The server could not prove that it knows the credentials
+    ServerNotVerified,
+}
+
+impl core::fmt::Display for AuthenticationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AuthenticationError::MissingAuthenticator => {
+                write!(f, "No authenticator was given to answer the server")
+            }
+            AuthenticationError::MethodMismatch => {
+                write!(f, "The server used a different authentication method")
+            }
+            AuthenticationError::Unexpected => {
+                write!(f, "The server sent an unexpected AUTH packet")
+            }
+            AuthenticationError::InvalidServerData => {
+                write!(f, "The authentication data of the server is invalid")
+            }
+            AuthenticationError::ServerNotVerified => {
+                write!(
+                    f,
+                    "The server could not prove that it knows the credentials"
+                )
+            }
+        }
+    }
+}
+
+impl core::error::Error for AuthenticationError {}
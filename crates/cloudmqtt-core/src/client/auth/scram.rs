@@ -0,0 +1,293 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! The client side of SCRAM-SHA-256, as described in RFC 5802 and RFC 7677
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::Hmac;
+use hmac::Mac;
+use sha2::Digest;
+use sha2::Sha256;
+
+use super::AuthenticationError;
+use super::Authenticator;
+
+/// The GS2 header of a client that does not support channel binding
+const GS2_HEADER: &str = "n,,";
+
+/// Authenticate with a username and password through SCRAM-SHA-256
+///
+/// The password is used as is, it is not normalized with SASLprep.
+pub struct ScramSha256 {
+    username: String,
+    password: String,
+    client_nonce: Option<String>,
+    state: State,
+
+    /// The last message for the server, kept so that it can be borrowed
+    message: Vec<u8>,
+}
+
+enum State {
+    Initial,
+    ClientFirstSent {
+        client_nonce: String,
+        client_first_bare: String,
+    },
+    ClientFinalSent {
+        server_key: [u8; 32],
+        auth_message: String,
+    },
+    Done,
+}
+
+impl ScramSha256 {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+            client_nonce: None,
+            state: State::Initial,
+            message: Vec::new(),
+        }
+    }
+
+    /// Always use the given nonce instead of a random one
+    ///
+    /// A nonce must not be reused, so this is only useful for testing.
+    pub fn with_client_nonce(mut self, client_nonce: impl Into<String>) -> Self {
+        self.client_nonce = Some(client_nonce.into());
+        self
+    }
+
+    fn generate_client_nonce(&self) -> String {
+        if let Some(client_nonce) = &self.client_nonce {
+            return client_nonce.clone();
+        }
+
+        let mut random = [0; 18];
+        getrandom::fill(&mut random).expect("No source of randomness available for the nonce");
+        BASE64.encode(random)
+    }
+
+    fn client_final(&mut self, server_first: &[u8]) -> Result<(), AuthenticationError> {
+        let State::ClientFirstSent {
+            client_nonce,
+            client_first_bare,
+        } = &self.state
+        else {
+            return Err(AuthenticationError::Unexpected);
+        };
+
+        let server_first = core::str::from_utf8(server_first)
+            .map_err(|_| AuthenticationError::InvalidServerData)?;
+        let ServerFirst {
+            nonce,
+            salt,
+            iterations,
+        } = ServerFirst::parse(server_first)?;
+
+        if !nonce.starts_with(client_nonce.as_str()) || nonce.len() == client_nonce.len() {
+            return Err(AuthenticationError::InvalidServerData);
+        }
+
+        let mut salted_password = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(
+            self.password.as_bytes(),
+            &salt,
+            iterations,
+            &mut salted_password,
+        );
+
+        let client_key = hmac(&salted_password, b"Client Key");
+        let stored_key: [u8; 32] = Sha256::digest(client_key).into();
+        let server_key = hmac(&salted_password, b"Server Key");
+
+        let client_final_without_proof = format!("c={},r={nonce}", BASE64.encode(GS2_HEADER));
+        let auth_message =
+            format!("{client_first_bare},{server_first},{client_final_without_proof}");
+
+        let client_signature = hmac(&stored_key, auth_message.as_bytes());
+        let client_proof: Vec<u8> = client_key
+            .iter()
+            .zip(client_signature)
+            .map(|(key, signature)| key ^ signature)
+            .collect();
+
+        self.message = format!(
+            "{client_final_without_proof},p={}",
+            BASE64.encode(client_proof)
+        )
+        .into_bytes();
+        self.state = State::ClientFinalSent {
+            server_key,
+            auth_message,
+        };
+
+        Ok(())
+    }
+}
+
+impl Authenticator for ScramSha256 {
+    fn method(&self) -> &'static str {
+        "SCRAM-SHA-256"
+    }
+
+    fn start(&mut self) -> Option<&[u8]> {
+        let client_nonce = self.generate_client_nonce();
+        let client_first_bare = format!("n={},r={client_nonce}", sasl_name(&self.username));
+
+        self.message = format!("{GS2_HEADER}{client_first_bare}").into_bytes();
+        self.state = State::ClientFirstSent {
+            client_nonce,
+            client_first_bare,
+        };
+
+        Some(&self.message)
+    }
+
+    fn challenge(&mut self, data: Option<&[u8]>) -> Result<Option<&[u8]>, AuthenticationError> {
+        let server_first = data.ok_or(AuthenticationError::InvalidServerData)?;
+
+        if let Err(error) = self.client_final(server_first) {
+            self.state = State::Done;
+            return Err(error);
+        }
+
+        Ok(Some(&self.message))
+    }
+
+    fn finish(&mut self, data: Option<&[u8]>) -> Result<(), AuthenticationError> {
+        let State::ClientFinalSent {
+            server_key,
+            auth_message,
+        } = core::mem::replace(&mut self.state, State::Done)
+        else {
+            return Err(AuthenticationError::Unexpected);
+        };
+
+        let server_final = data.ok_or(AuthenticationError::ServerNotVerified)?;
+        let server_signature = server_final
+            .strip_prefix(b"v=")
+            .and_then(|signature| BASE64.decode(signature).ok())
+            .ok_or(AuthenticationError::ServerNotVerified)?;
+
+        let mut mac =
+            <Hmac<Sha256>>::new_from_slice(&server_key).expect("HMAC accepts keys of any length");
+        mac.update(auth_message.as_bytes());
+        mac.verify_slice(&server_signature)
+            .map_err(|_| AuthenticationError::ServerNotVerified)
+    }
+}
+
+struct ServerFirst<'s> {
+    nonce: &'s str,
+    salt: Vec<u8>,
+    iterations: u32,
+}
+
+impl<'s> ServerFirst<'s> {
+    fn parse(server_first: &'s str) -> Result<Self, AuthenticationError> {
+        let mut nonce = None;
+        let mut salt = None;
+        let mut iterations = None;
+
+        for attribute in server_first.split(',') {
+            match attribute.split_once('=') {
+                Some(("r", value)) => nonce = Some(value),
+                Some(("s", value)) => salt = BASE64.decode(value).ok(),
+                Some(("i", value)) => iterations = value.parse().ok(),
+                // Mandatory extensions are not supported
+                Some(("m", _)) => return Err(AuthenticationError::InvalidServerData),
+                _ => {}
+            }
+        }
+
+        match (nonce, salt, iterations) {
+            (Some(nonce), Some(salt), Some(iterations)) if iterations > 0 => Ok(ServerFirst {
+                nonce,
+                salt,
+                iterations,
+            }),
+            _ => Err(AuthenticationError::InvalidServerData),
+        }
+    }
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256>>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// Escape the characters that have a meaning in SCRAM messages
+fn sasl_name(name: &str) -> String {
+    name.replace('=', "=3D").replace(',', "=2C")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ScramSha256;
+    use crate::client::auth::AuthenticationError;
+    use crate::client::auth::Authenticator;
+
+    // The example exchange of RFC 7677
+    const CLIENT_NONCE: &str = "rOprNGfwEbeRWgbNEkqO";
+    const SERVER_FIRST: &[u8] =
+        b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+    const CLIENT_FINAL: &[u8] = b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+    const SERVER_FINAL: &[u8] = b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+
+    #[test]
+    fn check_rfc_7677_exchange() {
+        let mut scram = ScramSha256::new("user", "pencil").with_client_nonce(CLIENT_NONCE);
+
+        assert_eq!(
+            scram.start(),
+            Some(&b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO"[..])
+        );
+        assert_eq!(scram.challenge(Some(SERVER_FIRST)), Ok(Some(CLIENT_FINAL)));
+        assert_eq!(scram.finish(Some(SERVER_FINAL)), Ok(()));
+    }
+
+    #[test]
+    fn check_wrong_server_signature() {
+        let mut scram = ScramSha256::new("user", "pencil").with_client_nonce(CLIENT_NONCE);
+
+        scram.start();
+        scram.challenge(Some(SERVER_FIRST)).unwrap();
+        assert_eq!(
+            scram.finish(Some(b"v=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=")),
+            Err(AuthenticationError::ServerNotVerified)
+        );
+    }
+
+    #[test]
+    fn check_server_nonce_must_extend_client_nonce() {
+        let mut scram = ScramSha256::new("user", "pencil").with_client_nonce(CLIENT_NONCE);
+
+        scram.start();
+        assert_eq!(
+            scram.challenge(Some(b"r=someoneelse,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096")),
+            Err(AuthenticationError::InvalidServerData)
+        );
+    }
+
+    #[test]
+    fn check_random_nonce() {
+        let mut first = ScramSha256::new("us=er,name", "pencil");
+        let first = first.start().unwrap().to_vec();
+        let mut second = ScramSha256::new("us=er,name", "pencil");
+
+        assert!(first.starts_with(b"n,,n=us=3Der=2Cname,r="));
+        assert_ne!(Some(&first[..]), second.start());
+    }
+}
@@ -6,47 +6,142 @@
 
 use core::hash::Hash;
 use core::hash::Hasher;
+use core::ops::Add;
 use core::ops::Not;
+use core::time::Duration;
 
 use mqtt_format::v5::packets::MqttPacket;
 use mqtt_format::v5::packets::connack::ConnackReasonCode;
 use mqtt_format::v5::packets::connect::MConnect;
+use mqtt_format::v5::packets::puback::PubackReasonCode;
+use mqtt_format::v5::packets::pubrec::PubrecReasonCode;
 use mqtt_format::v5::qos::QualityOfService;
+use mqtt_format::v5::variable_header::AuthenticationData;
+use mqtt_format::v5::variable_header::AuthenticationMethod;
+use mqtt_format::v5::variable_header::ReceiveMaximum;
 use rustc_hash::FxHasher;
 
 use crate::util::trace;
 
+mod auth;
+pub use self::auth::AuthenticationError;
+pub use self::auth::Authenticator;
+#[cfg(feature = "scram")]
+pub use self::auth::ScramSha256;
+
 mod packet_identifier_store;
+pub use self::packet_identifier_store::ArrayReceivedPacketIdentifierStore;
+pub use self::packet_identifier_store::BitmapPacketIdentifierStore;
 pub use self::packet_identifier_store::PacketIdentifierStore;
 pub use self::packet_identifier_store::PacketIdentifierUsage;
+pub use self::packet_identifier_store::ReceivedPacketIdentifierStore;
+pub use self::packet_identifier_store::ReceivedPacketIdentifierStoreFull;
 pub use self::packet_identifier_store::UsizePacketIdentifierStore;
+#[cfg(feature = "alloc")]
+pub use self::packet_identifier_store::VecPacketIdentifierStore;
+#[cfg(feature = "alloc")]
+pub use self::packet_identifier_store::VecReceivedPacketIdentifierStore;
+pub use self::packet_identifier_store::bitmap_words;
+
+/// The default store for identifiers of incoming QoS 2 publishes
+pub type DefaultReceivedPacketIdentifierStore = ArrayReceivedPacketIdentifierStore<64>;
 
 #[derive(Debug)]
-pub struct MqttClientFSM<ClientPacketIdentifierStore = UsizePacketIdentifierStore> {
+pub struct MqttClientFSM<
+    ClientPacketIdentifierStore = UsizePacketIdentifierStore,
+    ServerPacketIdentifierStore = DefaultReceivedPacketIdentifierStore,
+> {
     data: ClientData,
     connection_state: ConnectionState,
     client_pis: ClientPacketIdentifierStore,
+    server_pis: ServerPacketIdentifierStore,
 }
 
-impl MqttClientFSM {
+impl<CPIS, SPIS> MqttClientFSM<CPIS, SPIS> {
     pub fn is_connected(&self) -> bool {
         self.connection_state.is_connected()
     }
+
+    /// The keep alive interval in seconds, as overridden by the server if it did so
+    ///
+    /// While it is non-zero, [`run`](Self::run) has to be called by the
+    /// [`next_deadline`](Self::next_deadline) so that pings are sent and a connection whose server
+    /// stopped answering them is dropped.
+    pub fn keep_alive(&self) -> u16 {
+        self.data.keep_alive
+    }
+
+    /// When [`run`](Self::run) has to be called next, if anything is timed
+    ///
+    /// While connected with a non-zero keep alive, this is when a ping is due or when the server
+    /// took too long to answer one. Sending a packet moves the deadline further out, so it should
+    /// be asked for again after every call into the state machine.
+    pub fn next_deadline(&self) -> Option<MqttInstant> {
+        let ConnectionState::Connected(con) = &self.connection_state else {
+            return None;
+        };
+        if self.data.keep_alive == 0 {
+            return None;
+        }
+
+        let keep_alive = Duration::from_secs(u64::from(self.data.keep_alive));
+        Some(match con.ping_state {
+            PingState::WaitingForElapsed => con.last_time_sent + keep_alive,
+            PingState::WaitingForPingrespSince(since) => since + keep_alive,
+        })
+    }
+
+    /// The limits the server announced in its CONNACK, if connected
+    pub fn server_limits(&self) -> Option<&ServerLimits> {
+        match &self.connection_state {
+            ConnectionState::Connected(con) => Some(&con.server_limits),
+            _ => None,
+        }
+    }
+
+    /// Whether as many QoS 1 and 2 publishes are in flight as the server's receive maximum allows
+    ///
+    /// Until one of them is acknowledged, [`publish`](Self::publish) refuses further ones with
+    /// [`PublishError::ReceiveMaximumExceeded`], so they should be held back in the meantime.
+    pub fn receive_maximum_reached(&self) -> bool {
+        self.server_limits()
+            .is_some_and(|limits| self.data.in_flight_publishes >= limits.receive_maximum())
+    }
 }
 
 #[must_use = "Without being run, this will drop the incoming packet"]
-pub struct MqttClientConsumer<'c, 'p, CPIS> {
-    client: &'c mut MqttClientFSM<CPIS>,
+pub struct MqttClientConsumer<'c, 'p, CPIS, SPIS> {
+    client: &'c mut MqttClientFSM<CPIS, SPIS>,
     packet: MqttPacket<'p>,
 }
 
-impl<'p, CPIS> MqttClientConsumer<'_, 'p, CPIS>
+impl<'p, CPIS, SPIS> MqttClientConsumer<'_, 'p, CPIS, SPIS>
 where
     CPIS: PacketIdentifierStore,
+    SPIS: ReceivedPacketIdentifierStore,
 {
     pub fn run(self, current_time: MqttInstant) -> Option<ExpectedAction<'p>> {
-        self.client
-            .inner_run(current_time, ExternalInfos::ConsumePacket(self.packet))
+        self.client.inner_run(
+            current_time,
+            ExternalInfos::ConsumePacket(self.packet),
+            None,
+        )
+    }
+
+    /// Run, answering AUTH packets of the server with the given authenticator
+    ///
+    /// This is needed for every packet consumed while connecting with enhanced authentication
+    /// or re-authenticating.
+    pub fn run_with_authenticator(
+        self,
+        current_time: MqttInstant,
+        authenticator: &'p mut dyn Authenticator,
+    ) -> Option<ExpectedAction<'p>> {
+        self.client.inner_run(
+            current_time,
+            ExternalInfos::ConsumePacket(self.packet),
+            Some(authenticator),
+        )
     }
 }
 
@@ -58,14 +153,15 @@ enum DisconnectState<'p> {
 }
 
 #[must_use = "Without being run, this will not disconnect properly"]
-pub struct MqttClientDisconnecter<'c, 'p, CPIS> {
-    client: &'c mut MqttClientFSM<CPIS>,
+pub struct MqttClientDisconnecter<'c, 'p, CPIS, SPIS> {
+    client: &'c mut MqttClientFSM<CPIS, SPIS>,
     state: DisconnectState<'p>,
 }
 
-impl<'p, CPIS> MqttClientDisconnecter<'_, 'p, CPIS>
+impl<'p, CPIS, SPIS> MqttClientDisconnecter<'_, 'p, CPIS, SPIS>
 where
     CPIS: PacketIdentifierStore,
+    SPIS: ReceivedPacketIdentifierStore,
 {
     pub fn run(&mut self, _current_time: MqttInstant) -> Option<ExpectedAction<'p>> {
         // TODO: Do something about time?
@@ -96,41 +192,30 @@ enum PublishingState {
 }
 
 #[must_use = "Without being run, this will drop the publishing packet"]
-pub struct MqttClientPublisher<'c, 'p, CPIS> {
-    client: &'c mut MqttClientFSM<CPIS>,
+pub struct MqttClientPublisher<'c, 'p, CPIS, SPIS> {
+    client: &'c mut MqttClientFSM<CPIS, SPIS>,
     packet: Option<mqtt_format::v5::packets::publish::MPublish<'p>>,
     state: PublishingState,
 }
 
-impl<'p, CPIS> MqttClientPublisher<'_, 'p, CPIS>
+impl<'p, CPIS, SPIS> MqttClientPublisher<'_, 'p, CPIS, SPIS>
 where
     CPIS: PacketIdentifierStore,
+    SPIS: ReceivedPacketIdentifierStore,
 {
     pub fn run(&mut self, current_time: MqttInstant) -> Option<ExpectedAction<'p>> {
         match &mut self.state {
             PublishingState::Store => {
-                if let Some(packet) = &mut self.packet {
-                    if packet.quality_of_service != QualityOfService::AtMostOnce {
-                        let id = self
-                            .client
-                            .client_pis
-                            .get_next_free(PacketIdentifierUsage::Publish)
-                            .unwrap();
-                        packet.packet_identifier = Some(id);
-
-                        self.state = PublishingState::Send;
-                        return Some(ExpectedAction::StorePacket {
-                            id: packet.packet_identifier.unwrap(),
-                        });
-                    }
-                }
-                None
+                self.state = PublishingState::Send;
+                let id = self.packet.as_ref()?.packet_identifier?;
+                Some(ExpectedAction::StorePacket { id })
             }
             PublishingState::Send => {
                 self.state = PublishingState::Done;
                 self.client.inner_run(
                     current_time,
                     ExternalInfos::PublishPacket(self.packet.take().map(Into::into).unwrap()),
+                    None,
                 )
             }
             PublishingState::Done => None,
@@ -138,9 +223,12 @@ pub fn run(&mut self, current_time: MqttInstant) -> Option<ExpectedAction<'p>> {
     }
 }
 
-impl Default for MqttClientFSM<UsizePacketIdentifierStore> {
+impl Default for MqttClientFSM<UsizePacketIdentifierStore, DefaultReceivedPacketIdentifierStore> {
     fn default() -> Self {
-        Self::new(UsizePacketIdentifierStore::default())
+        Self::new(
+            UsizePacketIdentifierStore::default(),
+            DefaultReceivedPacketIdentifierStore::default(),
+        )
     }
 }
 
@@ -150,27 +238,46 @@ pub enum ExternalInfos<'p> {
     None,
 }
 
-impl<CPIS> MqttClientFSM<CPIS>
+impl<CPIS, SPIS> MqttClientFSM<CPIS, SPIS>
 where
     CPIS: PacketIdentifierStore,
+    SPIS: ReceivedPacketIdentifierStore,
 {
-    pub const fn new(client_pis: CPIS) -> MqttClientFSM<CPIS> {
+    pub const fn new(client_pis: CPIS, server_pis: SPIS) -> MqttClientFSM<CPIS, SPIS> {
         MqttClientFSM {
-            data: ClientData::const_new(0, None, MqttInstant::new(0)),
+            data: ClientData::const_new(0, None, MqttInstant::from_secs(0)),
             connection_state: ConnectionState::Disconnected,
             client_pis,
+            server_pis,
         }
     }
 
+    /// Start connecting with the given CONNECT
+    ///
+    /// Fails if the client is not disconnected, or if the session belongs to another client
+    /// identifier.
     pub fn handle_connect<'c>(
         &'_ mut self,
         current_time: MqttInstant,
-        connect: MConnect<'c>,
-    ) -> ExpectedAction<'c> {
-        assert!(matches!(
-            self.connection_state,
-            ConnectionState::Disconnected
-        ));
+        mut connect: MConnect<'c>,
+    ) -> Result<ExpectedAction<'c>, ConnectError> {
+        if !matches!(self.connection_state, ConnectionState::Disconnected) {
+            return Err(ConnectError::NotDisconnected);
+        }
+
+        let client_id_hash = connect.client_identifier.is_empty().not().then(|| {
+            let mut hasher = FxHasher::default();
+            connect.client_identifier.hash(&mut hasher);
+            hasher.finish()
+        });
+
+        match self.data.client_id_hash {
+            Some(id) if client_id_hash != Some(id) => {
+                return Err(ConnectError::ClientIdentifierChanged);
+            }
+            Some(_) => {}
+            None => self.data.client_id_hash = client_id_hash,
+        }
 
         if connect.clean_start {
             // TOOD: Handle cleaning of session
@@ -178,47 +285,117 @@ pub fn handle_connect<'c>(
 
         self.data.keep_alive = connect.keep_alive;
 
-        // TODO: Check the Receive Maximum property being sent, and make sure its correct
+        // The server may send as many QoS 2 publishes at once as we allow, each of them has to fit
+        // into the store
+        let requested_receive_maximum = connect
+            .properties
+            .receive_maximum()
+            .map_or(u16::MAX, |receive_maximum| receive_maximum.0.get());
+        let receive_maximum = requested_receive_maximum.min(self.server_pis.capacity().max(1));
+        if receive_maximum < requested_receive_maximum {
+            trace!(
+                requested_receive_maximum,
+                receive_maximum, "Capping the receive maximum to the received identifier store"
+            );
+            connect.properties.receive_maximum =
+                core::num::NonZeroU16::new(receive_maximum).map(ReceiveMaximum);
+        }
+        self.data.receive_maximum = receive_maximum;
 
         // TODO: Check the Maximum Packet Size property
 
         // TODO: Check Authentication Method is actually as advertised
 
-        let client_id_hash = connect.client_identifier.is_empty().not().then(|| {
-            let mut hasher = FxHasher::default();
-            connect.client_identifier.hash(&mut hasher);
-            hasher.finish()
-        });
-
-        if let Some(id) = self.data.client_id_hash {
-            assert!(client_id_hash.is_some());
-            assert!(client_id_hash.unwrap() == id);
+        self.connection_state = if connect.properties.authentication_method.is_some() {
+            ConnectionState::ConnectingWithAuth(ConnectingWithAuth {
+                connect_sent: current_time,
+            })
         } else {
-            self.data.client_id_hash = client_id_hash;
+            ConnectionState::ConnectingWithoutAuth(ConnectingWithoutAuth {
+                connect_sent: current_time,
+            })
+        };
+
+        Ok(ExpectedAction::SendPacket(connect.into()))
+    }
+
+    /// Connect using enhanced authentication
+    ///
+    /// The authentication method and initial data of the CONNECT packet are taken from the
+    /// authenticator. Packets have to be consumed through
+    /// [`MqttClientConsumer::run_with_authenticator`] until the server accepted the connection.
+    pub fn handle_connect_with_authenticator<'c>(
+        &'_ mut self,
+        current_time: MqttInstant,
+        mut connect: MConnect<'c>,
+        authenticator: &'c mut dyn Authenticator,
+    ) -> Result<ExpectedAction<'c>, ConnectError> {
+        connect.properties.authentication_method =
+            Some(AuthenticationMethod(authenticator.method()));
+        connect.properties.authentication_data = authenticator.start().map(AuthenticationData);
+
+        self.handle_connect(current_time, connect)
+    }
+
+    /// Start re-authenticating the current connection (4.12.1)
+    ///
+    /// The authenticator has to use the method the connection was established with. Returns
+    /// `None` if not connected or if a re-authentication is already going on.
+    pub fn reauthenticate<'a>(
+        &mut self,
+        current_time: MqttInstant,
+        authenticator: &'a mut dyn Authenticator,
+    ) -> Option<ExpectedAction<'a>> {
+        let ConnectionState::Connected(con) = &mut self.connection_state else {
+            return None;
+        };
+
+        if con.reauthenticating {
+            return None;
         }
 
-        self.connection_state = ConnectionState::ConnectingWithoutAuth(ConnectingWithoutAuth {
-            connect_sent: current_time,
-        });
+        con.reauthenticating = true;
+        con.last_time_sent = current_time;
 
-        ExpectedAction::SendPacket(connect.into())
+        let method = authenticator.method();
+        Some(ExpectedAction::SendPacket(auth_packet(
+            mqtt_format::v5::packets::auth::AuthReasonCode::ReAuthenticate,
+            method,
+            authenticator.start(),
+        )))
     }
 
     pub fn consume<'c, 'p>(
         &'c mut self,
         packet: MqttPacket<'p>,
-    ) -> MqttClientConsumer<'c, 'p, CPIS> {
+    ) -> MqttClientConsumer<'c, 'p, CPIS, SPIS> {
         MqttClientConsumer {
             client: self,
             packet,
         }
     }
 
+    /// Publish a packet, if it stays within the limits the server announced
+    ///
+    /// While not connected the limits of the server are unknown, and no publish is refused.
     pub fn publish<'c, 'p>(
         &'c mut self,
-        packet: mqtt_format::v5::packets::publish::MPublish<'p>,
-    ) -> MqttClientPublisher<'c, 'p, CPIS> {
-        MqttClientPublisher {
+        mut packet: mqtt_format::v5::packets::publish::MPublish<'p>,
+    ) -> Result<MqttClientPublisher<'c, 'p, CPIS, SPIS>, PublishError> {
+        if let Some(limits) = self.server_limits() {
+            limits.check_publish(&packet, self.data.in_flight_publishes)?;
+        }
+
+        if packet.quality_of_service != QualityOfService::AtMostOnce {
+            let id = self
+                .client_pis
+                .get_next_free(PacketIdentifierUsage::Publish)
+                .ok_or(PublishError::PacketIdentifiersExhausted)?;
+            packet.packet_identifier = Some(id);
+            self.data.in_flight_publishes += 1;
+        }
+
+        Ok(MqttClientPublisher {
             client: self,
             state: if packet.quality_of_service == QualityOfService::AtMostOnce {
                 PublishingState::Send
@@ -226,46 +403,95 @@ pub fn publish<'c, 'p>(
                 PublishingState::Store
             },
             packet: Some(packet),
-        }
+        })
     }
 
     pub fn subscribe<'p>(
         &mut self,
         current_time: MqttInstant,
         mut packet: mqtt_format::v5::packets::subscribe::MSubscribe<'p>,
-    ) -> ExpectedAction<'p> {
-        packet.packet_identifier = self
-            .client_pis
-            .get_next_free(PacketIdentifierUsage::NonPublish)
-            .expect("could not get a free packet identifier");
+    ) -> Result<ExpectedAction<'p>, RequestError> {
+        packet.packet_identifier = self.next_request_identifier()?;
+
+        Ok(ExpectedAction::SendPacket(
+            self.send_on_connection(current_time, packet.into()),
+        ))
+    }
+
+    pub fn unsubscribe<'p>(
+        &mut self,
+        current_time: MqttInstant,
+        mut packet: mqtt_format::v5::packets::unsubscribe::MUnsubscribe<'p>,
+    ) -> Result<ExpectedAction<'p>, RequestError> {
+        packet.packet_identifier = self.next_request_identifier()?;
 
-        self.inner_run(current_time, ExternalInfos::PublishPacket(packet.into()))
-            .expect("inner_run did not return packet as expected")
+        Ok(ExpectedAction::SendPacket(
+            self.send_on_connection(current_time, packet.into()),
+        ))
     }
 
+    /// Acknowledge an incoming publish
+    ///
+    /// Returns `None` if the connection the publish arrived on was lost. The server then sends
+    /// the publish again.
     pub fn acknowledge<'p>(
         &mut self,
         current_time: MqttInstant,
-        AcknowledgeAction(packet_identifier): AcknowledgeAction,
-    ) -> ExpectedAction<'p> {
-        self.inner_run(
-            current_time,
-            ExternalInfos::PublishPacket(
+        acknowledge: AcknowledgeAction,
+    ) -> Option<ExpectedAction<'p>> {
+        self.acknowledge_with_reason(current_time, acknowledge, PubackReasonCode::Success)
+    }
+
+    /// Acknowledge an incoming publish with the given reason
+    ///
+    /// An error reason tells the server that the publish was not accepted. For a QoS 2 publish
+    /// this ends the exchange, the server does not release it and may reuse its identifier.
+    pub fn acknowledge_with_reason<'p>(
+        &mut self,
+        current_time: MqttInstant,
+        acknowledge: AcknowledgeAction,
+        reason: PubackReasonCode,
+    ) -> Option<ExpectedAction<'p>> {
+        if !self.is_connected() {
+            trace!(id = ?acknowledge.packet_identifier, "Not acknowledging publish of a lost connection");
+            return None;
+        }
+
+        self.data.unacknowledged_publishes = self.data.unacknowledged_publishes.saturating_sub(1);
+
+        let packet = match acknowledge.quality_of_service {
+            AcknowledgedQualityOfService::AtLeastOnce => {
                 mqtt_format::v5::packets::puback::MPuback {
-                    packet_identifier,
-                    reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
+                    packet_identifier: acknowledge.packet_identifier,
+                    reason,
                     properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
                 }
-                .into(),
-            ),
-        )
-        .expect("inner_run did not return packet as expected")
+                .into()
+            }
+            AcknowledgedQualityOfService::ExactlyOnce => {
+                if u8::from(reason) >= 0x80 {
+                    self.server_pis.remove(acknowledge.packet_identifier);
+                }
+
+                mqtt_format::v5::packets::pubrec::MPubrec {
+                    packet_identifier: acknowledge.packet_identifier,
+                    reason: PubrecReasonCode::try_from(u8::from(reason))
+                        .expect("Every PUBACK reason code is a PUBREC reason code"),
+                    properties: mqtt_format::v5::packets::pubrec::PubrecProperties::new(),
+                }
+                .into()
+            }
+        };
+
+        Some(ExpectedAction::SendPacket(
+            self.send_on_connection(current_time, packet),
+        ))
     }
 
     pub fn disconnect<'p>(
         &mut self,
         disconnect: mqtt_format::v5::packets::disconnect::MDisconnect<'p>,
-    ) -> MqttClientDisconnecter<'_, 'p, CPIS> {
+    ) -> MqttClientDisconnecter<'_, 'p, CPIS, SPIS> {
         MqttClientDisconnecter {
             client: self,
             state: DisconnectState::DisconnectPacket(disconnect.into()),
@@ -277,13 +503,117 @@ pub fn connection_lost(&mut self, current_time: MqttInstant) {
         self.reset_connection();
     }
 
+    /// Take over an outgoing QoS 1 or 2 publish from a persisted session
+    ///
+    /// Its identifier stays in use until the server acknowledged it. Returns whether the
+    /// identifier could be claimed.
+    pub fn restore_publish(
+        &mut self,
+        id: mqtt_format::v5::variable_header::PacketIdentifier,
+    ) -> bool {
+        if !self.client_pis.claim(id, PacketIdentifierUsage::Publish) {
+            trace!(?id, "Could not restore publish");
+            return false;
+        }
+
+        self.data.in_flight_publishes += 1;
+        true
+    }
+
+    /// Give up on an outgoing QoS 1 or 2 publish, as the server did not keep its session
+    ///
+    /// Its identifier can be used again right away. Returns whether the identifier was in use.
+    pub fn forget_publish(
+        &mut self,
+        id: mqtt_format::v5::variable_header::PacketIdentifier,
+    ) -> bool {
+        if !self.client_pis.contains(id) {
+            return false;
+        }
+
+        self.release_publish(id);
+        true
+    }
+
+    /// Take over the identifier of an incoming QoS 2 publish from a persisted session
+    ///
+    /// A re-delivery of the publish is then acknowledged without handing it out again.
+    pub fn restore_received(
+        &mut self,
+        id: mqtt_format::v5::variable_header::PacketIdentifier,
+    ) -> Result<bool, ReceivedPacketIdentifierStoreFull> {
+        self.server_pis.insert(id)
+    }
+
+    /// Forget an incoming QoS 2 publish that was not acknowledged
+    ///
+    /// Should the server send it again, it is handed out again instead of being recognized as a
+    /// duplicate. Returns whether the identifier was known.
+    pub fn forget_received(
+        &mut self,
+        id: mqtt_format::v5::variable_header::PacketIdentifier,
+    ) -> bool {
+        self.server_pis.remove(id)
+    }
+
+    /// A packet identifier for a SUBSCRIBE or UNSUBSCRIBE
+    fn next_request_identifier(
+        &mut self,
+    ) -> Result<mqtt_format::v5::variable_header::PacketIdentifier, RequestError> {
+        if !self.is_connected() {
+            return Err(RequestError::NotConnected);
+        }
+
+        self.client_pis
+            .get_next_free(PacketIdentifierUsage::NonPublish)
+            .ok_or(RequestError::PacketIdentifiersExhausted)
+    }
+
+    /// Send a packet on the current connection, which has to be established
+    fn send_on_connection<'p>(
+        &mut self,
+        current_time: MqttInstant,
+        packet: MqttPacket<'p>,
+    ) -> MqttPacket<'p> {
+        if let ConnectionState::Connected(con) = &mut self.connection_state {
+            con.last_time_sent = current_time;
+        }
+        self.data.last_time_run = current_time;
+
+        packet
+    }
+
+    fn release_publish(&mut self, id: mqtt_format::v5::variable_header::PacketIdentifier) {
+        self.client_pis.release(id);
+        self.data.in_flight_publishes = self.data.in_flight_publishes.saturating_sub(1);
+    }
+
     fn reset_connection(&mut self) {
         self.client_pis.release_non_publish_slots();
         self.connection_state = ConnectionState::Disconnected;
+        self.data.unacknowledged_publishes = 0;
+    }
+
+    /// Close the connection, as the server sent more unacknowledged publishes than we allow
+    fn receive_maximum_exceeded(&mut self) -> ExpectedAction<'static> {
+        trace!(
+            receive_maximum = self.data.receive_maximum,
+            "Server exceeded the receive maximum"
+        );
+
+        self.protocol_violation(ProtocolViolation::ReceiveMaximumExceeded)
+    }
+
+    /// Close the connection, as the server violated the protocol
+    fn protocol_violation(&mut self, violation: ProtocolViolation) -> ExpectedAction<'static> {
+        trace!(?violation, "Server violated the protocol");
+        self.reset_connection();
+
+        ExpectedAction::ProtocolViolation(violation)
     }
 
     pub fn run(&mut self, current_time: MqttInstant) -> Option<ExpectedAction<'static>> {
-        self.inner_run(current_time, ExternalInfos::None)
+        self.inner_run(current_time, ExternalInfos::None, None)
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(
@@ -297,6 +627,7 @@ fn inner_run<'p>(
         &mut self,
         current_time: MqttInstant,
         external_infos: ExternalInfos<'p>,
+        authenticator: Option<&'p mut dyn Authenticator>,
     ) -> Option<ExpectedAction<'p>> {
         trace!("Doing one state machine step");
         let action = match &self.connection_state {
@@ -304,11 +635,14 @@ fn inner_run<'p>(
                 self.data.last_time_run = current_time;
                 None
             }
-            ConnectionState::ConnectingWithoutAuth { .. } => {
-                self.handle_connecting_without_auth(current_time, external_infos)
+            ConnectionState::ConnectingWithoutAuth(ConnectingWithoutAuth { connect_sent }) => {
+                self.handle_connecting_without_auth(*connect_sent, external_infos)
+            }
+            ConnectionState::ConnectingWithAuth(ConnectingWithAuth { connect_sent }) => {
+                self.handle_connecting_with_auth(*connect_sent, external_infos, authenticator)
             }
             ConnectionState::Connected { .. } => {
-                self.handle_connected(current_time, external_infos)
+                self.handle_connected(current_time, external_infos, authenticator)
             }
         };
 
@@ -321,6 +655,7 @@ fn handle_connected<'p>(
         &mut self,
         current_time: MqttInstant,
         external_infos: ExternalInfos<'p>,
+        authenticator: Option<&'p mut dyn Authenticator>,
     ) -> Option<ExpectedAction<'p>> {
         let ConnectionState::Connected(con) = &mut self.connection_state else {
             unreachable!()
@@ -346,50 +681,234 @@ fn handle_connected<'p>(
                             ));
                         }
                         QualityOfService::AtLeastOnce => {
+                            if self.data.unacknowledged_publishes >= self.data.receive_maximum {
+                                return Some(self.receive_maximum_exceeded());
+                            }
+                            self.data.unacknowledged_publishes += 1;
+
+                            let Some(packet_identifier) = packet_identifier else {
+                                return Some(self.protocol_violation(
+                                    ProtocolViolation::MissingPacketIdentifier,
+                                ));
+                            };
+
                             return Some(ExpectedAction::ReceivePacket(
                                 ReceivePacket::AcknowledgeNeeded {
-                                    // TODO: Dont unwrap()
-                                    acknowledge: AcknowledgeAction(packet_identifier.unwrap()),
+                                    acknowledge: AcknowledgeAction {
+                                        packet_identifier,
+                                        quality_of_service:
+                                            AcknowledgedQualityOfService::AtLeastOnce,
+                                    },
                                     packet: MqttPacket::Publish(publish),
                                 },
                             ));
                         }
-                        QualityOfService::ExactlyOnce => todo!(),
+                        QualityOfService::ExactlyOnce => {
+                            let Some(packet_identifier) = packet_identifier else {
+                                return Some(self.protocol_violation(
+                                    ProtocolViolation::MissingPacketIdentifier,
+                                ));
+                            };
+
+                            if !self.server_pis.contains(packet_identifier)
+                                && self.data.unacknowledged_publishes >= self.data.receive_maximum
+                            {
+                                return Some(self.receive_maximum_exceeded());
+                            }
+
+                            match self.server_pis.insert(packet_identifier) {
+                                Ok(true) => {
+                                    self.data.unacknowledged_publishes += 1;
+                                    return Some(ExpectedAction::ReceivePacket(
+                                        ReceivePacket::AcknowledgeNeeded {
+                                            acknowledge: AcknowledgeAction {
+                                                packet_identifier,
+                                                quality_of_service:
+                                                    AcknowledgedQualityOfService::ExactlyOnce,
+                                            },
+                                            packet: MqttPacket::Publish(publish),
+                                        },
+                                    ));
+                                }
+                                Ok(false) => {
+                                    trace!(
+                                        ?packet_identifier,
+                                        "Received QoS 2 publish again, not delivering it a second time"
+                                    );
+                                    con.last_time_sent = current_time;
+                                    return Some(ExpectedAction::SendPacket(
+                                        mqtt_format::v5::packets::pubrec::MPubrec {
+                                            packet_identifier,
+                                            reason: mqtt_format::v5::packets::pubrec::PubrecReasonCode::Success,
+                                            properties: mqtt_format::v5::packets::pubrec::PubrecProperties::new(),
+                                        }
+                                        .into(),
+                                    ));
+                                }
+                                Err(ReceivedPacketIdentifierStoreFull) => {
+                                    trace!(
+                                        ?packet_identifier,
+                                        "No space left to track incoming QoS 2 publish"
+                                    );
+                                    return Some(self.receive_maximum_exceeded());
+                                }
+                            }
+                        }
                     },
+                    MqttPacket::Pubrel(pubrel) => {
+                        let reason = if self.server_pis.remove(pubrel.packet_identifier) {
+                            mqtt_format::v5::packets::pubcomp::PubcompReasonCode::Success
+                        } else {
+                            mqtt_format::v5::packets::pubcomp::PubcompReasonCode::PacketIdentifierNotFound
+                        };
 
-                    MqttPacket::Disconnect(_disc) => {
-                        // TODO: Do something about being disconnected?
+                        con.last_time_sent = current_time;
+                        return Some(ExpectedAction::SendPacket(
+                            mqtt_format::v5::packets::pubcomp::MPubcomp {
+                                packet_identifier: pubrel.packet_identifier,
+                                reason,
+                                properties:
+                                    mqtt_format::v5::packets::pubcomp::PubcompProperties::new(),
+                            }
+                            .into(),
+                        ));
+                    }
+
+                    // The reason code is only traced
+                    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+                    MqttPacket::Disconnect(disconnect) => {
+                        trace!(reason_code = ?disconnect.reason_code, "Server closed the connection");
                         self.reset_connection();
 
                         return Some(ExpectedAction::Disconnect);
                     }
                     MqttPacket::Puback(puback) => {
-                        assert!(self.client_pis.contains(puback.packet_identifier));
+                        if !self.client_pis.contains(puback.packet_identifier) {
+                            return Some(
+                                self.protocol_violation(ProtocolViolation::UnknownPacketIdentifier),
+                            );
+                        }
 
-                        self.client_pis.release(puback.packet_identifier);
+                        self.release_publish(puback.packet_identifier);
 
                         return Some(ExpectedAction::ReleasePacket {
                             id: puback.packet_identifier,
                         });
                     }
+                    MqttPacket::Pubrec(pubrec) => {
+                        if !self.client_pis.contains(pubrec.packet_identifier) {
+                            con.last_time_sent = current_time;
+                            return Some(ExpectedAction::SendPacket(
+                                mqtt_format::v5::packets::pubrel::MPubrel {
+                                    packet_identifier: pubrec.packet_identifier,
+                                    reason: mqtt_format::v5::packets::pubrel::PubrelReasonCode::PacketIdentifierNotFound,
+                                    properties: mqtt_format::v5::packets::pubrel::PubrelProperties::new(),
+                                }
+                                .into(),
+                            ));
+                        }
+
+                        if u8::from(pubrec.reason) >= 0x80 {
+                            trace!(reason = ?pubrec.reason, "Server declined QoS 2 publish");
+                            self.release_publish(pubrec.packet_identifier);
+
+                            return Some(ExpectedAction::ReleasePacket {
+                                id: pubrec.packet_identifier,
+                            });
+                        }
+
+                        con.last_time_sent = current_time;
+                        return Some(ExpectedAction::SendPacket(
+                            mqtt_format::v5::packets::pubrel::MPubrel {
+                                packet_identifier: pubrec.packet_identifier,
+                                reason: mqtt_format::v5::packets::pubrel::PubrelReasonCode::Success,
+                                properties: mqtt_format::v5::packets::pubrel::PubrelProperties::new(
+                                ),
+                            }
+                            .into(),
+                        ));
+                    }
+                    MqttPacket::Pubcomp(pubcomp) => {
+                        if !self.client_pis.contains(pubcomp.packet_identifier) {
+                            return Some(
+                                self.protocol_violation(ProtocolViolation::UnknownPacketIdentifier),
+                            );
+                        }
+
+                        self.release_publish(pubcomp.packet_identifier);
+
+                        return Some(ExpectedAction::ReleasePacket {
+                            id: pubcomp.packet_identifier,
+                        });
+                    }
                     MqttPacket::Pingresp(mqtt_format::v5::packets::pingresp::MPingresp) => {
                         match &con.ping_state {
                             PingState::WaitingForPingrespSince(_since) => {
                                 con.ping_state = PingState::WaitingForElapsed;
                             }
                             PingState::WaitingForElapsed => {
-                                panic!("Protocol error, got PingResp without a req");
+                                return Some(
+                                    self.protocol_violation(ProtocolViolation::UnexpectedPingresp),
+                                );
                             }
                         }
                     }
                     MqttPacket::Suback(suback) => {
-                        assert!(self.client_pis.contains(suback.packet_identifier));
+                        if !self.client_pis.contains(suback.packet_identifier) {
+                            return Some(
+                                self.protocol_violation(ProtocolViolation::UnknownPacketIdentifier),
+                            );
+                        }
 
                         self.client_pis.release(suback.packet_identifier);
 
                         // TODO: Verify that subscriptions don't use QoS higher than we set as maximum
                     }
-                    _ => panic!("Invalid packet received"),
+                    MqttPacket::Unsuback(unsuback) => {
+                        if !self.client_pis.contains(unsuback.packet_identifier) {
+                            return Some(
+                                self.protocol_violation(ProtocolViolation::UnknownPacketIdentifier),
+                            );
+                        }
+
+                        self.client_pis.release(unsuback.packet_identifier);
+                    }
+                    MqttPacket::Auth(auth) => {
+                        if !con.reauthenticating {
+                            return self.authentication_failed(AuthenticationError::Unexpected);
+                        }
+
+                        let result = match auth.reason {
+                            mqtt_format::v5::packets::auth::AuthReasonCode::Success => {
+                                con.reauthenticating = false;
+                                matching_authenticator(
+                                    authenticator,
+                                    auth.properties.authentication_method.as_ref(),
+                                )
+                                .and_then(|authenticator| {
+                                    authenticator.finish(
+                                        auth.properties.authentication_data.as_ref().map(|data| data.0),
+                                    )
+                                })
+                                .map(|()| None)
+                            }
+                            mqtt_format::v5::packets::auth::AuthReasonCode::ContinueAuthentication => {
+                                con.last_time_sent = current_time;
+                                answer_challenge(authenticator, &auth).map(Some)
+                            }
+                            mqtt_format::v5::packets::auth::AuthReasonCode::ReAuthenticate => {
+                                Err(AuthenticationError::Unexpected)
+                            }
+                        };
+
+                        return match result {
+                            Ok(action) => action,
+                            Err(error) => self.authentication_failed(error),
+                        };
+                    }
+                    _ => {
+                        return Some(self.protocol_violation(ProtocolViolation::UnexpectedPacket));
+                    }
                 };
             }
             ExternalInfos::PublishPacket(outgoing_publish) => {
@@ -400,16 +919,15 @@ fn handle_connected<'p>(
                 if self.data.keep_alive > 0 {
                     trace!(ping_state = ?con.ping_state, keep_alive = self.data.keep_alive, "Keep alive is non-zero");
 
+                    let keep_alive = Duration::from_secs(u64::from(self.data.keep_alive));
                     match &con.ping_state {
                         PingState::WaitingForElapsed => {
                             trace!(
                                 elapsed_since_last_sent =
-                                    con.last_time_sent.elapsed_seconds(current_time),
+                                    ?con.last_time_sent.elapsed(current_time),
                                 "Checking if ping is required"
                             );
-                            if con.last_time_sent.elapsed_seconds(current_time)
-                                >= self.data.keep_alive as u64
-                            {
+                            if con.last_time_sent.elapsed(current_time) >= keep_alive {
                                 trace!("We need to send a ping, doing so now");
                                 con.ping_state = PingState::WaitingForPingrespSince(current_time);
                                 con.last_time_sent = current_time;
@@ -419,7 +937,14 @@ fn handle_connected<'p>(
                                 )));
                             }
                         }
-                        PingState::WaitingForPingrespSince(_mqtt_instant) => {}
+                        PingState::WaitingForPingrespSince(since) => {
+                            if since.elapsed(current_time) >= keep_alive {
+                                trace!("Server did not answer our ping in time, disconnecting");
+                                self.reset_connection();
+
+                                return Some(ExpectedAction::Disconnect);
+                            }
+                        }
                     }
                 }
             }
@@ -430,78 +955,184 @@ fn handle_connected<'p>(
 
     fn handle_connecting_without_auth<'p>(
         &mut self,
-        _current_time: MqttInstant,
+        connect_sent: MqttInstant,
         external_infos: ExternalInfos<'p>,
     ) -> Option<ExpectedAction<'p>> {
-        let ConnectionState::ConnectingWithoutAuth(conn_without_auth) = &mut self.connection_state
-        else {
-            unreachable!()
-        };
         match external_infos {
-            ExternalInfos::ConsumePacket(to_consume_packet) => {
-                match to_consume_packet {
-                    MqttPacket::Connack(connack) => {
-                        if connack.reason_code != ConnackReasonCode::Success {
-                            panic!("Connection unsuccessful");
+            ExternalInfos::ConsumePacket(to_consume_packet) => match to_consume_packet {
+                MqttPacket::Connack(connack) => self.accept_connection(connect_sent, connack),
+                _ => Some(self.protocol_violation(ProtocolViolation::UnexpectedPacket)),
+            },
+            ExternalInfos::PublishPacket(_mqtt_packet) => {
+                trace!("Not sending a packet before the server accepted the connection");
+                None
+            }
+            ExternalInfos::None => None,
+        }
+    }
+
+    fn handle_connecting_with_auth<'p>(
+        &mut self,
+        connect_sent: MqttInstant,
+        external_infos: ExternalInfos<'p>,
+        authenticator: Option<&'p mut dyn Authenticator>,
+    ) -> Option<ExpectedAction<'p>> {
+        match external_infos {
+            ExternalInfos::ConsumePacket(to_consume_packet) => match to_consume_packet {
+                MqttPacket::Auth(auth) => {
+                    let result = match auth.reason {
+                        mqtt_format::v5::packets::auth::AuthReasonCode::ContinueAuthentication => {
+                            answer_challenge(authenticator, &auth)
+                        }
+                        _ => Err(AuthenticationError::Unexpected),
+                    };
+
+                    match result {
+                        Ok(action) => Some(action),
+                        Err(error) => self.authentication_failed(error),
+                    }
+                }
+                MqttPacket::Connack(connack) => {
+                    if connack.reason_code == ConnackReasonCode::Success {
+                        let verified = matching_authenticator(
+                            authenticator,
+                            connack.properties.authentication_method.as_ref(),
+                        )
+                        .and_then(|authenticator| {
+                            authenticator.finish(
+                                connack
+                                    .properties
+                                    .authentication_data
+                                    .as_ref()
+                                    .map(|data| data.0),
+                            )
+                        });
+
+                        if let Err(error) = verified {
+                            return self.authentication_failed(error);
                         }
+                    }
 
-                        // TODO: Handle session_present flag
+                    self.accept_connection(connect_sent, connack)
+                }
+                _ => Some(self.protocol_violation(ProtocolViolation::UnexpectedPacket)),
+            },
+            ExternalInfos::PublishPacket(_mqtt_packet) => {
+                trace!("Not sending a packet before the server accepted the connection");
+                None
+            }
+            ExternalInfos::None => None,
+        }
+    }
 
-                        let _server_receive_maximum = connack
-                            .properties
-                            .receive_maximum()
-                            .map(|rm| rm.0.get())
-                            .unwrap_or(65_535);
+    fn accept_connection<'p>(
+        &mut self,
+        connect_sent: MqttInstant,
+        connack: mqtt_format::v5::packets::connack::MConnack<'p>,
+    ) -> Option<ExpectedAction<'p>> {
+        if connack.reason_code != ConnackReasonCode::Success {
+            trace!(reason_code = ?connack.reason_code, "Server refused the connection");
+            self.reset_connection();
 
-                        // TODO: Handle _server_receive_maximum above
+            return Some(ExpectedAction::Disconnect);
+        }
 
-                        // TODO: Handle maximum QoS
+        let server_limits = ServerLimits::from_connack(&connack);
+        trace!(?server_limits, "Server accepted the connection");
 
-                        // TODO: Handle retain available
+        if let Some(keep_alive) = connack.properties.server_keep_alive() {
+            self.data.keep_alive = keep_alive.0;
+        }
 
-                        // TODO: Handle max packet size
+        if !connack.session_present {
+            // The server will not release any incoming QoS 2 publish of a previous session
+            self.server_pis.clear();
+        }
 
-                        // TODO: Handle max packet size
+        let potential_client_id =
+            if let Some(client_identifier) = connack.properties.assigned_client_identifier() {
+                let mut hasher = FxHasher::default();
+                client_identifier.0.hash(&mut hasher);
+                self.data.client_id_hash = Some(hasher.finish());
+                Some(client_identifier.0)
+            } else {
+                None
+            };
 
-                        if let Some(keep_alive) = connack.properties.server_keep_alive() {
-                            self.data.keep_alive = keep_alive.0;
-                        }
+        self.connection_state = ConnectionState::Connected(Connected {
+            ping_state: PingState::WaitingForElapsed,
+            last_time_sent: connect_sent,
+            server_limits,
+            reauthenticating: false,
+        });
 
-                        let potential_client_id = if let Some(client_identifier) =
-                            connack.properties.assigned_client_identifier()
-                        {
-                            let mut hasher = FxHasher::default();
-                            client_identifier.0.hash(&mut hasher);
-                            self.data.client_id_hash = Some(hasher.finish());
-                            Some(client_identifier.0)
-                        } else {
-                            None
-                        };
+        potential_client_id.map(ExpectedAction::SaveClientIdentifier)
+    }
 
-                        self.connection_state = ConnectionState::Connected(Connected {
-                            ping_state: PingState::WaitingForElapsed,
-                            last_time_sent: conn_without_auth.connect_sent,
-                        });
+    fn authentication_failed<'p>(
+        &mut self,
+        error: AuthenticationError,
+    ) -> Option<ExpectedAction<'p>> {
+        trace!(?error, "Enhanced authentication failed");
+        self.reset_connection();
 
-                        potential_client_id.map(ExpectedAction::SaveClientIdentifier)
-                    }
-                    p => panic!("Unexpected packet received: {p:?}"),
-                }
-            }
-            ExternalInfos::PublishPacket(_mqtt_packet) => todo!(),
-            ExternalInfos::None => match &self.connection_state {
-                ConnectionState::Disconnected => None,
-                ConnectionState::ConnectingWithoutAuth { .. } => None,
-                ConnectionState::Connected(Connected { .. }) => None,
-            },
-        }
+        Some(ExpectedAction::AuthenticationFailed(error))
     }
 }
 
-#[derive(Debug)]
-pub enum ExpectedAction<'p> {
-    SendPacket(MqttPacket<'p>),
-    SaveClientIdentifier(&'p str),
+/// The authenticator, if it uses the method the server named
+fn matching_authenticator<'a>(
+    authenticator: Option<&'a mut dyn Authenticator>,
+    method: Option<&AuthenticationMethod<'_>>,
+) -> Result<&'a mut dyn Authenticator, AuthenticationError> {
+    let authenticator = authenticator.ok_or(AuthenticationError::MissingAuthenticator)?;
+
+    if method.is_some_and(|method| method.0 != authenticator.method()) {
+        return Err(AuthenticationError::MethodMismatch);
+    }
+
+    Ok(authenticator)
+}
+
+fn answer_challenge<'p>(
+    authenticator: Option<&'p mut dyn Authenticator>,
+    auth: &mqtt_format::v5::packets::auth::MAuth<'_>,
+) -> Result<ExpectedAction<'p>, AuthenticationError> {
+    let authenticator = matching_authenticator(
+        authenticator,
+        auth.properties.authentication_method.as_ref(),
+    )?;
+    let method = authenticator.method();
+    let data = authenticator.challenge(
+        auth.properties
+            .authentication_data
+            .as_ref()
+            .map(|data| data.0),
+    )?;
+
+    Ok(ExpectedAction::SendPacket(auth_packet(
+        mqtt_format::v5::packets::auth::AuthReasonCode::ContinueAuthentication,
+        method,
+        data,
+    )))
+}
+
+fn auth_packet<'p>(
+    reason: mqtt_format::v5::packets::auth::AuthReasonCode,
+    method: &'static str,
+    data: Option<&'p [u8]>,
+) -> MqttPacket<'p> {
+    let mut properties = mqtt_format::v5::packets::auth::AuthProperties::new();
+    properties.authentication_method = Some(AuthenticationMethod(method));
+    properties.authentication_data = data.map(AuthenticationData);
+
+    mqtt_format::v5::packets::auth::MAuth { reason, properties }.into()
+}
+
+#[derive(Debug)]
+pub enum ExpectedAction<'p> {
+    SendPacket(MqttPacket<'p>),
+    SaveClientIdentifier(&'p str),
     StorePacket {
         id: mqtt_format::v5::variable_header::PacketIdentifier,
     },
@@ -510,6 +1141,13 @@ pub enum ExpectedAction<'p> {
     },
     ReceivePacket(ReceivePacket<'p>),
     Disconnect,
+
+    /// Enhanced authentication failed, the connection has to be closed
+    AuthenticationFailed(AuthenticationError),
+
+    /// The server violated the protocol, the connection has to be closed with
+    /// [`ProtocolViolation::disconnect`]
+    ProtocolViolation(ProtocolViolation),
 }
 
 #[derive(Debug)]
@@ -523,18 +1161,59 @@ pub enum ReceivePacket<'p> {
 
 #[derive(Debug)]
 #[must_use = "AcknowledgeActions need to be sent back to the FSM so that the server considers it received."]
-pub struct AcknowledgeAction(mqtt_format::v5::variable_header::PacketIdentifier);
+pub struct AcknowledgeAction {
+    packet_identifier: mqtt_format::v5::variable_header::PacketIdentifier,
+    quality_of_service: AcknowledgedQualityOfService,
+}
+
+impl AcknowledgeAction {
+    pub fn packet_identifier(&self) -> mqtt_format::v5::variable_header::PacketIdentifier {
+        self.packet_identifier
+    }
+
+    pub fn quality_of_service(&self) -> QualityOfService {
+        match self.quality_of_service {
+            AcknowledgedQualityOfService::AtLeastOnce => QualityOfService::AtLeastOnce,
+            AcknowledgedQualityOfService::ExactlyOnce => QualityOfService::ExactlyOnce,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AcknowledgedQualityOfService {
+    AtLeastOnce,
+    ExactlyOnce,
+}
 
+/// A point in time, in milliseconds since whenever the runtime started counting
 #[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub struct MqttInstant(u64);
 
 impl MqttInstant {
-    pub const fn new(now: u64) -> MqttInstant {
-        MqttInstant(now)
+    pub const fn from_millis(millis: u64) -> MqttInstant {
+        MqttInstant(millis)
+    }
+
+    pub const fn from_secs(secs: u64) -> MqttInstant {
+        MqttInstant(secs.saturating_mul(1000))
+    }
+
+    pub const fn as_millis(&self) -> u64 {
+        self.0
+    }
+
+    /// The time between this instant and `current_time`
+    pub fn elapsed(&self, current_time: MqttInstant) -> Duration {
+        Duration::from_millis(self.0.abs_diff(current_time.0))
     }
+}
 
-    pub fn elapsed_seconds(&self, current_time: MqttInstant) -> u64 {
-        self.0.abs_diff(current_time.0)
+impl Add<Duration> for MqttInstant {
+    type Output = MqttInstant;
+
+    fn add(self, duration: Duration) -> MqttInstant {
+        let millis = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        MqttInstant(self.0.saturating_add(millis))
     }
 }
 
@@ -549,6 +1228,13 @@ pub struct ClientData {
     keep_alive: u16,
     client_id_hash: Option<u64>,
     last_time_run: MqttInstant,
+    in_flight_publishes: u16,
+
+    /// How many unacknowledged QoS 1 and 2 publishes we allow the server to send
+    receive_maximum: u16,
+
+    /// Incoming QoS 1 and 2 publishes that were not acknowledged yet
+    unacknowledged_publishes: u16,
 }
 
 impl ClientData {
@@ -561,6 +1247,9 @@ pub const fn const_new(
             keep_alive,
             client_id_hash,
             last_time_run,
+            in_flight_publishes: 0,
+            receive_maximum: u16::MAX,
+            unacknowledged_publishes: 0,
         }
     }
 }
@@ -569,17 +1258,323 @@ pub const fn const_new(
 struct Connected {
     last_time_sent: MqttInstant,
     ping_state: PingState,
+    server_limits: ServerLimits,
+    reauthenticating: bool,
+}
+
+/// What the server allows for the current connection, as announced in its CONNACK
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerLimits {
+    session_present: bool,
+    receive_maximum: u16,
+    maximum_qos: QualityOfService,
+    retain_available: bool,
+    maximum_packet_size: Option<u32>,
+    topic_alias_maximum: u16,
+}
+
+impl ServerLimits {
+    fn from_connack(connack: &mqtt_format::v5::packets::connack::MConnack<'_>) -> Self {
+        use mqtt_format::v5::qos::MaximumQualityOfService;
+
+        ServerLimits {
+            session_present: connack.session_present,
+            receive_maximum: connack
+                .properties
+                .receive_maximum()
+                .map(|rm| rm.0.get())
+                .unwrap_or(u16::MAX),
+            maximum_qos: match connack.properties.maximum_qos().map(|mq| mq.0) {
+                Some(MaximumQualityOfService::AtMostOnce) => QualityOfService::AtMostOnce,
+                Some(MaximumQualityOfService::AtLeastOnce) => QualityOfService::AtLeastOnce,
+                None => QualityOfService::ExactlyOnce,
+            },
+            retain_available: connack.properties.retain_available().is_none_or(|ra| ra.0),
+            maximum_packet_size: connack.properties.maximum_packet_size().map(|mps| mps.0),
+            topic_alias_maximum: connack
+                .properties
+                .topic_alias_maximum()
+                .map(|tam| tam.0)
+                .unwrap_or(0),
+        }
+    }
+
+    /// Whether the server resumed a previous session
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    /// How many QoS 1 and 2 publishes may be unacknowledged at the same time
+    pub fn receive_maximum(&self) -> u16 {
+        self.receive_maximum
+    }
+
+    pub fn maximum_qos(&self) -> QualityOfService {
+        self.maximum_qos
+    }
+
+    pub fn retain_available(&self) -> bool {
+        self.retain_available
+    }
+
+    /// The largest packet in bytes the server accepts, `None` if it set no limit
+    pub fn maximum_packet_size(&self) -> Option<u32> {
+        self.maximum_packet_size
+    }
+
+    /// The highest topic alias the server accepts, `0` if it does not accept any
+    pub fn topic_alias_maximum(&self) -> u16 {
+        self.topic_alias_maximum
+    }
+
+    fn check_publish(
+        &self,
+        publish: &mqtt_format::v5::packets::publish::MPublish<'_>,
+        in_flight_publishes: u16,
+    ) -> Result<(), PublishError> {
+        if u8::from(publish.quality_of_service) > u8::from(self.maximum_qos) {
+            return Err(PublishError::QualityOfServiceNotSupported {
+                maximum: self.maximum_qos,
+            });
+        }
+
+        if publish.retain && !self.retain_available {
+            return Err(PublishError::RetainNotAvailable);
+        }
+
+        if let Some(alias) = publish.properties.topic_alias() {
+            if alias.0.get() > self.topic_alias_maximum {
+                return Err(PublishError::TopicAliasInvalid {
+                    alias: alias.0.get(),
+                    maximum: self.topic_alias_maximum,
+                });
+            }
+        }
+
+        if let Some(maximum) = self.maximum_packet_size {
+            // The identifier is only assigned once the publish passed, but takes up room all the same
+            let packet_identifier = match publish.quality_of_service {
+                QualityOfService::AtMostOnce => None,
+                _ => Some(mqtt_format::v5::variable_header::PacketIdentifier(
+                    core::num::NonZeroU16::MAX,
+                )),
+            };
+            let size = MqttPacket::Publish(mqtt_format::v5::packets::publish::MPublish {
+                packet_identifier,
+                ..publish.clone()
+            })
+            .binary_size();
+            if size > maximum {
+                return Err(PublishError::PacketTooLarge { size, maximum });
+            }
+        }
+
+        if publish.quality_of_service != QualityOfService::AtMostOnce
+            && in_flight_publishes >= self.receive_maximum
+        {
+            return Err(PublishError::ReceiveMaximumExceeded);
+        }
+
+        Ok(())
+    }
+}
+
+/// Why a publish was refused before being sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublishError {
+    /// The server does not support the requested quality of service
+    QualityOfServiceNotSupported { maximum: QualityOfService },
+
+    /// The server does not support retained messages
+    RetainNotAvailable,
+
+    /// The packet is larger than the server accepts
+    PacketTooLarge { size: u32, maximum: u32 },
+
+    /// As many QoS 1 and 2 publishes as the server accepts are already unacknowledged
+    ReceiveMaximumExceeded,
+
+    /// The topic alias is higher than the server accepts
+    TopicAliasInvalid { alias: u16, maximum: u16 },
+
+    /// Every packet identifier the store can hand out is in use by another publish
+    PacketIdentifiersExhausted,
+}
+
+impl core::fmt::Display for PublishError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PublishError::QualityOfServiceNotSupported { maximum } => {
+                write!(f, "The server supports at most {maximum:?}")
+            }
+            PublishError::RetainNotAvailable => {
+                write!(f, "The server does not support retained messages")
+            }
+            PublishError::PacketTooLarge { size, maximum } => write!(
+                f,
+                "The packet has {size} bytes, but the server accepts at most {maximum}"
+            ),
+            PublishError::ReceiveMaximumExceeded => write!(
+                f,
+                "Too many publishes are waiting to be acknowledged by the server"
+            ),
+            PublishError::TopicAliasInvalid { alias, maximum } => write!(
+                f,
+                "The topic alias is {alias}, but the server accepts at most {maximum}"
+            ),
+            PublishError::PacketIdentifiersExhausted => {
+                write!(f, "No packet identifier is free")
+            }
+        }
+    }
+}
+
+impl core::error::Error for PublishError {}
+
+/// Why a CONNECT could not be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectError {
+    /// The client is connected, or connecting already
+    NotDisconnected,
+
+    /// The session was started with another client identifier
+    ClientIdentifierChanged,
+}
+
+impl core::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConnectError::NotDisconnected => write!(f, "The client is not disconnected"),
+            ConnectError::ClientIdentifierChanged => {
+                write!(f, "The session belongs to another client identifier")
+            }
+        }
+    }
+}
+
+impl core::error::Error for ConnectError {}
+
+/// Why a SUBSCRIBE or UNSUBSCRIBE could not be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    /// There is no connection to send it on
+    NotConnected,
+
+    /// Every packet identifier is in use by another request
+    PacketIdentifiersExhausted,
+}
+
+impl core::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RequestError::NotConnected => write!(f, "The client is not connected"),
+            RequestError::PacketIdentifiersExhausted => {
+                write!(f, "No packet identifier is free")
+            }
+        }
+    }
+}
+
+impl core::error::Error for RequestError {}
+
+/// How the server violated the protocol
+///
+/// The client closes the connection with a DISCONNECT carrying the matching reason code, and a
+/// description of the violation as reason string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolViolation {
+    /// A QoS 1 or 2 publish without packet identifier
+    MissingPacketIdentifier,
+
+    /// An acknowledgement for a packet identifier the client does not use
+    UnknownPacketIdentifier,
+
+    /// A PINGRESP although no ping was sent
+    UnexpectedPingresp,
+
+    /// A packet the server must not send at this point
+    UnexpectedPacket,
+
+    /// More unacknowledged QoS 1 and 2 publishes than the receive maximum allows
+    ReceiveMaximumExceeded,
+
+    /// A topic alias above the topic alias maximum of the client
+    InvalidTopicAlias,
+
+    /// A publish without topic name, whose topic alias was not set before
+    UnknownTopicAlias,
+}
+
+impl ProtocolViolation {
+    pub fn reason_code(&self) -> mqtt_format::v5::packets::disconnect::DisconnectReasonCode {
+        use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
+
+        match self {
+            ProtocolViolation::MissingPacketIdentifier => DisconnectReasonCode::MalformedPacket,
+            ProtocolViolation::UnknownPacketIdentifier
+            | ProtocolViolation::UnexpectedPingresp
+            | ProtocolViolation::UnexpectedPacket
+            | ProtocolViolation::UnknownTopicAlias => DisconnectReasonCode::ProtocolError,
+            ProtocolViolation::ReceiveMaximumExceeded => {
+                DisconnectReasonCode::ReceiveMaximumExceeded
+            }
+            ProtocolViolation::InvalidTopicAlias => DisconnectReasonCode::TopicAliasInvalid,
+        }
+    }
+
+    /// The DISCONNECT to close the connection with
+    pub fn disconnect(&self) -> mqtt_format::v5::packets::disconnect::MDisconnect<'static> {
+        mqtt_format::v5::packets::disconnect::MDisconnect {
+            reason_code: self.reason_code(),
+            properties: mqtt_format::v5::packets::disconnect::DisconnectProperties {
+                reason_string: Some(mqtt_format::v5::variable_header::ReasonString(
+                    self.as_str(),
+                )),
+                ..mqtt_format::v5::packets::disconnect::DisconnectProperties::new()
+            },
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProtocolViolation::MissingPacketIdentifier => {
+                "QoS 1 or 2 publish without packet identifier"
+            }
+            ProtocolViolation::UnknownPacketIdentifier => {
+                "Acknowledgement for an unknown packet identifier"
+            }
+            ProtocolViolation::UnexpectedPingresp => "PINGRESP without PINGREQ",
+            ProtocolViolation::UnexpectedPacket => "Unexpected packet",
+            ProtocolViolation::ReceiveMaximumExceeded => "Receive maximum exceeded",
+            ProtocolViolation::InvalidTopicAlias => "Topic alias above the topic alias maximum",
+            ProtocolViolation::UnknownTopicAlias => "Topic alias that was never set",
+        }
+    }
+}
+
+impl core::fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
+impl core::error::Error for ProtocolViolation {}
+
 #[derive(Debug)]
 struct ConnectingWithoutAuth {
     connect_sent: MqttInstant,
 }
 
+#[derive(Debug)]
+struct ConnectingWithAuth {
+    connect_sent: MqttInstant,
+}
+
 #[derive(Debug)]
 enum ConnectionState {
     Disconnected,
     ConnectingWithoutAuth(ConnectingWithoutAuth),
+    ConnectingWithAuth(ConnectingWithAuth),
     Connected(Connected),
 }
 
@@ -595,16 +1590,22 @@ fn is_connected(&self) -> bool {
 
 #[cfg(test)]
 mod tests {
+    use mqtt_format::v5::packets::MqttPacket;
+    use mqtt_format::v5::packets::puback::PubackReasonCode;
+    use mqtt_format::v5::packets::pubrec::PubrecReasonCode;
+
     use super::MqttClientFSM;
     use crate::client::ConnectionState;
     use crate::client::ExpectedAction;
+    use crate::client::ProtocolViolation;
+    use crate::client::RequestError;
 
     #[test]
     fn check_simple_connect() {
         let mut fsm = MqttClientFSM::default();
 
         fsm.handle_connect(
-            crate::client::MqttInstant::new(0),
+            crate::client::MqttInstant::from_secs(0),
             mqtt_format::v5::packets::connect::MConnect {
                 client_identifier: "testing",
                 username: None,
@@ -614,7 +1615,8 @@ fn check_simple_connect() {
                 properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
                 keep_alive: 0,
             },
-        );
+        )
+        .unwrap();
 
         let action = fsm
             .consume(mqtt_format::v5::packets::MqttPacket::Connack(
@@ -624,7 +1626,7 @@ fn check_simple_connect() {
                     properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
                 },
             ))
-            .run(crate::client::MqttInstant::new(0));
+            .run(crate::client::MqttInstant::from_secs(0));
         assert!(action.is_none());
 
         assert!(matches!(
@@ -638,7 +1640,7 @@ fn check_simple_connect_and_disconnect() {
         let mut fsm = MqttClientFSM::default();
 
         fsm.handle_connect(
-            crate::client::MqttInstant::new(0),
+            crate::client::MqttInstant::from_secs(0),
             mqtt_format::v5::packets::connect::MConnect {
                 client_identifier: "testing",
                 username: None,
@@ -648,7 +1650,8 @@ fn check_simple_connect_and_disconnect() {
                 properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
                 keep_alive: 0,
             },
-        );
+        )
+        .unwrap();
 
         let action = fsm
             .consume(mqtt_format::v5::packets::MqttPacket::Connack(
@@ -658,7 +1661,7 @@ fn check_simple_connect_and_disconnect() {
                     properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
                 },
             ))
-            .run(crate::client::MqttInstant::new(0));
+            .run(crate::client::MqttInstant::from_secs(0));
         assert!(action.is_none());
 
         assert!(matches!(
@@ -674,7 +1677,7 @@ fn check_simple_connect_and_disconnect() {
         ));
 
         fsm.handle_connect(
-            crate::client::MqttInstant::new(0),
+            crate::client::MqttInstant::from_secs(0),
             mqtt_format::v5::packets::connect::MConnect {
                 client_identifier: "testing",
                 username: None,
@@ -684,7 +1687,8 @@ fn check_simple_connect_and_disconnect() {
                 properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
                 keep_alive: 0,
             },
-        );
+        )
+        .unwrap();
         let action = fsm
             .consume(mqtt_format::v5::packets::MqttPacket::Connack(
                 mqtt_format::v5::packets::connack::MConnack {
@@ -693,7 +1697,7 @@ fn check_simple_connect_and_disconnect() {
                     properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
                 },
             ))
-            .run(crate::client::MqttInstant::new(0));
+            .run(crate::client::MqttInstant::from_secs(0));
         assert!(action.is_none());
 
         assert!(matches!(
@@ -707,7 +1711,7 @@ fn check_manual_disconnect() {
         let mut fsm = MqttClientFSM::default();
 
         fsm.handle_connect(
-            crate::client::MqttInstant::new(0),
+            crate::client::MqttInstant::from_secs(0),
             mqtt_format::v5::packets::connect::MConnect {
                 client_identifier: "testing",
                 username: None,
@@ -717,7 +1721,8 @@ fn check_manual_disconnect() {
                 properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
                 keep_alive: 0,
             },
-        );
+        )
+        .unwrap();
 
         let action = fsm
             .consume(mqtt_format::v5::packets::MqttPacket::Connack(
@@ -727,7 +1732,7 @@ fn check_manual_disconnect() {
                     properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
                 },
             ))
-            .run(crate::client::MqttInstant::new(0));
+            .run(crate::client::MqttInstant::from_secs(0));
         assert!(action.is_none());
 
         let mut disconnecter = fsm.disconnect(mqtt_format::v5::packets::disconnect::MDisconnect {
@@ -764,7 +1769,7 @@ fn check_incoming_disconnects() {
         let mut fsm = MqttClientFSM::default();
 
         fsm.handle_connect(
-            crate::client::MqttInstant::new(0),
+            crate::client::MqttInstant::from_secs(0),
             mqtt_format::v5::packets::connect::MConnect {
                 client_identifier: "testing",
                 username: None,
@@ -774,7 +1779,8 @@ fn check_incoming_disconnects() {
                 properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
                 keep_alive: 0,
             },
-        );
+        )
+        .unwrap();
 
         let action = fsm
             .consume(mqtt_format::v5::packets::MqttPacket::Connack(
@@ -784,7 +1790,7 @@ fn check_incoming_disconnects() {
                     properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
                 },
             ))
-            .run(crate::client::MqttInstant::new(0));
+            .run(crate::client::MqttInstant::from_secs(0));
         assert!(action.is_none());
 
         let action = fsm
@@ -794,7 +1800,7 @@ fn check_incoming_disconnects() {
                     properties: mqtt_format::v5::packets::disconnect::DisconnectProperties::new()
                 },
             ))
-            .run(crate::client::MqttInstant::new(1));
+            .run(crate::client::MqttInstant::from_secs(1));
         assert!(matches!(action, Some(ExpectedAction::Disconnect)));
 
         assert!(matches!(
@@ -808,7 +1814,7 @@ fn check_ping_request() {
         let mut fsm = MqttClientFSM::default();
 
         fsm.handle_connect(
-            crate::client::MqttInstant::new(0),
+            crate::client::MqttInstant::from_secs(0),
             mqtt_format::v5::packets::connect::MConnect {
                 client_identifier: "testing",
                 username: None,
@@ -818,7 +1824,8 @@ fn check_ping_request() {
                 properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
                 keep_alive: 10,
             },
-        );
+        )
+        .unwrap();
 
         let action = fsm
             .consume(mqtt_format::v5::packets::MqttPacket::Connack(
@@ -828,7 +1835,7 @@ fn check_ping_request() {
                     properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
                 },
             ))
-            .run(crate::client::MqttInstant::new(0));
+            .run(crate::client::MqttInstant::from_secs(0));
         assert!(action.is_none());
 
         assert!(matches!(
@@ -836,13 +1843,13 @@ fn check_ping_request() {
             ConnectionState::Connected { .. }
         ));
 
-        let action = fsm.run(crate::client::MqttInstant::new(5));
+        let action = fsm.run(crate::client::MqttInstant::from_secs(5));
         assert!(action.is_none());
 
-        let action = fsm.run(crate::client::MqttInstant::new(9));
+        let action = fsm.run(crate::client::MqttInstant::from_secs(9));
         assert!(action.is_none());
 
-        let action = fsm.run(crate::client::MqttInstant::new(10));
+        let action = fsm.run(crate::client::MqttInstant::from_secs(10));
         assert!(matches!(
             action,
             Some(ExpectedAction::SendPacket(
@@ -850,17 +1857,17 @@ fn check_ping_request() {
             ))
         ));
 
-        let action = fsm.run(crate::client::MqttInstant::new(12));
+        let action = fsm.run(crate::client::MqttInstant::from_secs(12));
         assert!(action.is_none());
 
         let action = fsm
             .consume(mqtt_format::v5::packets::MqttPacket::Pingresp(
                 mqtt_format::v5::packets::pingresp::MPingresp,
             ))
-            .run(crate::client::MqttInstant::new(15));
+            .run(crate::client::MqttInstant::from_secs(15));
         assert!(action.is_none());
 
-        let action = fsm.run(crate::client::MqttInstant::new(20));
+        let action = fsm.run(crate::client::MqttInstant::from_secs(20));
         assert!(matches!(
             action,
             Some(ExpectedAction::SendPacket(
@@ -868,21 +1875,16 @@ fn check_ping_request() {
             ))
         ));
 
-        let action = fsm.run(crate::client::MqttInstant::new(22));
+        let action = fsm.run(crate::client::MqttInstant::from_secs(22));
         assert!(action.is_none());
     }
 
     #[test]
-    fn check_simple_publish() {
-        tracing_subscriber::fmt()
-            .with_test_writer()
-            .pretty()
-            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-            .init();
+    fn check_ping_timeout() {
         let mut fsm = MqttClientFSM::default();
 
         fsm.handle_connect(
-            crate::client::MqttInstant::new(0),
+            crate::client::MqttInstant::from_secs(0),
             mqtt_format::v5::packets::connect::MConnect {
                 client_identifier: "testing",
                 username: None,
@@ -892,7 +1894,8 @@ fn check_simple_publish() {
                 properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
                 keep_alive: 10,
             },
-        );
+        )
+        .unwrap();
 
         let action = fsm
             .consume(mqtt_format::v5::packets::MqttPacket::Connack(
@@ -902,53 +1905,38 @@ fn check_simple_publish() {
                     properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
                 },
             ))
-            .run(crate::client::MqttInstant::new(0));
+            .run(crate::client::MqttInstant::from_secs(0));
         assert!(action.is_none());
 
+        let action = fsm.run(crate::client::MqttInstant::from_secs(10));
         assert!(matches!(
-            fsm.connection_state,
-            ConnectionState::Connected { .. }
+            action,
+            Some(ExpectedAction::SendPacket(
+                mqtt_format::v5::packets::MqttPacket::Pingreq(..)
+            ))
         ));
 
-        let mut publisher = fsm.publish(mqtt_format::v5::packets::publish::MPublish {
-            duplicate: false,
-            quality_of_service: mqtt_format::v5::qos::QualityOfService::AtMostOnce,
-            retain: false,
-            topic_name: "foo/bar",
-            packet_identifier: None,
-            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
-            payload: b"Hello World",
-        });
-
-        let action = publisher.run(crate::client::MqttInstant::new(11));
-        assert!(
-            matches!(
-                action,
-                Some(ExpectedAction::SendPacket(
-                    mqtt_format::v5::packets::MqttPacket::Publish(..)
-                ))
-            ),
-            "Got action: {action:?}"
-        );
+        let action = fsm.run(crate::client::MqttInstant::from_secs(19));
+        assert!(action.is_none());
 
-        let action = publisher.run(crate::client::MqttInstant::new(11));
-        assert!(action.is_none(), "Got action: {action:?}");
+        let action = fsm.run(crate::client::MqttInstant::from_secs(20));
+        assert!(matches!(action, Some(ExpectedAction::Disconnect)));
 
-        let action = fsm.run(crate::client::MqttInstant::new(12));
-        assert!(action.is_none(), "Got action: {action:?}");
+        assert!(matches!(
+            fsm.connection_state,
+            ConnectionState::Disconnected
+        ));
     }
 
     #[test]
-    fn check_qos1_publish() {
-        tracing_subscriber::fmt()
-            .with_test_writer()
-            .pretty()
-            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-            .init();
+    fn check_next_deadline() {
+        use crate::client::MqttInstant;
+
         let mut fsm = MqttClientFSM::default();
+        assert_eq!(fsm.next_deadline(), None);
 
         fsm.handle_connect(
-            crate::client::MqttInstant::new(0),
+            MqttInstant::from_millis(250),
             mqtt_format::v5::packets::connect::MConnect {
                 client_identifier: "testing",
                 username: None,
@@ -956,9 +1944,11 @@ fn check_qos1_publish() {
                 clean_start: false,
                 will: None,
                 properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
-                keep_alive: 10,
+                keep_alive: 1,
             },
-        );
+        )
+        .unwrap();
+        assert_eq!(fsm.next_deadline(), None);
 
         let action = fsm
             .consume(mqtt_format::v5::packets::MqttPacket::Connack(
@@ -968,81 +1958,32 @@ fn check_qos1_publish() {
                     properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
                 },
             ))
-            .run(crate::client::MqttInstant::new(0));
+            .run(MqttInstant::from_millis(400));
         assert!(action.is_none());
+        assert_eq!(fsm.next_deadline(), Some(MqttInstant::from_millis(1250)));
 
+        assert!(fsm.run(MqttInstant::from_millis(1249)).is_none());
+        let action = fsm.run(MqttInstant::from_millis(1250));
         assert!(matches!(
-            fsm.connection_state,
-            ConnectionState::Connected { .. }
-        ));
-
-        let mut publisher = fsm.publish(mqtt_format::v5::packets::publish::MPublish {
-            duplicate: false,
-            quality_of_service: mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
-            retain: false,
-            topic_name: "foo/bar",
-            packet_identifier: None,
-            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
-            payload: b"Hello World",
-        });
-
-        let action = publisher.run(crate::client::MqttInstant::new(10));
-        assert!(
-            matches!(action, Some(ExpectedAction::StorePacket { id }) if id.0.get() == 1),
-            "Got action: {action:?}"
-        );
-
-        let action = publisher.run(crate::client::MqttInstant::new(10));
-        assert!(
-            matches!(
-                action,
-                Some(ExpectedAction::SendPacket(
-                    mqtt_format::v5::packets::MqttPacket::Publish(..)
-                ))
-            ),
-            "Got action: {action:?}"
-        );
-
-        let action = publisher.run(crate::client::MqttInstant::new(11));
-        assert!(action.is_none(), "Got action: {action:?}");
-
-        let action = fsm.run(crate::client::MqttInstant::new(12));
-        assert!(action.is_none(), "Got action: {action:?}");
-
-        let action = fsm
-            .consume(mqtt_format::v5::packets::MqttPacket::Puback(
-                mqtt_format::v5::packets::puback::MPuback {
-                    packet_identifier: mqtt_format::v5::variable_header::PacketIdentifier(
-                        1.try_into().unwrap(),
-                    ),
-                    reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
-                    properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
-                },
+            action,
+            Some(ExpectedAction::SendPacket(
+                mqtt_format::v5::packets::MqttPacket::Pingreq(..)
             ))
-            .run(crate::client::MqttInstant(13));
-        assert!(
-            matches!(
-                action,
-                Some(ExpectedAction::ReleasePacket { id }) if id.0.get() == 1
-            ),
-            "Got action: {action:?}"
-        );
+        ));
+        assert_eq!(fsm.next_deadline(), Some(MqttInstant::from_millis(2250)));
 
-        let action = fsm.run(crate::client::MqttInstant::new(12));
-        assert!(action.is_none(), "Got action: {action:?}");
+        assert!(fsm.run(MqttInstant::from_millis(2249)).is_none());
+        let action = fsm.run(MqttInstant::from_millis(2250));
+        assert!(matches!(action, Some(ExpectedAction::Disconnect)));
+        assert_eq!(fsm.next_deadline(), None);
     }
 
     #[test]
-    fn check_publish_recv() {
-        tracing_subscriber::fmt()
-            .with_test_writer()
-            .pretty()
-            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-            .init();
+    fn check_server_keep_alive() {
         let mut fsm = MqttClientFSM::default();
 
         fsm.handle_connect(
-            crate::client::MqttInstant::new(0),
+            crate::client::MqttInstant::from_secs(0),
             mqtt_format::v5::packets::connect::MConnect {
                 client_identifier: "testing",
                 username: None,
@@ -1050,27 +1991,244 @@ fn check_publish_recv() {
                 clean_start: false,
                 will: None,
                 properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
-                keep_alive: 10,
+                keep_alive: 60,
             },
-        );
+        )
+        .unwrap();
+        assert_eq!(fsm.keep_alive(), 60);
 
         let action = fsm
             .consume(mqtt_format::v5::packets::MqttPacket::Connack(
                 mqtt_format::v5::packets::connack::MConnack {
                     session_present: false,
                     reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
-                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties {
+                        server_keep_alive: Some(mqtt_format::v5::variable_header::ServerKeepAlive(
+                            5,
+                        )),
+                        ..mqtt_format::v5::packets::connack::ConnackProperties::new()
+                    },
                 },
             ))
-            .run(crate::client::MqttInstant::new(0));
+            .run(crate::client::MqttInstant::from_secs(0));
         assert!(action.is_none());
+        assert_eq!(fsm.keep_alive(), 5);
 
+        let action = fsm.run(crate::client::MqttInstant::from_secs(5));
         assert!(matches!(
-            fsm.connection_state,
-            ConnectionState::Connected { .. }
-        ));
-
-        let action = fsm
+            action,
+            Some(ExpectedAction::SendPacket(
+                mqtt_format::v5::packets::MqttPacket::Pingreq(..)
+            ))
+        ));
+    }
+
+    #[test]
+    fn check_simple_publish() {
+        let _ = tracing_subscriber::fmt()
+            .with_test_writer()
+            .pretty()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .try_init();
+        let mut fsm = MqttClientFSM::default();
+
+        fsm.handle_connect(
+            crate::client::MqttInstant::from_secs(0),
+            mqtt_format::v5::packets::connect::MConnect {
+                client_identifier: "testing",
+                username: None,
+                password: None,
+                clean_start: false,
+                will: None,
+                properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+                keep_alive: 10,
+            },
+        )
+        .unwrap();
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(0));
+        assert!(action.is_none());
+
+        assert!(matches!(
+            fsm.connection_state,
+            ConnectionState::Connected { .. }
+        ));
+
+        let mut publisher = fsm
+            .publish(mqtt_format::v5::packets::publish::MPublish {
+                duplicate: false,
+                quality_of_service: mqtt_format::v5::qos::QualityOfService::AtMostOnce,
+                retain: false,
+                topic_name: "foo/bar",
+                packet_identifier: None,
+                properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                payload: b"Hello World",
+            })
+            .unwrap();
+
+        let action = publisher.run(crate::client::MqttInstant::from_secs(11));
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::SendPacket(
+                    mqtt_format::v5::packets::MqttPacket::Publish(..)
+                ))
+            ),
+            "Got action: {action:?}"
+        );
+
+        let action = publisher.run(crate::client::MqttInstant::from_secs(11));
+        assert!(action.is_none(), "Got action: {action:?}");
+
+        let action = fsm.run(crate::client::MqttInstant::from_secs(12));
+        assert!(action.is_none(), "Got action: {action:?}");
+    }
+
+    #[test]
+    fn check_qos1_publish() {
+        let _ = tracing_subscriber::fmt()
+            .with_test_writer()
+            .pretty()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .try_init();
+        let mut fsm = MqttClientFSM::default();
+
+        fsm.handle_connect(
+            crate::client::MqttInstant::from_secs(0),
+            mqtt_format::v5::packets::connect::MConnect {
+                client_identifier: "testing",
+                username: None,
+                password: None,
+                clean_start: false,
+                will: None,
+                properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+                keep_alive: 10,
+            },
+        )
+        .unwrap();
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(0));
+        assert!(action.is_none());
+
+        assert!(matches!(
+            fsm.connection_state,
+            ConnectionState::Connected { .. }
+        ));
+
+        let mut publisher = fsm
+            .publish(mqtt_format::v5::packets::publish::MPublish {
+                duplicate: false,
+                quality_of_service: mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                retain: false,
+                topic_name: "foo/bar",
+                packet_identifier: None,
+                properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                payload: b"Hello World",
+            })
+            .unwrap();
+
+        let action = publisher.run(crate::client::MqttInstant::from_secs(10));
+        assert!(
+            matches!(action, Some(ExpectedAction::StorePacket { id }) if id.0.get() == 1),
+            "Got action: {action:?}"
+        );
+
+        let action = publisher.run(crate::client::MqttInstant::from_secs(10));
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::SendPacket(
+                    mqtt_format::v5::packets::MqttPacket::Publish(..)
+                ))
+            ),
+            "Got action: {action:?}"
+        );
+
+        let action = publisher.run(crate::client::MqttInstant::from_secs(11));
+        assert!(action.is_none(), "Got action: {action:?}");
+
+        let action = fsm.run(crate::client::MqttInstant::from_secs(12));
+        assert!(action.is_none(), "Got action: {action:?}");
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Puback(
+                mqtt_format::v5::packets::puback::MPuback {
+                    packet_identifier: mqtt_format::v5::variable_header::PacketIdentifier(
+                        1.try_into().unwrap(),
+                    ),
+                    reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                },
+            ))
+            .run(crate::client::MqttInstant(13));
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::ReleasePacket { id }) if id.0.get() == 1
+            ),
+            "Got action: {action:?}"
+        );
+
+        let action = fsm.run(crate::client::MqttInstant::from_secs(12));
+        assert!(action.is_none(), "Got action: {action:?}");
+    }
+
+    #[test]
+    fn check_publish_recv() {
+        let _ = tracing_subscriber::fmt()
+            .with_test_writer()
+            .pretty()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .try_init();
+        let mut fsm = MqttClientFSM::default();
+
+        fsm.handle_connect(
+            crate::client::MqttInstant::from_secs(0),
+            mqtt_format::v5::packets::connect::MConnect {
+                client_identifier: "testing",
+                username: None,
+                password: None,
+                clean_start: false,
+                will: None,
+                properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+                keep_alive: 10,
+            },
+        )
+        .unwrap();
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(0));
+        assert!(action.is_none());
+
+        assert!(matches!(
+            fsm.connection_state,
+            ConnectionState::Connected { .. }
+        ));
+
+        let action = fsm
             .consume(mqtt_format::v5::packets::MqttPacket::Publish(
                 mqtt_format::v5::packets::publish::MPublish {
                     duplicate: false,
@@ -1082,7 +2240,7 @@ fn check_publish_recv() {
                     payload: &[],
                 },
             ))
-            .run(crate::client::MqttInstant::new(1));
+            .run(crate::client::MqttInstant::from_secs(1));
 
         // match from hell
         assert!(matches!(
@@ -1092,7 +2250,7 @@ fn check_publish_recv() {
             ))
         ));
 
-        let action = fsm.run(crate::client::MqttInstant::new(2));
+        let action = fsm.run(crate::client::MqttInstant::from_secs(2));
         assert!(action.is_none(), "Got action: {action:?}");
 
         let action = fsm
@@ -1109,7 +2267,7 @@ fn check_publish_recv() {
                     payload: &[],
                 },
             ))
-            .run(crate::client::MqttInstant::new(3));
+            .run(crate::client::MqttInstant::from_secs(3));
 
         // match from hell
         let Some(ExpectedAction::ReceivePacket(crate::client::ReceivePacket::AcknowledgeNeeded {
@@ -1124,18 +2282,1269 @@ fn check_publish_recv() {
             packet,
             mqtt_format::v5::packets::MqttPacket::Publish { .. }
         ));
-        assert!(matches!(acknowledge, crate::client::AcknowledgeAction(pid) if pid.0.get() == 11));
+        assert_eq!(acknowledge.packet_identifier().0.get(), 11);
 
-        let action = fsm.acknowledge(crate::client::MqttInstant::new(4), acknowledge);
+        let action = fsm.acknowledge(crate::client::MqttInstant::from_secs(4), acknowledge);
         assert!(
             matches!(
                 action,
-                ExpectedAction::SendPacket(mqtt_format::v5::packets::MqttPacket::Puback(..))
+                Some(ExpectedAction::SendPacket(
+                    mqtt_format::v5::packets::MqttPacket::Puback(..)
+                ))
             ),
             "Got action: {action:?}"
         );
 
-        let action = fsm.run(crate::client::MqttInstant::new(2));
+        let action = fsm.run(crate::client::MqttInstant::from_secs(2));
         assert!(action.is_none(), "Got action: {action:?}");
     }
+
+    #[test]
+    fn check_server_limits() {
+        use mqtt_format::v5::packets::publish::MPublish;
+        use mqtt_format::v5::qos::QualityOfService;
+
+        use crate::client::PublishError;
+
+        let mut fsm = MqttClientFSM::default();
+
+        fsm.handle_connect(
+            crate::client::MqttInstant::from_secs(0),
+            mqtt_format::v5::packets::connect::MConnect {
+                client_identifier: "testing",
+                username: None,
+                password: None,
+                clean_start: false,
+                will: None,
+                properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+                keep_alive: 0,
+            },
+        )
+        .unwrap();
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: true,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties {
+                        receive_maximum: Some(mqtt_format::v5::variable_header::ReceiveMaximum(
+                            1.try_into().unwrap(),
+                        )),
+                        maximum_qos: Some(mqtt_format::v5::variable_header::MaximumQoS(
+                            mqtt_format::v5::qos::MaximumQualityOfService::AtLeastOnce,
+                        )),
+                        retain_available: Some(mqtt_format::v5::variable_header::RetainAvailable(
+                            false,
+                        )),
+                        maximum_packet_size: Some(
+                            mqtt_format::v5::variable_header::MaximumPacketSize(64),
+                        ),
+                        topic_alias_maximum: Some(
+                            mqtt_format::v5::variable_header::TopicAliasMaximum(2),
+                        ),
+                        ..mqtt_format::v5::packets::connack::ConnackProperties::new()
+                    },
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(0));
+        assert!(action.is_none());
+
+        let limits = fsm.server_limits().unwrap();
+        assert!(limits.session_present());
+        assert_eq!(limits.receive_maximum(), 1);
+        assert_eq!(limits.maximum_qos(), QualityOfService::AtLeastOnce);
+        assert!(!limits.retain_available());
+        assert_eq!(limits.maximum_packet_size(), Some(64));
+        assert_eq!(limits.topic_alias_maximum(), 2);
+
+        let publish = MPublish {
+            duplicate: false,
+            quality_of_service: QualityOfService::AtLeastOnce,
+            retain: false,
+            topic_name: "foo",
+            packet_identifier: Some(mqtt_format::v5::variable_header::PacketIdentifier(
+                1.try_into().unwrap(),
+            )),
+            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+            payload: b"bar",
+        };
+
+        let refused = fsm
+            .publish(MPublish {
+                quality_of_service: QualityOfService::ExactlyOnce,
+                ..publish.clone()
+            })
+            .err();
+        assert_eq!(
+            refused,
+            Some(PublishError::QualityOfServiceNotSupported {
+                maximum: QualityOfService::AtLeastOnce
+            })
+        );
+
+        let refused = fsm
+            .publish(MPublish {
+                retain: true,
+                ..publish.clone()
+            })
+            .err();
+        assert_eq!(refused, Some(PublishError::RetainNotAvailable));
+
+        let refused = fsm
+            .publish(MPublish {
+                payload: &[0; 64],
+                ..publish.clone()
+            })
+            .err();
+        assert!(matches!(
+            refused,
+            Some(PublishError::PacketTooLarge { maximum: 64, .. })
+        ));
+
+        let refused = fsm
+            .publish(MPublish {
+                properties: mqtt_format::v5::packets::publish::PublishProperties {
+                    topic_alias: Some(mqtt_format::v5::variable_header::TopicAlias(
+                        3.try_into().unwrap(),
+                    )),
+                    ..mqtt_format::v5::packets::publish::PublishProperties::new()
+                },
+                ..publish.clone()
+            })
+            .err();
+        assert_eq!(
+            refused,
+            Some(PublishError::TopicAliasInvalid {
+                alias: 3,
+                maximum: 2
+            })
+        );
+
+        let mut publisher = fsm.publish(publish.clone()).unwrap();
+        let id = match publisher.run(crate::client::MqttInstant::from_secs(1)) {
+            Some(ExpectedAction::StorePacket { id }) => id,
+            action => panic!("Expected the packet to be stored, got: {action:?}"),
+        };
+        assert!(
+            publisher
+                .run(crate::client::MqttInstant::from_secs(1))
+                .is_some()
+        );
+
+        assert!(fsm.receive_maximum_reached());
+        let refused = fsm.publish(publish.clone()).err();
+        assert_eq!(refused, Some(PublishError::ReceiveMaximumExceeded));
+
+        assert!(
+            fsm.publish(MPublish {
+                quality_of_service: QualityOfService::AtMostOnce,
+                packet_identifier: None,
+                ..publish.clone()
+            })
+            .is_ok()
+        );
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Puback(
+                mqtt_format::v5::packets::puback::MPuback {
+                    packet_identifier: id,
+                    reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(2));
+        assert!(matches!(action, Some(ExpectedAction::ReleasePacket { .. })));
+
+        assert!(!fsm.receive_maximum_reached());
+        let mut publisher = fsm.publish(publish).unwrap();
+        let id = match publisher.run(crate::client::MqttInstant::from_secs(3)) {
+            Some(ExpectedAction::StorePacket { id }) => id,
+            action => panic!("Expected the packet to be stored, got: {action:?}"),
+        };
+
+        // A publish the server lost along with the session frees its slot all the same
+        assert!(fsm.receive_maximum_reached());
+        assert!(fsm.forget_publish(id));
+        assert!(!fsm.forget_publish(id));
+        assert!(!fsm.receive_maximum_reached());
+    }
+
+    #[test]
+    fn check_publish_size_includes_packet_identifier() {
+        use mqtt_format::v5::packets::publish::MPublish;
+        use mqtt_format::v5::qos::QualityOfService;
+
+        use crate::client::PublishError;
+
+        let mut fsm = MqttClientFSM::default();
+
+        fsm.handle_connect(
+            crate::client::MqttInstant::from_secs(0),
+            mqtt_format::v5::packets::connect::MConnect {
+                client_identifier: "testing",
+                username: None,
+                password: None,
+                clean_start: false,
+                will: None,
+                properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+                keep_alive: 0,
+            },
+        )
+        .unwrap();
+        let _ = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties {
+                        maximum_packet_size: Some(
+                            mqtt_format::v5::variable_header::MaximumPacketSize(64),
+                        ),
+                        ..mqtt_format::v5::packets::connack::ConnackProperties::new()
+                    },
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(0));
+
+        let payload = [0; 64];
+        let publish = |length: usize| MPublish {
+            duplicate: false,
+            quality_of_service: QualityOfService::AtLeastOnce,
+            retain: false,
+            topic_name: "foo",
+            packet_identifier: None,
+            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+            payload: &payload[..length],
+        };
+
+        // The size as sent, with the identifier the FSM assigns
+        let size = |length: usize| {
+            MqttPacket::Publish(MPublish {
+                packet_identifier: Some(mqtt_format::v5::variable_header::PacketIdentifier(
+                    1.try_into().unwrap(),
+                )),
+                ..publish(length)
+            })
+            .binary_size()
+        };
+        let fitting = (0..payload.len())
+            .find(|length| size(length + 1) > 64)
+            .unwrap();
+        assert_eq!(size(fitting), 64);
+
+        assert_eq!(
+            fsm.publish(publish(fitting + 1)).err(),
+            Some(PublishError::PacketTooLarge {
+                size: 65,
+                maximum: 64
+            })
+        );
+        assert!(fsm.publish(publish(fitting)).is_ok());
+    }
+
+    #[test]
+    fn check_qos2_publish() {
+        let mut fsm = MqttClientFSM::default();
+
+        fsm.handle_connect(
+            crate::client::MqttInstant::from_secs(0),
+            mqtt_format::v5::packets::connect::MConnect {
+                client_identifier: "testing",
+                username: None,
+                password: None,
+                clean_start: false,
+                will: None,
+                properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+                keep_alive: 10,
+            },
+        )
+        .unwrap();
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(0));
+        assert!(action.is_none());
+
+        let mut publisher = fsm
+            .publish(mqtt_format::v5::packets::publish::MPublish {
+                duplicate: false,
+                quality_of_service: mqtt_format::v5::qos::QualityOfService::ExactlyOnce,
+                retain: false,
+                topic_name: "foo/bar",
+                packet_identifier: None,
+                properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                payload: b"Hello World",
+            })
+            .unwrap();
+
+        let action = publisher.run(crate::client::MqttInstant::from_secs(1));
+        assert!(
+            matches!(action, Some(ExpectedAction::StorePacket { id }) if id.0.get() == 1),
+            "Got action: {action:?}"
+        );
+
+        let action = publisher.run(crate::client::MqttInstant::from_secs(1));
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::SendPacket(
+                    mqtt_format::v5::packets::MqttPacket::Publish(..)
+                ))
+            ),
+            "Got action: {action:?}"
+        );
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Pubrec(
+                mqtt_format::v5::packets::pubrec::MPubrec {
+                    packet_identifier: mqtt_format::v5::variable_header::PacketIdentifier(
+                        1.try_into().unwrap(),
+                    ),
+                    reason: mqtt_format::v5::packets::pubrec::PubrecReasonCode::Success,
+                    properties: mqtt_format::v5::packets::pubrec::PubrecProperties::new(),
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(2));
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::SendPacket(
+                    mqtt_format::v5::packets::MqttPacket::Pubrel(mqtt_format::v5::packets::pubrel::MPubrel {
+                        packet_identifier,
+                        reason: mqtt_format::v5::packets::pubrel::PubrelReasonCode::Success,
+                        ..
+                    })
+                )) if packet_identifier.0.get() == 1
+            ),
+            "Got action: {action:?}"
+        );
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Pubcomp(
+                mqtt_format::v5::packets::pubcomp::MPubcomp {
+                    packet_identifier: mqtt_format::v5::variable_header::PacketIdentifier(
+                        1.try_into().unwrap(),
+                    ),
+                    reason: mqtt_format::v5::packets::pubcomp::PubcompReasonCode::Success,
+                    properties: mqtt_format::v5::packets::pubcomp::PubcompProperties::new(),
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(3));
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::ReleasePacket { id }) if id.0.get() == 1
+            ),
+            "Got action: {action:?}"
+        );
+
+        let action = fsm.run(crate::client::MqttInstant::from_secs(4));
+        assert!(action.is_none(), "Got action: {action:?}");
+    }
+
+    #[test]
+    fn check_qos2_publish_recv() {
+        let mut fsm = MqttClientFSM::default();
+
+        fsm.handle_connect(
+            crate::client::MqttInstant::from_secs(0),
+            mqtt_format::v5::packets::connect::MConnect {
+                client_identifier: "testing",
+                username: None,
+                password: None,
+                clean_start: false,
+                will: None,
+                properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+                keep_alive: 10,
+            },
+        )
+        .unwrap();
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(0));
+        assert!(action.is_none());
+
+        let publish = mqtt_format::v5::packets::publish::MPublish {
+            duplicate: false,
+            quality_of_service: mqtt_format::v5::qos::QualityOfService::ExactlyOnce,
+            retain: false,
+            topic_name: "foo",
+            packet_identifier: Some(mqtt_format::v5::variable_header::PacketIdentifier(
+                42.try_into().unwrap(),
+            )),
+            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+            payload: &[],
+        };
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Publish(
+                publish.clone(),
+            ))
+            .run(crate::client::MqttInstant::from_secs(1));
+
+        let Some(ExpectedAction::ReceivePacket(crate::client::ReceivePacket::AcknowledgeNeeded {
+            packet: _,
+            acknowledge,
+        })) = action
+        else {
+            panic!("Expected ReceivePacket with AcknowledgeNeeded: {action:?}")
+        };
+        assert_eq!(acknowledge.packet_identifier().0.get(), 42);
+
+        let action = fsm.acknowledge(crate::client::MqttInstant::from_secs(2), acknowledge);
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::SendPacket(
+                    mqtt_format::v5::packets::MqttPacket::Pubrec(..)
+                ))
+            ),
+            "Got action: {action:?}"
+        );
+
+        // The server did not see our PUBREC and sends the publish again
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Publish(
+                mqtt_format::v5::packets::publish::MPublish {
+                    duplicate: true,
+                    ..publish.clone()
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(3));
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::SendPacket(
+                    mqtt_format::v5::packets::MqttPacket::Pubrec(..)
+                ))
+            ),
+            "Got action: {action:?}"
+        );
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Pubrel(
+                mqtt_format::v5::packets::pubrel::MPubrel {
+                    packet_identifier: mqtt_format::v5::variable_header::PacketIdentifier(
+                        42.try_into().unwrap(),
+                    ),
+                    reason: mqtt_format::v5::packets::pubrel::PubrelReasonCode::Success,
+                    properties: mqtt_format::v5::packets::pubrel::PubrelProperties::new(),
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(4));
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::SendPacket(
+                    mqtt_format::v5::packets::MqttPacket::Pubcomp(
+                        mqtt_format::v5::packets::pubcomp::MPubcomp {
+                            reason: mqtt_format::v5::packets::pubcomp::PubcompReasonCode::Success,
+                            ..
+                        }
+                    )
+                ))
+            ),
+            "Got action: {action:?}"
+        );
+
+        // After the PUBREL the identifier may be reused for a new message
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Publish(publish))
+            .run(crate::client::MqttInstant::from_secs(5));
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::ReceivePacket(
+                    crate::client::ReceivePacket::AcknowledgeNeeded { .. }
+                ))
+            ),
+            "Got action: {action:?}"
+        );
+    }
+
+    #[test]
+    fn check_qos2_publish_rejected() {
+        let mut fsm = MqttClientFSM::default();
+
+        fsm.handle_connect(
+            crate::client::MqttInstant::from_secs(0),
+            mqtt_format::v5::packets::connect::MConnect {
+                client_identifier: "testing",
+                username: None,
+                password: None,
+                clean_start: false,
+                will: None,
+                properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+                keep_alive: 10,
+            },
+        )
+        .unwrap();
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(0));
+        assert!(action.is_none());
+
+        let publish = mqtt_format::v5::packets::publish::MPublish {
+            duplicate: false,
+            quality_of_service: mqtt_format::v5::qos::QualityOfService::ExactlyOnce,
+            retain: false,
+            topic_name: "foo",
+            packet_identifier: Some(mqtt_format::v5::variable_header::PacketIdentifier(
+                42.try_into().unwrap(),
+            )),
+            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+            payload: &[],
+        };
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Publish(
+                publish.clone(),
+            ))
+            .run(crate::client::MqttInstant::from_secs(1));
+        let Some(ExpectedAction::ReceivePacket(crate::client::ReceivePacket::AcknowledgeNeeded {
+            acknowledge,
+            ..
+        })) = action
+        else {
+            panic!("Expected ReceivePacket with AcknowledgeNeeded: {action:?}")
+        };
+        assert_eq!(
+            acknowledge.quality_of_service(),
+            mqtt_format::v5::qos::QualityOfService::ExactlyOnce
+        );
+
+        let action = fsm.acknowledge_with_reason(
+            crate::client::MqttInstant::from_secs(2),
+            acknowledge,
+            PubackReasonCode::ImplementationSpecificError,
+        );
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::SendPacket(
+                    mqtt_format::v5::packets::MqttPacket::Pubrec(
+                        mqtt_format::v5::packets::pubrec::MPubrec {
+                            reason: PubrecReasonCode::ImplementationSpecificError,
+                            ..
+                        }
+                    )
+                ))
+            ),
+            "Got action: {action:?}"
+        );
+
+        // The rejected publish is not released, so its identifier is free for a new one
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Publish(
+                publish.clone(),
+            ))
+            .run(crate::client::MqttInstant::from_secs(3));
+        let Some(ExpectedAction::ReceivePacket(crate::client::ReceivePacket::AcknowledgeNeeded {
+            acknowledge,
+            ..
+        })) = action
+        else {
+            panic!("Expected ReceivePacket with AcknowledgeNeeded: {action:?}")
+        };
+
+        // Without an acknowledgement it can also be forgotten, to be handed out once more
+        assert!(fsm.forget_received(acknowledge.packet_identifier()));
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Publish(publish))
+            .run(crate::client::MqttInstant::from_secs(4));
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::ReceivePacket(
+                    crate::client::ReceivePacket::AcknowledgeNeeded { .. }
+                ))
+            ),
+            "Got action: {action:?}"
+        );
+    }
+
+    #[test]
+    fn check_receive_maximum_exceeded() {
+        let mut fsm = MqttClientFSM::default();
+
+        fsm.handle_connect(
+            crate::client::MqttInstant::from_secs(0),
+            mqtt_format::v5::packets::connect::MConnect {
+                client_identifier: "testing",
+                username: None,
+                password: None,
+                clean_start: false,
+                will: None,
+                properties: mqtt_format::v5::packets::connect::ConnectProperties {
+                    receive_maximum: Some(mqtt_format::v5::variable_header::ReceiveMaximum(
+                        1.try_into().unwrap(),
+                    )),
+                    ..mqtt_format::v5::packets::connect::ConnectProperties::new()
+                },
+                keep_alive: 10,
+            },
+        )
+        .unwrap();
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(0));
+        assert!(action.is_none());
+
+        let publish = |id: u16| {
+            mqtt_format::v5::packets::MqttPacket::Publish(
+                mqtt_format::v5::packets::publish::MPublish {
+                    duplicate: false,
+                    quality_of_service: mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                    retain: false,
+                    topic_name: "foo",
+                    packet_identifier: Some(mqtt_format::v5::variable_header::PacketIdentifier(
+                        id.try_into().unwrap(),
+                    )),
+                    properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                    payload: &[],
+                },
+            )
+        };
+
+        let action = fsm
+            .consume(publish(1))
+            .run(crate::client::MqttInstant::from_secs(1));
+        let Some(ExpectedAction::ReceivePacket(crate::client::ReceivePacket::AcknowledgeNeeded {
+            acknowledge,
+            ..
+        })) = action
+        else {
+            panic!("Expected ReceivePacket with AcknowledgeNeeded: {action:?}")
+        };
+
+        // Once acknowledged, the server may send the next one
+        let _ = fsm.acknowledge(crate::client::MqttInstant::from_secs(2), acknowledge);
+        let action = fsm
+            .consume(publish(2))
+            .run(crate::client::MqttInstant::from_secs(3));
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::ReceivePacket(
+                    crate::client::ReceivePacket::AcknowledgeNeeded { .. }
+                ))
+            ),
+            "Got action: {action:?}"
+        );
+
+        let action = fsm
+            .consume(publish(3))
+            .run(crate::client::MqttInstant::from_secs(4));
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::ProtocolViolation(
+                    ProtocolViolation::ReceiveMaximumExceeded
+                ))
+            ),
+            "Got action: {action:?}"
+        );
+        assert!(!fsm.is_connected());
+    }
+
+    #[test]
+    fn check_connect_errors() {
+        let connect = |client_identifier| mqtt_format::v5::packets::connect::MConnect {
+            client_identifier,
+            username: None,
+            password: None,
+            clean_start: false,
+            will: None,
+            properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+            keep_alive: 0,
+        };
+        let now = crate::client::MqttInstant::from_secs(0);
+
+        let mut fsm = MqttClientFSM::default();
+        fsm.handle_connect(now, connect("testing")).unwrap();
+        assert_eq!(
+            fsm.handle_connect(now, connect("testing")).err(),
+            Some(crate::client::ConnectError::NotDisconnected)
+        );
+
+        fsm.connection_lost(now);
+        assert_eq!(
+            fsm.handle_connect(now, connect("other")).err(),
+            Some(crate::client::ConnectError::ClientIdentifierChanged)
+        );
+        assert_eq!(
+            fsm.handle_connect(now, connect("")).err(),
+            Some(crate::client::ConnectError::ClientIdentifierChanged)
+        );
+        assert!(fsm.handle_connect(now, connect("testing")).is_ok());
+    }
+
+    #[test]
+    fn check_protocol_violation_disconnect() {
+        let violation = ProtocolViolation::ReceiveMaximumExceeded;
+        let disconnect = violation.disconnect();
+
+        assert_eq!(
+            disconnect.reason_code,
+            mqtt_format::v5::packets::disconnect::DisconnectReasonCode::ReceiveMaximumExceeded
+        );
+        assert_eq!(
+            disconnect.properties.reason_string().map(|reason| reason.0),
+            Some("Receive maximum exceeded")
+        );
+    }
+
+    #[test]
+    fn check_receive_maximum_capped_to_received_store() {
+        let connect = |receive_maximum: Option<u16>| mqtt_format::v5::packets::connect::MConnect {
+            client_identifier: "testing",
+            username: None,
+            password: None,
+            clean_start: false,
+            will: None,
+            properties: mqtt_format::v5::packets::connect::ConnectProperties {
+                receive_maximum: receive_maximum.map(|receive_maximum| {
+                    mqtt_format::v5::variable_header::ReceiveMaximum(
+                        receive_maximum.try_into().unwrap(),
+                    )
+                }),
+                ..mqtt_format::v5::packets::connect::ConnectProperties::new()
+            },
+            keep_alive: 10,
+        };
+        let sent_receive_maximum = |action: ExpectedAction<'_>| {
+            let ExpectedAction::SendPacket(mqtt_format::v5::packets::MqttPacket::Connect(connect)) =
+                action
+            else {
+                panic!("Expected a CONNECT, got: {action:?}");
+            };
+            connect
+                .properties
+                .receive_maximum()
+                .map(|receive_maximum| receive_maximum.0.get())
+        };
+        let new_fsm = || {
+            MqttClientFSM::new(
+                crate::client::UsizePacketIdentifierStore::new(),
+                crate::client::ArrayReceivedPacketIdentifierStore::<16>::new(),
+            )
+        };
+
+        let mut fsm = new_fsm();
+        let action = fsm
+            .handle_connect(crate::client::MqttInstant::from_secs(0), connect(None))
+            .unwrap();
+        assert_eq!(sent_receive_maximum(action), Some(16));
+
+        let mut fsm = new_fsm();
+        let action = fsm
+            .handle_connect(
+                crate::client::MqttInstant::from_secs(0),
+                connect(Some(1000)),
+            )
+            .unwrap();
+        assert_eq!(sent_receive_maximum(action), Some(16));
+
+        let mut fsm = new_fsm();
+        let action = fsm
+            .handle_connect(crate::client::MqttInstant::from_secs(0), connect(Some(4)))
+            .unwrap();
+        assert_eq!(sent_receive_maximum(action), Some(4));
+    }
+
+    #[test]
+    fn check_protocol_violations() {
+        let connect = |fsm: &mut MqttClientFSM| {
+            fsm.handle_connect(
+                crate::client::MqttInstant::from_secs(0),
+                mqtt_format::v5::packets::connect::MConnect {
+                    client_identifier: "testing",
+                    username: None,
+                    password: None,
+                    clean_start: false,
+                    will: None,
+                    properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+                    keep_alive: 0,
+                },
+            )
+            .unwrap();
+        };
+        let connack = |reason_code| {
+            mqtt_format::v5::packets::MqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            )
+        };
+        let is_violation = |action: &Option<ExpectedAction<'_>>, violation: ProtocolViolation| {
+            matches!(
+                action,
+                Some(ExpectedAction::ProtocolViolation(reported)) if *reported == violation
+            )
+        };
+        let now = crate::client::MqttInstant::from_secs(1);
+
+        let mut fsm = MqttClientFSM::default();
+        let subscribe = mqtt_format::v5::packets::subscribe::MSubscribe {
+            packet_identifier: mqtt_format::v5::variable_header::PacketIdentifier(
+                1.try_into().unwrap(),
+            ),
+            properties: mqtt_format::v5::packets::subscribe::SubscribeProperties::new(),
+            subscriptions: mqtt_format::v5::packets::subscribe::Subscriptions::parse_complete(
+                b"\x00\x03a/b\x00",
+            )
+            .unwrap(),
+        };
+        assert_eq!(
+            fsm.subscribe(now, subscribe).err(),
+            Some(RequestError::NotConnected)
+        );
+
+        // Only a CONNACK may answer the CONNECT
+        connect(&mut fsm);
+        let action = fsm
+            .consume(MqttPacket::Pingresp(
+                mqtt_format::v5::packets::pingresp::MPingresp,
+            ))
+            .run(now);
+        assert!(is_violation(&action, ProtocolViolation::UnexpectedPacket));
+        assert!(!fsm.is_connected());
+
+        connect(&mut fsm);
+        let action = fsm
+            .consume(connack(
+                mqtt_format::v5::packets::connack::ConnackReasonCode::NotAuthorized,
+            ))
+            .run(now);
+        assert!(matches!(action, Some(ExpectedAction::Disconnect)));
+
+        connect(&mut fsm);
+        let _ = fsm
+            .consume(connack(
+                mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+            ))
+            .run(now);
+        let action = fsm
+            .consume(MqttPacket::Puback(
+                mqtt_format::v5::packets::puback::MPuback {
+                    packet_identifier: mqtt_format::v5::variable_header::PacketIdentifier(
+                        5.try_into().unwrap(),
+                    ),
+                    reason: PubackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                },
+            ))
+            .run(now);
+        assert!(is_violation(
+            &action,
+            ProtocolViolation::UnknownPacketIdentifier
+        ));
+        assert!(!fsm.is_connected());
+
+        connect(&mut fsm);
+        let _ = fsm
+            .consume(connack(
+                mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+            ))
+            .run(now);
+        let action = fsm
+            .consume(MqttPacket::Pingresp(
+                mqtt_format::v5::packets::pingresp::MPingresp,
+            ))
+            .run(now);
+        assert!(is_violation(&action, ProtocolViolation::UnexpectedPingresp));
+        assert!(!fsm.is_connected());
+    }
+
+    #[test]
+    fn check_restored_session() {
+        let mut fsm = MqttClientFSM::default();
+        let id =
+            |id: u16| mqtt_format::v5::variable_header::PacketIdentifier(id.try_into().unwrap());
+
+        assert!(fsm.restore_publish(id(3)));
+        assert!(!fsm.restore_publish(id(3)));
+        assert_eq!(fsm.restore_received(id(42)), Ok(true));
+
+        let connect = |fsm: &mut MqttClientFSM, session_present| {
+            fsm.handle_connect(
+                crate::client::MqttInstant::from_secs(0),
+                mqtt_format::v5::packets::connect::MConnect {
+                    client_identifier: "testing",
+                    username: None,
+                    password: None,
+                    clean_start: false,
+                    will: None,
+                    properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+                    keep_alive: 0,
+                },
+            )
+            .unwrap();
+
+            let action = fsm
+                .consume(mqtt_format::v5::packets::MqttPacket::Connack(
+                    mqtt_format::v5::packets::connack::MConnack {
+                        session_present,
+                        reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                        properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                    },
+                ))
+                .run(crate::client::MqttInstant::from_secs(0));
+            assert!(action.is_none());
+        };
+        connect(&mut fsm, true);
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Puback(
+                mqtt_format::v5::packets::puback::MPuback {
+                    packet_identifier: id(3),
+                    reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(1));
+        assert!(matches!(action, Some(ExpectedAction::ReleasePacket { id }) if id.0.get() == 3));
+
+        let publish = mqtt_format::v5::packets::publish::MPublish {
+            duplicate: true,
+            quality_of_service: mqtt_format::v5::qos::QualityOfService::ExactlyOnce,
+            retain: false,
+            topic_name: "foo",
+            packet_identifier: Some(id(42)),
+            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+            payload: &[],
+        };
+
+        // Already received before the session was persisted, so only acknowledged
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Publish(
+                publish.clone(),
+            ))
+            .run(crate::client::MqttInstant::from_secs(2));
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::SendPacket(
+                    mqtt_format::v5::packets::MqttPacket::Pubrec(..)
+                ))
+            ),
+            "Got action: {action:?}"
+        );
+
+        // A server that lost the session will not release the identifier, so it is forgotten
+        fsm.connection_lost(crate::client::MqttInstant::from_secs(3));
+        connect(&mut fsm, false);
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Publish(publish))
+            .run(crate::client::MqttInstant::from_secs(4));
+        assert!(
+            matches!(
+                action,
+                Some(ExpectedAction::ReceivePacket(
+                    crate::client::ReceivePacket::AcknowledgeNeeded { .. }
+                ))
+            ),
+            "Got action: {action:?}"
+        );
+    }
+
+    #[test]
+    fn check_unsubscribe() {
+        use mqtt_format::v5::packets::MqttPacket;
+
+        use crate::client::packet_identifier_store::PacketIdentifierStore;
+
+        let mut fsm = MqttClientFSM::default();
+
+        fsm.handle_connect(
+            crate::client::MqttInstant::from_secs(0),
+            mqtt_format::v5::packets::connect::MConnect {
+                client_identifier: "testing",
+                username: None,
+                password: None,
+                clean_start: false,
+                will: None,
+                properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+                keep_alive: 0,
+            },
+        )
+        .unwrap();
+
+        let action = fsm
+            .consume(MqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(0));
+        assert!(action.is_none());
+
+        let action = fsm.unsubscribe(
+            crate::client::MqttInstant::from_secs(1),
+            mqtt_format::v5::packets::unsubscribe::MUnsubscribe {
+                packet_identifier: mqtt_format::v5::variable_header::PacketIdentifier(
+                    1.try_into().unwrap(),
+                ),
+                properties: mqtt_format::v5::packets::unsubscribe::UnsubscribeProperties::new(),
+                unsubscriptions:
+                    mqtt_format::v5::packets::unsubscribe::Unsubscriptions::parse_complete(
+                        b"\x00\x03a/b",
+                    )
+                    .unwrap(),
+            },
+        );
+
+        let Ok(ExpectedAction::SendPacket(MqttPacket::Unsubscribe(unsubscribe))) = action else {
+            panic!("Expected an unsubscribe, got: {action:?}");
+        };
+        let packet_identifier = unsubscribe.packet_identifier;
+
+        let action = fsm
+            .consume(MqttPacket::Unsuback(
+                mqtt_format::v5::packets::unsuback::MUnsuback {
+                    packet_identifier,
+                    properties: mqtt_format::v5::packets::unsuback::UnsubackProperties::new(),
+                    reasons: &[mqtt_format::v5::packets::unsuback::UnsubackReasonCode::Success],
+                },
+            ))
+            .run(crate::client::MqttInstant::from_secs(2));
+        assert!(action.is_none());
+        assert!(!fsm.client_pis.contains(packet_identifier));
+    }
+
+    struct ChallengeAuthenticator;
+
+    impl crate::client::Authenticator for ChallengeAuthenticator {
+        fn method(&self) -> &'static str {
+            "CHALLENGE"
+        }
+
+        fn start(&mut self) -> Option<&[u8]> {
+            Some(b"hello")
+        }
+
+        fn challenge(
+            &mut self,
+            data: Option<&[u8]>,
+        ) -> Result<Option<&[u8]>, crate::client::AuthenticationError> {
+            match data {
+                Some(b"challenge") => Ok(Some(b"response")),
+                _ => Err(crate::client::AuthenticationError::InvalidServerData),
+            }
+        }
+
+        fn finish(
+            &mut self,
+            data: Option<&[u8]>,
+        ) -> Result<(), crate::client::AuthenticationError> {
+            match data {
+                Some(b"done") => Ok(()),
+                _ => Err(crate::client::AuthenticationError::ServerNotVerified),
+            }
+        }
+    }
+
+    fn auth(
+        reason: mqtt_format::v5::packets::auth::AuthReasonCode,
+        method: &'static str,
+        data: &'static [u8],
+    ) -> mqtt_format::v5::packets::MqttPacket<'static> {
+        let mut properties = mqtt_format::v5::packets::auth::AuthProperties::new();
+        properties.authentication_method = Some(
+            mqtt_format::v5::variable_header::AuthenticationMethod(method),
+        );
+        properties.authentication_data =
+            Some(mqtt_format::v5::variable_header::AuthenticationData(data));
+
+        mqtt_format::v5::packets::MqttPacket::Auth(mqtt_format::v5::packets::auth::MAuth {
+            reason,
+            properties,
+        })
+    }
+
+    #[test]
+    fn check_enhanced_authentication() {
+        use mqtt_format::v5::packets::MqttPacket;
+        use mqtt_format::v5::packets::auth::AuthReasonCode;
+
+        let mut fsm = MqttClientFSM::default();
+        let mut authenticator = ChallengeAuthenticator;
+
+        let action = fsm
+            .handle_connect_with_authenticator(
+                crate::client::MqttInstant::from_secs(0),
+                mqtt_format::v5::packets::connect::MConnect {
+                    client_identifier: "testing",
+                    username: None,
+                    password: None,
+                    clean_start: false,
+                    will: None,
+                    properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+                    keep_alive: 0,
+                },
+                &mut authenticator,
+            )
+            .unwrap();
+        let ExpectedAction::SendPacket(MqttPacket::Connect(connect)) = action else {
+            panic!("Expected a connect, got: {action:?}");
+        };
+        assert_eq!(
+            connect.properties.authentication_method().unwrap().0,
+            "CHALLENGE"
+        );
+        assert_eq!(
+            connect.properties.authentication_data().unwrap().0,
+            b"hello"
+        );
+
+        let action = fsm
+            .consume(auth(
+                AuthReasonCode::ContinueAuthentication,
+                "CHALLENGE",
+                b"challenge",
+            ))
+            .run_with_authenticator(crate::client::MqttInstant::from_secs(1), &mut authenticator);
+        let Some(ExpectedAction::SendPacket(MqttPacket::Auth(auth_packet))) = action else {
+            panic!("Expected an auth packet, got: {action:?}");
+        };
+        assert_eq!(auth_packet.reason, AuthReasonCode::ContinueAuthentication);
+        assert_eq!(
+            auth_packet.properties.authentication_data().unwrap().0,
+            b"response"
+        );
+
+        let mut properties = mqtt_format::v5::packets::connack::ConnackProperties::new();
+        properties.authentication_method = Some(
+            mqtt_format::v5::variable_header::AuthenticationMethod("CHALLENGE"),
+        );
+        properties.authentication_data = Some(
+            mqtt_format::v5::variable_header::AuthenticationData(b"done"),
+        );
+        let action = fsm
+            .consume(MqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties,
+                },
+            ))
+            .run_with_authenticator(crate::client::MqttInstant::from_secs(2), &mut authenticator);
+        assert!(action.is_none());
+        assert!(fsm.is_connected());
+
+        let action =
+            fsm.reauthenticate(crate::client::MqttInstant::from_secs(3), &mut authenticator);
+        let Some(ExpectedAction::SendPacket(MqttPacket::Auth(auth_packet))) = action else {
+            panic!("Expected an auth packet, got: {action:?}");
+        };
+        assert_eq!(auth_packet.reason, AuthReasonCode::ReAuthenticate);
+        assert!(
+            fsm.reauthenticate(crate::client::MqttInstant::from_secs(3), &mut authenticator)
+                .is_none()
+        );
+
+        let action = fsm
+            .consume(auth(
+                AuthReasonCode::ContinueAuthentication,
+                "CHALLENGE",
+                b"challenge",
+            ))
+            .run_with_authenticator(crate::client::MqttInstant::from_secs(4), &mut authenticator);
+        assert!(matches!(
+            action,
+            Some(ExpectedAction::SendPacket(MqttPacket::Auth(_)))
+        ));
+
+        let action = fsm
+            .consume(auth(AuthReasonCode::Success, "CHALLENGE", b"done"))
+            .run_with_authenticator(crate::client::MqttInstant::from_secs(5), &mut authenticator);
+        assert!(action.is_none());
+        assert!(fsm.is_connected());
+
+        let action = fsm
+            .consume(auth(AuthReasonCode::Success, "CHALLENGE", b"done"))
+            .run_with_authenticator(crate::client::MqttInstant::from_secs(6), &mut authenticator);
+        assert!(matches!(
+            action,
+            Some(ExpectedAction::AuthenticationFailed(
+                crate::client::AuthenticationError::Unexpected
+            ))
+        ));
+        assert!(!fsm.is_connected());
+    }
+
+    #[test]
+    fn check_enhanced_authentication_failure() {
+        use mqtt_format::v5::packets::auth::AuthReasonCode;
+
+        let mut fsm = MqttClientFSM::default();
+        let mut authenticator = ChallengeAuthenticator;
+
+        let _ = fsm
+            .handle_connect_with_authenticator(
+                crate::client::MqttInstant::from_secs(0),
+                mqtt_format::v5::packets::connect::MConnect {
+                    client_identifier: "testing",
+                    username: None,
+                    password: None,
+                    clean_start: false,
+                    will: None,
+                    properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+                    keep_alive: 0,
+                },
+                &mut authenticator,
+            )
+            .unwrap();
+
+        let action = fsm
+            .consume(auth(
+                AuthReasonCode::ContinueAuthentication,
+                "OTHER",
+                b"challenge",
+            ))
+            .run_with_authenticator(crate::client::MqttInstant::from_secs(1), &mut authenticator);
+        assert!(matches!(
+            action,
+            Some(ExpectedAction::AuthenticationFailed(
+                crate::client::AuthenticationError::MethodMismatch
+            ))
+        ));
+        assert!(matches!(
+            fsm.connection_state,
+            ConnectionState::Disconnected
+        ));
+    }
 }
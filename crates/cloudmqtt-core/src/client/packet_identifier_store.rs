@@ -30,6 +30,15 @@ fn get_next_free(
     fn release(&mut self, id: mqtt_format::v5::variable_header::PacketIdentifier);
     fn release_non_publish_slots(&mut self);
 
+    /// Mark a specific identifier as used, like one restored from a persisted session
+    ///
+    /// Returns whether the identifier was free and could be claimed.
+    fn claim(
+        &mut self,
+        id: mqtt_format::v5::variable_header::PacketIdentifier,
+        usage: PacketIdentifierUsage,
+    ) -> bool;
+
     fn contains(&self, id: mqtt_format::v5::variable_header::PacketIdentifier) -> bool;
 }
 
@@ -84,6 +93,22 @@ fn release_non_publish_slots(&mut self) {
         self.slots &= self.is_publish
     }
 
+    fn claim(
+        &mut self,
+        id: mqtt_format::v5::variable_header::PacketIdentifier,
+        usage: PacketIdentifierUsage,
+    ) -> bool {
+        if self.contains(id) || (id.0.get() as u32 - 1) >= usize::BITS {
+            return false;
+        }
+
+        let mask = 0b1 << (id.0.get() - 1);
+        trace!(bit_index = (id.0.get() - 1), "Claiming index");
+        self.slots |= mask;
+        self.is_publish |= mask & (if usage.is_publish() { usize::MAX } else { 0 });
+        true
+    }
+
     fn contains(&self, id: mqtt_format::v5::variable_header::PacketIdentifier) -> bool {
         let id = id.0.get() as usize;
         if (id as u32 - 1) >= usize::BITS {
@@ -97,10 +122,447 @@ fn contains(&self, id: mqtt_format::v5::variable_header::PacketIdentifier) -> bo
     }
 }
 
+/// How many packet identifiers there are, 0 is not one of them
+const IDENTIFIERS: usize = u16::MAX as usize;
+
+/// The index of an identifier in a store, with the identifier `n` at index `n - 1`
+fn index(id: mqtt_format::v5::variable_header::PacketIdentifier) -> usize {
+    usize::from(id.0.get()) - 1
+}
+
+fn identifier(index: usize) -> mqtt_format::v5::variable_header::PacketIdentifier {
+    let id = u16::try_from(index + 1)
+        .ok()
+        .and_then(|id| id.try_into().ok())
+        .expect("Indices are below the number of identifiers");
+    mqtt_format::v5::variable_header::PacketIdentifier(id)
+}
+
+/// How many words a [`BitmapPacketIdentifierStore`] needs for the given number of identifiers
+pub const fn bitmap_words(identifiers: usize) -> usize {
+    identifiers.div_ceil(64)
+}
+
+/// Identifiers in use, one bit per identifier
+///
+/// Shared by the stores that keep a bitmap, whether fixed or growable.
+struct Bitmap<'s> {
+    slots: &'s mut [u64],
+    is_publish: &'s mut [u64],
+
+    /// How many identifiers fit, at most one per bit
+    capacity: usize,
+}
+
+impl Bitmap<'_> {
+    fn is_used(&self, index: usize) -> bool {
+        is_used(self.slots, index)
+    }
+
+    /// The first free index from `next` on, without wrapping around
+    fn find_free_from(&self, next: usize) -> Option<usize> {
+        (next.min(self.capacity)..self.capacity).find(|index| !self.is_used(*index))
+    }
+
+    /// The first free index, starting at `next` and wrapping around
+    fn find_free(&self, next: usize) -> Option<usize> {
+        self.find_free_from(next)
+            .or_else(|| (0..next.min(self.capacity)).find(|index| !self.is_used(*index)))
+    }
+
+    fn take(&mut self, index: usize, usage: PacketIdentifierUsage) {
+        let mask = 1 << (index % 64);
+        self.slots[index / 64] |= mask;
+        if usage.is_publish() {
+            self.is_publish[index / 64] |= mask;
+        }
+    }
+
+    fn release(&mut self, index: usize) {
+        if index >= self.capacity {
+            return;
+        }
+
+        let mask = !(1 << (index % 64));
+        self.slots[index / 64] &= mask;
+        self.is_publish[index / 64] &= mask;
+    }
+
+    fn release_non_publish_slots(&mut self) {
+        for (slots, is_publish) in self.slots.iter_mut().zip(self.is_publish.iter()) {
+            *slots &= is_publish;
+        }
+    }
+}
+
+fn is_used(slots: &[u64], index: usize) -> bool {
+    slots
+        .get(index / 64)
+        .is_some_and(|word| word & (1 << (index % 64)) != 0)
+}
+
+/// A [`PacketIdentifierStore`] for up to `N` identifiers, without allocating
+///
+/// Identifiers are handed out round-robin, so that a released identifier is not reused right
+/// away. Choose `N` as the number of publishes the receive maximum of the server allows to be in
+/// flight, plus room for subscribes and unsubscribes; with `N = 65535` every identifier can be
+/// used.
+///
+/// Each identifier takes a bit, kept in `WORDS` words which must be [`bitmap_words`]`(N)`, e.g.
+/// `BitmapPacketIdentifierStore<300, { bitmap_words(300) }>`. Stable Rust cannot derive it from
+/// `N` yet, a mismatch fails to compile.
+#[derive(Debug)]
+pub struct BitmapPacketIdentifierStore<const N: usize, const WORDS: usize> {
+    slots: [u64; WORDS],
+    is_publish: [u64; WORDS],
+
+    /// The index to look for a free identifier first
+    next: usize,
+}
+
+impl<const N: usize, const WORDS: usize> BitmapPacketIdentifierStore<N, WORDS> {
+    pub const fn new() -> Self {
+        const {
+            assert!(
+                WORDS == bitmap_words(N),
+                "WORDS must be bitmap_words(N) for a BitmapPacketIdentifierStore"
+            );
+        }
+
+        Self {
+            slots: [0; WORDS],
+            is_publish: [0; WORDS],
+            next: 0,
+        }
+    }
+
+    fn bitmap(&mut self) -> Bitmap<'_> {
+        Bitmap {
+            slots: &mut self.slots,
+            is_publish: &mut self.is_publish,
+            capacity: N.min(IDENTIFIERS),
+        }
+    }
+}
+
+impl<const N: usize, const WORDS: usize> Default for BitmapPacketIdentifierStore<N, WORDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const WORDS: usize> PacketIdentifierStore
+    for BitmapPacketIdentifierStore<N, WORDS>
+{
+    fn get_next_free(
+        &mut self,
+        usage: PacketIdentifierUsage,
+    ) -> Option<mqtt_format::v5::variable_header::PacketIdentifier> {
+        let next = self.next;
+        let mut bitmap = self.bitmap();
+        let index = bitmap.find_free(next)?;
+        bitmap.take(index, usage);
+
+        trace!(?index, "Found a slot");
+        self.next = index + 1;
+        Some(identifier(index))
+    }
+
+    fn release(&mut self, id: mqtt_format::v5::variable_header::PacketIdentifier) {
+        self.bitmap().release(index(id));
+    }
+
+    fn release_non_publish_slots(&mut self) {
+        self.bitmap().release_non_publish_slots();
+    }
+
+    fn claim(
+        &mut self,
+        id: mqtt_format::v5::variable_header::PacketIdentifier,
+        usage: PacketIdentifierUsage,
+    ) -> bool {
+        let index = index(id);
+        let mut bitmap = self.bitmap();
+        if index >= bitmap.capacity || bitmap.is_used(index) {
+            return false;
+        }
+
+        bitmap.take(index, usage);
+        true
+    }
+
+    fn contains(&self, id: mqtt_format::v5::variable_header::PacketIdentifier) -> bool {
+        index(id) < N && is_used(&self.slots, index(id))
+    }
+}
+
+/// A [`PacketIdentifierStore`] that grows with the number of identifiers in use, up to every one
+/// of them
+///
+/// Identifiers are handed out round-robin, so that a released identifier is not reused right
+/// away. The store rather grows than wrap around to a released one.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Default)]
+pub struct VecPacketIdentifierStore {
+    slots: alloc::vec::Vec<u64>,
+    is_publish: alloc::vec::Vec<u64>,
+
+    /// The index to look for a free identifier first
+    next: usize,
+}
+
+#[cfg(feature = "alloc")]
+impl VecPacketIdentifierStore {
+    pub const fn new() -> Self {
+        Self {
+            slots: alloc::vec::Vec::new(),
+            is_publish: alloc::vec::Vec::new(),
+            next: 0,
+        }
+    }
+
+    fn bitmap(&mut self) -> Bitmap<'_> {
+        Bitmap {
+            capacity: (self.slots.len() * 64).min(IDENTIFIERS),
+            slots: &mut self.slots,
+            is_publish: &mut self.is_publish,
+        }
+    }
+
+    /// Make room for the given index, returns whether there can be such an index
+    fn grow_to(&mut self, index: usize) -> bool {
+        if index >= IDENTIFIERS {
+            return false;
+        }
+
+        let words = index / 64 + 1;
+        if self.slots.len() < words {
+            trace!(words, "Growing packet identifier store");
+            self.slots.resize(words, 0);
+            self.is_publish.resize(words, 0);
+        }
+        true
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl PacketIdentifierStore for VecPacketIdentifierStore {
+    fn get_next_free(
+        &mut self,
+        usage: PacketIdentifierUsage,
+    ) -> Option<mqtt_format::v5::variable_header::PacketIdentifier> {
+        let next = self.next;
+        let capacity = self.bitmap().capacity;
+        let index = match self.bitmap().find_free_from(next) {
+            Some(index) => index,
+            None if self.grow_to(capacity) => capacity,
+            None => self.bitmap().find_free(next)?,
+        };
+        self.bitmap().take(index, usage);
+
+        trace!(?index, "Found a slot");
+        self.next = index + 1;
+        Some(identifier(index))
+    }
+
+    fn release(&mut self, id: mqtt_format::v5::variable_header::PacketIdentifier) {
+        self.bitmap().release(index(id));
+    }
+
+    fn release_non_publish_slots(&mut self) {
+        self.bitmap().release_non_publish_slots();
+    }
+
+    fn claim(
+        &mut self,
+        id: mqtt_format::v5::variable_header::PacketIdentifier,
+        usage: PacketIdentifierUsage,
+    ) -> bool {
+        let index = index(id);
+        if !self.grow_to(index) || self.bitmap().is_used(index) {
+            return false;
+        }
+
+        self.bitmap().take(index, usage);
+        true
+    }
+
+    fn contains(&self, id: mqtt_format::v5::variable_header::PacketIdentifier) -> bool {
+        is_used(&self.slots, index(id))
+    }
+}
+
+/// Tracks the packet identifiers of incoming QoS 2 publishes
+///
+/// An identifier is recorded when the PUBLISH arrives and forgotten once the matching PUBREL was
+/// received. As long as it is recorded, any PUBLISH with the same identifier is a re-delivery and
+/// must not be handed to the application again.
+pub trait ReceivedPacketIdentifierStore {
+    /// Record the given identifier
+    ///
+    /// Returns whether the identifier was newly recorded (`Ok(true)`) or already present
+    /// (`Ok(false)`). If the store cannot hold any more identifiers, an error is returned.
+    fn insert(
+        &mut self,
+        id: mqtt_format::v5::variable_header::PacketIdentifier,
+    ) -> Result<bool, ReceivedPacketIdentifierStoreFull>;
+
+    /// Forget the given identifier, returns whether it was present
+    fn remove(&mut self, id: mqtt_format::v5::variable_header::PacketIdentifier) -> bool;
+
+    fn contains(&self, id: mqtt_format::v5::variable_header::PacketIdentifier) -> bool;
+
+    /// Forget all identifiers, as the server will not release them anymore
+    fn clear(&mut self);
+
+    /// How many identifiers the store can hold at once
+    ///
+    /// The receive maximum sent to the server is capped to it, so that a server that keeps to
+    /// the receive maximum never overflows the store.
+    fn capacity(&self) -> u16;
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ReceivedPacketIdentifierStoreFull;
+
+/// A [`ReceivedPacketIdentifierStore`] that can hold up to `N` identifiers at once
+#[derive(Debug)]
+pub struct ArrayReceivedPacketIdentifierStore<const N: usize> {
+    slots: [Option<mqtt_format::v5::variable_header::PacketIdentifier>; N],
+}
+
+impl<const N: usize> ArrayReceivedPacketIdentifierStore<N> {
+    pub const fn new() -> Self {
+        Self { slots: [None; N] }
+    }
+}
+
+impl<const N: usize> Default for ArrayReceivedPacketIdentifierStore<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ReceivedPacketIdentifierStore for ArrayReceivedPacketIdentifierStore<N> {
+    fn insert(
+        &mut self,
+        id: mqtt_format::v5::variable_header::PacketIdentifier,
+    ) -> Result<bool, ReceivedPacketIdentifierStoreFull> {
+        if self.contains(id) {
+            trace!(?id, "Identifier already recorded");
+            return Ok(false);
+        }
+
+        let slot = self
+            .slots
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ReceivedPacketIdentifierStoreFull)?;
+
+        *slot = Some(id);
+        Ok(true)
+    }
+
+    fn remove(&mut self, id: mqtt_format::v5::variable_header::PacketIdentifier) -> bool {
+        match self.slots.iter_mut().find(|slot| **slot == Some(id)) {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn contains(&self, id: mqtt_format::v5::variable_header::PacketIdentifier) -> bool {
+        self.slots.contains(&Some(id))
+    }
+
+    fn clear(&mut self) {
+        self.slots = [None; N];
+    }
+
+    fn capacity(&self) -> u16 {
+        u16::try_from(N).unwrap_or(u16::MAX)
+    }
+}
+
+/// A [`ReceivedPacketIdentifierStore`] that grows with the identifiers recorded, up to every one of
+/// them
+#[cfg(feature = "alloc")]
+#[derive(Debug, Default)]
+pub struct VecReceivedPacketIdentifierStore {
+    slots: alloc::vec::Vec<u64>,
+}
+
+#[cfg(feature = "alloc")]
+impl VecReceivedPacketIdentifierStore {
+    pub const fn new() -> Self {
+        Self {
+            slots: alloc::vec::Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl ReceivedPacketIdentifierStore for VecReceivedPacketIdentifierStore {
+    fn insert(
+        &mut self,
+        id: mqtt_format::v5::variable_header::PacketIdentifier,
+    ) -> Result<bool, ReceivedPacketIdentifierStoreFull> {
+        if self.contains(id) {
+            trace!(?id, "Identifier already recorded");
+            return Ok(false);
+        }
+
+        let index = index(id);
+        let words = index / 64 + 1;
+        if self.slots.len() < words {
+            trace!(words, "Growing received packet identifier store");
+            self.slots.resize(words, 0);
+        }
+
+        self.slots[index / 64] |= 1 << (index % 64);
+        Ok(true)
+    }
+
+    fn remove(&mut self, id: mqtt_format::v5::variable_header::PacketIdentifier) -> bool {
+        let index = index(id);
+        let Some(word) = self.slots.get_mut(index / 64) else {
+            return false;
+        };
+
+        let mask = 1 << (index % 64);
+        let present = *word & mask != 0;
+        *word &= !mask;
+        present
+    }
+
+    fn contains(&self, id: mqtt_format::v5::variable_header::PacketIdentifier) -> bool {
+        is_used(&self.slots, index(id))
+    }
+
+    fn clear(&mut self) {
+        self.slots.clear();
+    }
+
+    fn capacity(&self) -> u16 {
+        u16::MAX
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::ArrayReceivedPacketIdentifierStore;
+    use super::BitmapPacketIdentifierStore;
     use super::PacketIdentifierStore;
+    use super::ReceivedPacketIdentifierStore;
+    use super::ReceivedPacketIdentifierStoreFull;
     use super::UsizePacketIdentifierStore;
+    #[cfg(feature = "alloc")]
+    use super::VecPacketIdentifierStore;
+    #[cfg(feature = "alloc")]
+    use super::VecReceivedPacketIdentifierStore;
+    use super::bitmap_words;
     use crate::client::packet_identifier_store::PacketIdentifierUsage;
 
     #[test]
@@ -125,4 +587,167 @@ fn check_slot_reuse_after_release() {
             .unwrap();
         assert_eq!(fourth.0.get(), 3);
     }
+
+    #[test]
+    fn check_claim() {
+        let mut store = UsizePacketIdentifierStore::default();
+        let id =
+            |id: u16| mqtt_format::v5::variable_header::PacketIdentifier(id.try_into().unwrap());
+
+        assert!(store.claim(id(2), PacketIdentifierUsage::Publish));
+        assert!(!store.claim(id(2), PacketIdentifierUsage::Publish));
+        assert!(!store.claim(id(65), PacketIdentifierUsage::Publish));
+        assert!(store.contains(id(2)));
+
+        store.release_non_publish_slots();
+        assert!(store.contains(id(2)));
+
+        let next = store.get_next_free(PacketIdentifierUsage::Publish).unwrap();
+        assert_eq!(next.0.get(), 1);
+        let next = store.get_next_free(PacketIdentifierUsage::Publish).unwrap();
+        assert_eq!(next.0.get(), 3);
+    }
+
+    #[test]
+    fn check_bitmap_round_robin() {
+        let mut store = BitmapPacketIdentifierStore::<128, { bitmap_words(128) }>::new();
+        let id =
+            |id: u16| mqtt_format::v5::variable_header::PacketIdentifier(id.try_into().unwrap());
+
+        let first = store.get_next_free(PacketIdentifierUsage::Publish).unwrap();
+        assert_eq!(first, id(1));
+        store.release(first);
+
+        // Released identifiers are only reused once every other one was handed out
+        assert_eq!(
+            store.get_next_free(PacketIdentifierUsage::Publish),
+            Some(id(2))
+        );
+        assert!(store.claim(id(3), PacketIdentifierUsage::NonPublish));
+        assert!(!store.claim(id(129), PacketIdentifierUsage::Publish));
+
+        for expected in 4..=128 {
+            assert_eq!(
+                store.get_next_free(PacketIdentifierUsage::Publish),
+                Some(id(expected))
+            );
+        }
+        assert_eq!(
+            store.get_next_free(PacketIdentifierUsage::Publish),
+            Some(id(1))
+        );
+        assert_eq!(store.get_next_free(PacketIdentifierUsage::Publish), None);
+
+        store.release_non_publish_slots();
+        assert!(!store.contains(id(3)));
+        assert!(store.contains(id(2)));
+        assert_eq!(
+            store.get_next_free(PacketIdentifierUsage::Publish),
+            Some(id(3))
+        );
+
+        // The capacity is in identifiers, not rounded up to anything
+        let mut store = BitmapPacketIdentifierStore::<100, { bitmap_words(100) }>::new();
+        assert!(store.claim(id(100), PacketIdentifierUsage::Publish));
+        assert!(!store.claim(id(101), PacketIdentifierUsage::Publish));
+        assert!(!store.contains(id(101)));
+        for expected in 1..=99 {
+            assert_eq!(
+                store.get_next_free(PacketIdentifierUsage::Publish),
+                Some(id(expected))
+            );
+        }
+        assert_eq!(store.get_next_free(PacketIdentifierUsage::Publish), None);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn check_vec_store_uses_every_identifier() {
+        let mut store = VecPacketIdentifierStore::new();
+        let id =
+            |id: u16| mqtt_format::v5::variable_header::PacketIdentifier(id.try_into().unwrap());
+
+        for expected in 1..=u16::MAX {
+            assert_eq!(
+                store.get_next_free(PacketIdentifierUsage::Publish),
+                Some(id(expected))
+            );
+        }
+        assert_eq!(store.get_next_free(PacketIdentifierUsage::Publish), None);
+
+        store.release(id(500));
+        store.release(id(7));
+        assert_eq!(
+            store.get_next_free(PacketIdentifierUsage::NonPublish),
+            Some(id(7))
+        );
+        assert_eq!(
+            store.get_next_free(PacketIdentifierUsage::Publish),
+            Some(id(500))
+        );
+
+        // The store grows before it wraps around to a released identifier
+        let mut store = VecPacketIdentifierStore::new();
+        for expected in 1..=64 {
+            assert_eq!(
+                store.get_next_free(PacketIdentifierUsage::Publish),
+                Some(id(expected))
+            );
+        }
+        store.release(id(1));
+        assert_eq!(
+            store.get_next_free(PacketIdentifierUsage::Publish),
+            Some(id(65))
+        );
+
+        let mut store = VecPacketIdentifierStore::new();
+        assert!(!store.contains(id(1000)));
+        assert!(store.claim(id(1000), PacketIdentifierUsage::Publish));
+        assert!(store.contains(id(1000)));
+        assert_eq!(
+            store.get_next_free(PacketIdentifierUsage::Publish),
+            Some(id(1))
+        );
+    }
+
+    #[test]
+    fn check_received_identifiers_are_deduplicated() {
+        let mut store = ArrayReceivedPacketIdentifierStore::<2>::new();
+        let id =
+            |id: u16| mqtt_format::v5::variable_header::PacketIdentifier(id.try_into().unwrap());
+
+        assert_eq!(store.insert(id(7)), Ok(true));
+        assert_eq!(store.insert(id(7)), Ok(false));
+        assert_eq!(store.insert(id(300)), Ok(true));
+        assert_eq!(store.insert(id(9)), Err(ReceivedPacketIdentifierStoreFull));
+
+        assert!(store.remove(id(7)));
+        assert!(!store.remove(id(7)));
+        assert!(!store.contains(id(7)));
+        assert_eq!(store.insert(id(9)), Ok(true));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn check_vec_received_store_holds_every_identifier() {
+        let mut store = VecReceivedPacketIdentifierStore::new();
+        let id =
+            |id: u16| mqtt_format::v5::variable_header::PacketIdentifier(id.try_into().unwrap());
+
+        assert_eq!(store.capacity(), u16::MAX);
+        for expected in 1..=u16::MAX {
+            assert_eq!(store.insert(id(expected)), Ok(true));
+        }
+        assert_eq!(store.insert(id(u16::MAX)), Ok(false));
+
+        assert!(store.remove(id(300)));
+        assert!(!store.remove(id(300)));
+        assert!(!store.contains(id(300)));
+        assert!(store.contains(id(301)));
+
+        store.clear();
+        assert!(!store.contains(id(1)));
+        assert!(!store.remove(id(1)));
+        assert_eq!(store.insert(id(1)), Ok(true));
+    }
 }
@@ -8,5 +8,8 @@
 #![cfg_attr(test, allow(clippy::disallowed_methods))]
 #![deny(clippy::disallowed_types)]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 pub mod client;
 mod util;
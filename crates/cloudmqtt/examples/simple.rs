@@ -8,7 +8,9 @@
 
 #[tokio::main]
 async fn main() {
-    let mut client = CloudmqttClient::new_with_address("localhost:1883".to_string()).await;
+    let client = CloudmqttClient::new_with_address("localhost:1883")
+        .await
+        .unwrap();
 
     client.publish(b"What's up", "foo/bar").await.unwrap();
 
@@ -9,7 +9,9 @@
 
 #[tokio::main]
 async fn main() {
-    let mut client = CloudmqttClient::new_with_address("localhost:1883".to_string()).await;
+    let client = CloudmqttClient::new_with_address("localhost:1883")
+        .await
+        .unwrap();
 
     let whatsub = client.subscribe("whats/up").await.unwrap();
     let morestuff = client.subscribe("more/stuff").await.unwrap();
@@ -0,0 +1,210 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Acknowledging incoming QoS 1 and 2 publishes from the application
+//!
+//! By default the client acknowledges a publish as soon as it has handed it to the subscriptions.
+//! With [manual acknowledgement] every [`Message`](crate::message::Message) of such a publish
+//! carries an [`Acknowledger`] instead, and the PUBACK or PUBREC is only sent once the
+//! application used it. This way a message can be acknowledged only after it was processed, and
+//! the server sends it again if the connection is lost before that.
+//!
+//! [manual acknowledgement]: crate::connect::ConnectOptions::with_manual_acknowledgement
+
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use cloudmqtt_core::client::AcknowledgeAction;
+use mqtt_format::v5::packets::puback::PubackReasonCode;
+
+/// An acknowledgement the connection has to send
+#[derive(Debug)]
+pub(crate) struct Acknowledgement {
+    pub(crate) acknowledge: AcknowledgeAction,
+    pub(crate) reason: PubackReasonCode,
+}
+
+/// The handle to acknowledge an incoming publish with
+///
+/// If a publish was routed to several subscriptions, each of them gets its own handle, and the
+/// acknowledgement is sent once all of them were used. Should any of them carry an error reason,
+/// the first one is sent.
+///
+/// Dropping the handle without using it leaves the publish unacknowledged, until the server
+/// sends it again after a reconnection. The server only sends as many unacknowledged publishes
+/// as the receive maximum allows, so handles should not be held on to indefinitely.
+#[derive(Debug)]
+pub struct Acknowledger {
+    pending: Arc<Pending>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    state: Mutex<PendingState>,
+
+    /// Closed once the connection the publish arrived on is lost
+    sender: tokio::sync::mpsc::UnboundedSender<Acknowledgement>,
+}
+
+#[derive(Debug)]
+struct PendingState {
+    /// How many handles were not used yet
+    handles: usize,
+    reason: PubackReasonCode,
+    acknowledge: Option<AcknowledgeAction>,
+}
+
+impl Acknowledger {
+    pub(crate) fn new(
+        acknowledge: AcknowledgeAction,
+        sender: tokio::sync::mpsc::UnboundedSender<Acknowledgement>,
+    ) -> Self {
+        Self {
+            pending: Arc::new(Pending {
+                state: Mutex::new(PendingState {
+                    handles: 1,
+                    reason: PubackReasonCode::Success,
+                    acknowledge: Some(acknowledge),
+                }),
+                sender,
+            }),
+        }
+    }
+
+    /// Another handle that has to be used before the publish is acknowledged
+    pub(crate) fn share(&self) -> Self {
+        self.pending.state.lock().unwrap().handles += 1;
+
+        Self {
+            pending: self.pending.clone(),
+        }
+    }
+
+    /// Acknowledge the publish as received
+    pub fn ack(self) {
+        self.ack_with_reason(PubackReasonCode::Success)
+    }
+
+    /// Acknowledge the publish with the given reason
+    ///
+    /// An error reason, like [`PubackReasonCode::ImplementationSpecificError`], tells the server
+    /// that the publish was not accepted. It is then not sent again.
+    pub fn ack_with_reason(self, reason: PubackReasonCode) {
+        let mut state = self.pending.state.lock().unwrap();
+
+        if u8::from(reason) >= 0x80 && u8::from(state.reason) < 0x80 {
+            state.reason = reason;
+        }
+
+        state.handles -= 1;
+        if state.handles > 0 {
+            return;
+        }
+
+        let Some(acknowledge) = state.acknowledge.take() else {
+            return;
+        };
+
+        let acknowledgement = Acknowledgement {
+            acknowledge,
+            reason: state.reason,
+        };
+        if self.pending.sender.send(acknowledgement).is_err() {
+            tracing::debug!("Connection was lost before the publish was acknowledged");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_format::v5::packets::puback::PubackReasonCode;
+
+    use super::Acknowledger;
+
+    fn acknowledge_action() -> cloudmqtt_core::client::AcknowledgeAction {
+        let mut fsm = cloudmqtt_core::client::MqttClientFSM::default();
+        let now = cloudmqtt_core::client::MqttInstant::from_secs(0);
+
+        fsm.handle_connect(
+            now,
+            mqtt_format::v5::packets::connect::MConnect {
+                client_identifier: "testing",
+                username: None,
+                password: None,
+                clean_start: true,
+                will: None,
+                properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+                keep_alive: 0,
+            },
+        )
+        .unwrap();
+        let _ = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            ))
+            .run(now);
+
+        let action = fsm
+            .consume(mqtt_format::v5::packets::MqttPacket::Publish(
+                mqtt_format::v5::packets::publish::MPublish {
+                    duplicate: false,
+                    quality_of_service: mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                    retain: false,
+                    topic_name: "a/b",
+                    packet_identifier: Some(mqtt_format::v5::variable_header::PacketIdentifier(
+                        7.try_into().unwrap(),
+                    )),
+                    properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                    payload: b"hello",
+                },
+            ))
+            .run(now);
+
+        let Some(cloudmqtt_core::client::ExpectedAction::ReceivePacket(
+            cloudmqtt_core::client::ReceivePacket::AcknowledgeNeeded { acknowledge, .. },
+        )) = action
+        else {
+            panic!("Expected a publish to acknowledge, got: {action:?}");
+        };
+
+        acknowledge
+    }
+
+    #[test]
+    fn check_shared_acknowledgement() {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let acknowledger = Acknowledger::new(acknowledge_action(), sender);
+        let first = acknowledger.share();
+        let second = acknowledger.share();
+
+        acknowledger.ack();
+        first.ack_with_reason(PubackReasonCode::ImplementationSpecificError);
+        assert!(receiver.try_recv().is_err());
+
+        second.ack_with_reason(PubackReasonCode::NotAuthorized);
+        let acknowledgement = receiver.try_recv().unwrap();
+        assert_eq!(acknowledgement.acknowledge.packet_identifier().0.get(), 7);
+        assert_eq!(
+            acknowledgement.reason,
+            PubackReasonCode::ImplementationSpecificError
+        );
+    }
+
+    #[test]
+    fn check_dropped_handle_does_not_acknowledge() {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let acknowledger = Acknowledger::new(acknowledge_action(), sender);
+        let shared = acknowledger.share();
+
+        drop(shared);
+        acknowledger.ack();
+        assert!(receiver.try_recv().is_err());
+    }
+}
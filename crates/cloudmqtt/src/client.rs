@@ -4,165 +4,821 @@
 //   file, You can obtain one at http://mozilla.org/MPL/2.0/.
 //
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::num::NonZeroU16;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::Duration;
 
 use cloudmqtt_core::client::ExpectedAction;
 use cloudmqtt_core::client::MqttClientFSM;
 use cloudmqtt_core::client::MqttInstant;
+use cloudmqtt_core::client::VecPacketIdentifierStore;
+use cloudmqtt_core::client::VecReceivedPacketIdentifierStore;
 use futures::SinkExt;
 use futures::StreamExt;
+use mqtt_format::v5::packets::connack::ConnackReasonCode;
+use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
+use mqtt_format::v5::packets::puback::PubackReasonCode;
+use mqtt_format::v5::packets::suback::SubackReasonCode;
+use mqtt_format::v5::packets::unsuback::UnsubackReasonCode;
 use tokio::sync::Mutex;
+use tokio::time::Instant;
 use tokio_util::codec::FramedRead;
 use tokio_util::codec::FramedWrite;
 
 use crate::SendUsage;
+use crate::acknowledge::Acknowledgement;
+use crate::acknowledge::Acknowledger;
 use crate::codec::MqttPacket;
 use crate::codec::MqttPacketCodec;
+use crate::codec::MqttPacketCodecError;
+use crate::connect::ConnectOptions;
 use crate::error::Error;
+use crate::event::ConnectionEvent;
+use crate::event::DisconnectReason;
+use crate::message::Message;
+use crate::queue::PublishQueue;
+use crate::reconnect::BoxedTransport;
+use crate::reconnect::ConnectionFactory;
+use crate::reconnect::ReconnectPolicy;
+use crate::session::SharedSessionStore;
+use crate::topic_alias::TopicAliases;
 
 fn since(start: Instant) -> MqttInstant {
-    MqttInstant::new(start.elapsed().as_secs())
+    MqttInstant::from_millis(u64::try_from(start.elapsed().as_millis()).unwrap_or(u64::MAX))
 }
 
+#[derive(Clone)]
 pub struct CoreClient {
-    incoming_sender: tokio::sync::mpsc::Sender<MqttPacket>,
+    incoming_sender: tokio::sync::mpsc::Sender<Message>,
     connection_state: Arc<Mutex<ConnectionState>>,
+    reconnect: Option<Reconnect>,
+    events: tokio::sync::broadcast::Sender<ConnectionEvent>,
+
+    /// Whether a task is driving connections, cleared once the client is disconnected for good
+    running: tokio::sync::watch::Sender<bool>,
+
+    /// Set once the client is asked to disconnect, so that no connection is attempted anymore
+    stopping: tokio::sync::watch::Sender<bool>,
+
+    /// Publishes waiting for a connection, if the client queues them
+    queue: Option<Arc<PublishQueue>>,
+
+    /// Subscribes and unsubscribes, in the order they were requested
+    requests: tokio::sync::mpsc::UnboundedSender<SendUsage>,
 }
 
+/// How many connection events are kept for receivers that lag behind
+const CONNECTION_EVENTS_CAPACITY: usize = 16;
+
 enum ConnectionState {
     Unconnected {
-        client: MqttClientFSM,
+        session: Session,
     },
 
+    Connecting,
+
     Connected {
         sender: tokio::sync::mpsc::Sender<SendUsage>,
     },
 }
 
+type ClientFsm = MqttClientFSM<VecPacketIdentifierStore, VecReceivedPacketIdentifierStore>;
+
+/// Everything that has to survive the loss of a connection
+struct Session {
+    fsm: ClientFsm,
+    start: Instant,
+
+    outstanding: Outstanding,
+
+    /// Granted subscriptions, to restore if the server did not keep our session
+    subscriptions: Vec<MqttPacket>,
+
+    /// Whether the next connection should try to resume the session instead of starting clean
+    resume: bool,
+
+    queue: Option<Arc<PublishQueue>>,
+
+    /// Subscribes and unsubscribes, sent once connected
+    ///
+    /// They live as long as the session instead of a connection, so that an unsubscribe requested
+    /// without waiting for a connection still goes out before any later subscribe.
+    requests: tokio::sync::mpsc::UnboundedReceiver<SendUsage>,
+
+    options: ConnectOptions,
+}
+
+impl Session {
+    fn new(
+        options: ConnectOptions,
+        requests: tokio::sync::mpsc::UnboundedReceiver<SendUsage>,
+    ) -> Self {
+        // Growable, so that as many publishes can be in flight either way as the receive maximums
+        // allow
+        let mut fsm = ClientFsm::new(
+            VecPacketIdentifierStore::new(),
+            VecReceivedPacketIdentifierStore::new(),
+        );
+        let outstanding = Outstanding::restore(options.session_store(), &mut fsm);
+
+        Self {
+            fsm,
+            start: Instant::now(),
+            outstanding,
+            subscriptions: Vec::new(),
+            resume: false,
+            queue: options
+                .offline_queue()
+                .map(|offline_queue| Arc::new(PublishQueue::new(offline_queue.clone()))),
+            requests,
+            options,
+        }
+    }
+}
+
+/// The packets of a session that are not completely acknowledged yet, mirrored to its store
+struct Outstanding {
+    /// Outgoing QoS 1 and 2 packets the server has not completely acknowledged yet
+    in_flight: BTreeMap<NonZeroU16, InFlight>,
+
+    /// Identifiers of incoming QoS 2 publishes the server has not released yet
+    received: BTreeSet<NonZeroU16>,
+
+    store: SharedSessionStore,
+}
+
+impl Outstanding {
+    /// Pick up the session persisted in the store, and make the FSM aware of it
+    fn restore(store: SharedSessionStore, fsm: &mut ClientFsm) -> Self {
+        let stored = store.load();
+        let mut outstanding = Self {
+            in_flight: BTreeMap::new(),
+            received: BTreeSet::new(),
+            store,
+        };
+
+        for (id, packet) in stored.outgoing {
+            let packet_identifier = mqtt_format::v5::variable_header::PacketIdentifier(id);
+            let restored =
+                MqttPacket::from_bytes(&packet).filter(|_| fsm.restore_publish(packet_identifier));
+
+            match restored {
+                Some(packet) => {
+                    tracing::debug!(?id, "Restored outgoing packet");
+                    outstanding.in_flight.insert(
+                        id,
+                        InFlight {
+                            packet,
+                            delivered: None,
+                        },
+                    );
+                }
+                None => {
+                    tracing::warn!(?id, "Could not restore outgoing packet, dropping it");
+                    outstanding.store.update(|store| store.release_outgoing(id));
+                }
+            }
+        }
+
+        for id in stored.incoming {
+            let packet_identifier = mqtt_format::v5::variable_header::PacketIdentifier(id);
+            match fsm.restore_received(packet_identifier) {
+                Ok(_) => {
+                    tracing::debug!(?id, "Restored incoming QoS 2 packet identifier");
+                    outstanding.received.insert(id);
+                }
+                Err(_) => {
+                    tracing::warn!(?id, "Could not restore incoming packet identifier");
+                    outstanding.store.update(|store| store.release_incoming(id));
+                }
+            }
+        }
+
+        outstanding
+    }
+
+    /// Remember a packet to resend until the server acknowledges it
+    ///
+    /// A PUBREL replaces the publish it releases.
+    fn send(&mut self, id: NonZeroU16, packet: MqttPacket) {
+        self.store
+            .update(|store| store.store_outgoing(id, packet.as_bytes()));
+
+        match self.in_flight.get_mut(&id) {
+            Some(in_flight) => in_flight.packet = packet,
+            None => {
+                self.in_flight.insert(
+                    id,
+                    InFlight {
+                        packet,
+                        delivered: None,
+                    },
+                );
+            }
+        }
+    }
+
+    fn release(&mut self, id: NonZeroU16) -> Option<InFlight> {
+        let in_flight = self.in_flight.remove(&id)?;
+        self.store.update(|store| store.release_outgoing(id));
+        Some(in_flight)
+    }
+
+    /// Remember an incoming QoS 2 publish, before it is acknowledged
+    fn receive(&mut self, id: NonZeroU16) {
+        if self.received.insert(id) {
+            self.store.update(|store| store.store_incoming(id));
+        }
+    }
+
+    /// Forget an incoming QoS 2 publish the server released
+    fn released(&mut self, id: NonZeroU16) {
+        if self.received.remove(&id) {
+            self.store.update(|store| store.release_incoming(id));
+        }
+    }
+
+    /// Forget all incoming QoS 2 publishes, as the server did not keep the session
+    fn forget_received(&mut self) {
+        for id in std::mem::take(&mut self.received) {
+            self.store.update(|store| store.release_incoming(id));
+        }
+    }
+}
+
+/// An outgoing packet that has to be resent until the server acknowledges it
+struct InFlight {
+    packet: MqttPacket,
+
+    /// Notified once the server acknowledged the publish
+    delivered: Option<tokio::sync::oneshot::Sender<Result<(), Error>>>,
+}
+
+impl InFlight {
+    fn deliver(&mut self, result: Result<(), Error>) {
+        if let Some(delivered) = self.delivered.take() {
+            let _ = delivered.send(result);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Reconnect {
+    policy: ReconnectPolicy,
+    factory: ConnectionFactory,
+}
+
+impl Reconnect {
+    pub(crate) fn new(policy: ReconnectPolicy, factory: ConnectionFactory) -> Self {
+        Self { policy, factory }
+    }
+
+    /// Open a new transport, backing off after every failed attempt
+    ///
+    /// After a lost connection the client always waits at least the initial backoff. Returns
+    /// `None` once the policy gives up.
+    async fn connect(
+        &self,
+        failed_attempts: &mut u32,
+        reconnecting: bool,
+        events: &tokio::sync::broadcast::Sender<ConnectionEvent>,
+    ) -> Option<BoxedTransport> {
+        loop {
+            if self.policy.attempts_exhausted(*failed_attempts) {
+                tracing::warn!(failed_attempts, "Giving up reconnecting");
+                return None;
+            }
+
+            let _ = events.send(ConnectionEvent::Reconnecting {
+                attempt: *failed_attempts + 1,
+            });
+
+            if reconnecting || *failed_attempts > 0 {
+                let backoff = self.policy.backoff(failed_attempts.saturating_sub(1));
+                tracing::debug!(?backoff, "Waiting before next connection attempt");
+                tokio::time::sleep(backoff).await;
+            }
+
+            match (self.factory)().await {
+                Ok(transport) => return Some(transport),
+                Err(error) => {
+                    tracing::warn!(?error, failed_attempts, "Could not open connection");
+                    *failed_attempts += 1;
+                }
+            }
+        }
+    }
+}
+
 impl CoreClient {
-    pub fn new_and_connect<Read, Write>(
-        reader: Read,
-        writer: Write,
-        incoming_sender: tokio::sync::mpsc::Sender<MqttPacket>,
+    pub fn new_and_connect<C>(
+        connection: C,
+        incoming_sender: tokio::sync::mpsc::Sender<Message>,
+        options: ConnectOptions,
     ) -> Self
     where
-        Read: tokio::io::AsyncRead + Send + 'static,
-        Write: tokio::io::AsyncWrite + Send + 'static,
+        C: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
     {
         let (sender, receiver): (tokio::sync::mpsc::Sender<SendUsage>, _) =
             tokio::sync::mpsc::channel(1);
 
-        let start = Instant::now();
-
         let connection_state = Arc::new(Mutex::new(ConnectionState::Connected { sender }));
+        let (events, _) = tokio::sync::broadcast::channel(CONNECTION_EVENTS_CAPACITY);
+        let running = tokio::sync::watch::Sender::new(true);
+        let stopping = tokio::sync::watch::Sender::new(false);
+        let (requests, requests_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let session = Session::new(options, requests_receiver);
+        let queue = session.queue.clone();
 
-        let state_arc = connection_state.clone();
-        let incoming_sender_clone = incoming_sender.clone();
-        tokio::task::spawn(async move {
-            let fsm = MqttClientFSM::default();
+        tokio::task::spawn(run_connections(
+            connection_state.clone(),
+            incoming_sender.clone(),
+            session,
+            Some((Box::pin(connection), receiver)),
+            None,
+            events.clone(),
+            running.clone(),
+            stopping.clone(),
+        ));
 
-            let mut fsm =
-                handle_connection(reader, writer, incoming_sender_clone, receiver, start, fsm)
-                    .await;
+        Self {
+            incoming_sender,
+            connection_state,
+            reconnect: None,
+            events,
+            running,
+            stopping,
+            queue,
+            requests,
+        }
+    }
 
-            tracing::trace!("Connection lost. Telling FSM");
-            fsm.connection_lost(since(start));
+    pub fn new_with_reconnect(
+        incoming_sender: tokio::sync::mpsc::Sender<Message>,
+        reconnect: Reconnect,
+        options: ConnectOptions,
+    ) -> Self {
+        let connection_state = Arc::new(Mutex::new(ConnectionState::Connecting));
+        let (events, _) = tokio::sync::broadcast::channel(CONNECTION_EVENTS_CAPACITY);
+        let running = tokio::sync::watch::Sender::new(true);
+        let stopping = tokio::sync::watch::Sender::new(false);
+        let (requests, requests_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let session = Session::new(options, requests_receiver);
+        let queue = session.queue.clone();
 
-            tracing::trace!("Setting state to Unconnected");
-            *state_arc.lock().await = ConnectionState::Unconnected { client: fsm };
-        });
+        tokio::task::spawn(run_connections(
+            connection_state.clone(),
+            incoming_sender.clone(),
+            session,
+            None,
+            Some(reconnect.clone()),
+            events.clone(),
+            running.clone(),
+            stopping.clone(),
+        ));
 
         Self {
             incoming_sender,
             connection_state,
+            reconnect: Some(reconnect),
+            events,
+            running,
+            stopping,
+            queue,
+            requests,
         }
     }
 
-    pub fn new(incoming_sender: tokio::sync::mpsc::Sender<MqttPacket>) -> Self {
+    pub fn new(
+        incoming_sender: tokio::sync::mpsc::Sender<Message>,
+        options: ConnectOptions,
+    ) -> Self {
+        let (requests, requests_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let session = Session::new(options, requests_receiver);
+
         Self {
             incoming_sender,
-            connection_state: Arc::new(Mutex::new(ConnectionState::Unconnected {
-                client: MqttClientFSM::default(),
-            })),
+            queue: session.queue.clone(),
+            connection_state: Arc::new(Mutex::new(ConnectionState::Unconnected { session })),
+            reconnect: None,
+            events: tokio::sync::broadcast::channel(CONNECTION_EVENTS_CAPACITY).0,
+            running: tokio::sync::watch::Sender::new(false),
+            stopping: tokio::sync::watch::Sender::new(false),
+            requests,
         }
     }
 
-    pub async fn connect<Read, Write>(&self, reader: Read, writer: Write) -> Result<(), Error>
+    pub async fn connect<C>(&self, connection: C) -> Result<(), Error>
     where
-        Read: tokio::io::AsyncRead + Send + 'static,
-        Write: tokio::io::AsyncWrite + Send + 'static,
+        C: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
     {
-        let (sender, receiver): (tokio::sync::mpsc::Sender<SendUsage>, _) =
-            tokio::sync::mpsc::channel(1);
+        let mut connection_state = self.connection_state.lock().await;
 
-        let ConnectionState::Unconnected { client: fsm } =
-            std::mem::replace(&mut *self.connection_state.lock().await, {
-                ConnectionState::Connected { sender }
-            })
-        else {
+        let ConnectionState::Unconnected { .. } = &*connection_state else {
             return Err(Error::AlreadyConnected);
         };
 
-        let start = Instant::now();
-        let state_arc = self.connection_state.clone();
-        let incoming_sender_clone = self.incoming_sender.clone();
-        tokio::task::spawn(async move {
-            let mut fsm =
-                handle_connection(reader, writer, incoming_sender_clone, receiver, start, fsm)
-                    .await;
+        let (sender, receiver): (tokio::sync::mpsc::Sender<SendUsage>, _) =
+            tokio::sync::mpsc::channel(1);
 
-            tracing::trace!("Connection lost. Telling FSM");
-            fsm.connection_lost(since(start));
+        let ConnectionState::Unconnected { session } = std::mem::replace(
+            &mut *connection_state,
+            ConnectionState::Connected { sender },
+        ) else {
+            unreachable!("The connection state was checked above while holding the lock")
+        };
 
-            tracing::trace!("Setting state to Unconnected");
-            *state_arc.lock().await = ConnectionState::Unconnected { client: fsm };
-        });
+        self.running.send_replace(true);
+        self.stopping.send_replace(false);
+        tokio::task::spawn(run_connections(
+            self.connection_state.clone(),
+            self.incoming_sender.clone(),
+            session,
+            Some((Box::pin(connection), receiver)),
+            self.reconnect.clone(),
+            self.events.clone(),
+            self.running.clone(),
+            self.stopping.clone(),
+        ));
 
         Ok(())
     }
 
+    pub fn connection_events(&self) -> tokio::sync::broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Wait until the client is disconnected and does not reconnect anymore
+    pub async fn wait_for_shutdown(&self) {
+        let mut running = self.running.subscribe();
+
+        // The sender lives as long as the client, so this cannot fail
+        let _ = running.wait_for(|running| !running).await;
+    }
+
     pub async fn publish(&self, packet: MqttPacket) -> Result<(), Error> {
+        let (delivered_sender, delivered) = tokio::sync::oneshot::channel();
+
+        {
+            let connection_state = self.connection_state.lock().await;
+            match (&*connection_state, &self.queue) {
+                // Queued publishes go first, so that publishes stay in order
+                (ConnectionState::Connected { sender }, queue)
+                    if queue.as_ref().is_none_or(|queue| queue.is_empty()) =>
+                {
+                    tracing::debug!("Sending out publish packet");
+                    sender
+                        .send(SendUsage::Publish(packet, delivered_sender))
+                        .await
+                        .map_err(|_| Error::TokioChannel)?;
+                }
+                (_, Some(queue)) => {
+                    // The queue may have to be drained before the publish fits, which needs the lock
+                    drop(connection_state);
+                    tracing::debug!("Queueing publish packet");
+                    queue.push(packet, delivered_sender).await?;
+                }
+                (_, None) => {
+                    tracing::warn!("Tried to publish although not connected");
+                    return Err(Error::NotConnected);
+                }
+            }
+        }
+
+        // The client may be dropped before the publish was delivered
+        delivered.await.map_err(|_| Error::NotConnected)?
+    }
+
+    /// Subscribe and wait for the server to acknowledge it
+    ///
+    /// Returns the reason codes of the SUBACK, one per topic filter.
+    pub async fn subscribe(&self, packet: MqttPacket) -> Result<Vec<SubackReasonCode>, Error> {
+        let (subscribed_sender, subscribed) = tokio::sync::oneshot::channel();
+
         match *self.connection_state.lock().await {
-            ConnectionState::Unconnected { .. } => {
-                tracing::warn!("Tried to publish although not connected");
-                Err(Error::NotConnected)
+            ConnectionState::Unconnected { .. } | ConnectionState::Connecting => {
+                tracing::warn!("Tried to subscribe although not connected");
+                return Err(Error::NotConnected);
             }
-            ConnectionState::Connected { ref sender } => {
-                tracing::debug!("Sending out publish packet");
-                sender
-                    .send(SendUsage::Publish(packet))
-                    .await
-                    .map_err(|_| Error::TokioChannel)
+            ConnectionState::Connected { .. } => {
+                tracing::debug!("Trying to subscribe");
+                self.requests
+                    .send(SendUsage::Subscribe(packet, subscribed_sender))
+                    .map_err(|_| Error::TokioChannel)?;
             }
         }
+
+        // The connection may be lost before the server acknowledged the subscription
+        subscribed.await.map_err(|_| Error::NotConnected)?
     }
 
-    pub async fn subscribe(&self, packet: MqttPacket) -> Result<(), Error> {
+    /// Unsubscribe and wait for the server to acknowledge it
+    ///
+    /// Returns the reason codes of the UNSUBACK, one per topic filter.
+    pub async fn unsubscribe(&self, packet: MqttPacket) -> Result<Vec<UnsubackReasonCode>, Error> {
+        let (unsubscribed_sender, unsubscribed) = tokio::sync::oneshot::channel();
+
         match *self.connection_state.lock().await {
-            ConnectionState::Unconnected { .. } => {
-                tracing::warn!("Tried to subscribe although not connected");
-                Err(Error::NotConnected)
+            ConnectionState::Unconnected { .. } | ConnectionState::Connecting => {
+                tracing::warn!("Tried to unsubscribe although not connected");
+                return Err(Error::NotConnected);
+            }
+            ConnectionState::Connected { .. } => {
+                tracing::debug!("Trying to unsubscribe");
+                self.requests
+                    .send(SendUsage::Unsubscribe(packet, unsubscribed_sender))
+                    .map_err(|_| Error::TokioChannel)?;
+            }
+        }
+
+        unsubscribed.await.map_err(|_| Error::NotConnected)?
+    }
+
+    /// Unsubscribe without waiting for the server to acknowledge it
+    ///
+    /// The unsubscribe is queued right away, so it is sent before any subscribe requested after
+    /// it, once the client is connected.
+    pub fn unsubscribe_detached(&self, packet: MqttPacket) {
+        self.detached_unsubscriber().unsubscribe(packet);
+    }
+
+    /// A handle to unsubscribe detached with, that does not keep the client alive
+    pub(crate) fn detached_unsubscriber(&self) -> DetachedUnsubscriber {
+        DetachedUnsubscriber {
+            requests: self.requests.clone(),
+        }
+    }
+
+    /// Re-authenticate and wait for the server to accept it
+    pub async fn reauthenticate(&self) -> Result<(), Error> {
+        let (reauthenticated_sender, reauthenticated) = tokio::sync::oneshot::channel();
+
+        match *self.connection_state.lock().await {
+            ConnectionState::Unconnected { .. } | ConnectionState::Connecting => {
+                tracing::warn!("Tried to re-authenticate although not connected");
+                return Err(Error::NotConnected);
             }
             ConnectionState::Connected { ref sender } => {
-                tracing::debug!("Trying to subscribe");
+                tracing::debug!("Trying to re-authenticate");
                 sender
-                    .send(SendUsage::Subscribe(packet))
+                    .send(SendUsage::Reauthenticate(reauthenticated_sender))
                     .await
-                    .map_err(|_| Error::TokioChannel)
+                    .map_err(|_| Error::TokioChannel)?;
+            }
+        }
+
+        reauthenticated.await.map_err(|_| Error::NotConnected)?
+    }
+
+    /// Disconnect once the publishes in flight are acknowledged, or the timeout passed
+    ///
+    /// While the client is still waiting to reconnect, or for the server to accept the connection,
+    /// it gives up on the connection instead.
+    pub async fn disconnect(&self, packet: MqttPacket, timeout: Duration) -> Result<(), Error> {
+        let (disconnected_sender, disconnected) = tokio::sync::oneshot::channel();
+
+        self.stopping.send_replace(true);
+        let sender = match &*self.connection_state.lock().await {
+            ConnectionState::Unconnected { .. } => {
+                tracing::debug!("Tried to disconnect although not connected");
+                return Ok(());
+            }
+            ConnectionState::Connecting => None,
+            ConnectionState::Connected { sender } => Some(sender.clone()),
+        };
+
+        let disconnect = async {
+            let Some(sender) = sender else {
+                return Err(Error::NotConnected);
+            };
+
+            tracing::debug!("Trying to disconnect");
+            sender
+                .send(SendUsage::Disconnect(packet, timeout, disconnected_sender))
+                .await
+                .map_err(|_| Error::TokioChannel)?;
+            disconnected.await.map_err(|_| Error::NotConnected)?
+        };
+
+        let mut running = self.running.subscribe();
+        let result = tokio::select! {
+            result = disconnect => result,
+            // Given up on the connection before the server accepted it
+            _ = running.wait_for(|running| !running) => Ok(()),
+        };
+
+        match result {
+            Err(Error::SessionExpiryIntervalFixed) => {
+                self.stopping.send_replace(false);
+                Err(Error::SessionExpiryIntervalFixed)
+            }
+            // Without a connection to send the DISCONNECT on, the client only stops connecting
+            Err(error) => {
+                tracing::debug!(%error, "Could not send DISCONNECT, stopping to connect");
+                let _ = running.wait_for(|running| !running).await;
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+}
+
+/// Drive connections until the client is disconnected for good
+///
+/// If no initial connection is given, or whenever a connection is lost, a new one is opened
+/// through the reconnect policy, if there is one.
+#[allow(clippy::too_many_arguments)]
+async fn run_connections(
+    connection_state: Arc<Mutex<ConnectionState>>,
+    incoming_sender: tokio::sync::mpsc::Sender<Message>,
+    mut session: Session,
+    mut connection: Option<(BoxedTransport, tokio::sync::mpsc::Receiver<SendUsage>)>,
+    reconnect: Option<Reconnect>,
+    events: tokio::sync::broadcast::Sender<ConnectionEvent>,
+    running: tokio::sync::watch::Sender<bool>,
+    stopping: tokio::sync::watch::Sender<bool>,
+) {
+    let mut stopping = stopping.subscribe();
+    let mut failed_attempts = 0;
+    let mut reconnecting = false;
+    let mut redirect: Option<Redirect> = None;
+
+    // Reconnections go to where the server moved, instead of through the reconnect policy
+    let mut moved_to: Option<String> = None;
+
+    loop {
+        let (transport, receiver) = match connection.take() {
+            Some(connection) => connection,
+            None => {
+                let server_reference = redirect
+                    .take()
+                    .map(|redirect| redirect.server_reference)
+                    .or_else(|| moved_to.clone());
+                let transport = match (server_reference, session.options.redirect()) {
+                    (Some(server_reference), Some(factory)) => {
+                        tracing::debug!(server_reference, "Following the server to another server");
+                        *connection_state.lock().await = ConnectionState::Connecting;
+                        let _ = events.send(ConnectionEvent::Redirecting {
+                            server_reference: server_reference.clone(),
+                        });
+
+                        let opened = tokio::select! {
+                            opened = factory(server_reference) => opened,
+                            () = stopped(&mut stopping) => break,
+                        };
+                        match opened {
+                            Ok(transport) => Some(transport),
+                            Err(error) => {
+                                tracing::warn!(
+                                    ?error,
+                                    "Could not open connection to redirected server"
+                                );
+                                None
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+
+                let transport = match transport {
+                    Some(transport) => transport,
+                    None => {
+                        let Some(reconnect) = &reconnect else {
+                            break;
+                        };
+
+                        tracing::trace!("Setting state to Connecting");
+                        *connection_state.lock().await = ConnectionState::Connecting;
+
+                        let connected = tokio::select! {
+                            connected = reconnect.connect(&mut failed_attempts, reconnecting, &events) => connected,
+                            () = stopped(&mut stopping) => None,
+                        };
+                        let Some(transport) = connected else {
+                            break;
+                        };
+
+                        transport
+                    }
+                };
+
+                let (sender, receiver) = tokio::sync::mpsc::channel(1);
+                *connection_state.lock().await = ConnectionState::Connected { sender };
+
+                (transport, receiver)
+            }
+        };
+
+        let (reader, writer) = tokio::io::split(transport);
+        let end = handle_connection(
+            reader,
+            writer,
+            incoming_sender.clone(),
+            receiver,
+            &mut session,
+            &events,
+            &mut stopping,
+        )
+        .await;
+
+        tracing::trace!("Connection lost. Telling FSM");
+        session.fsm.connection_lost(since(session.start));
+
+        match end {
+            ConnectionEnd::Lost {
+                connected_for,
+                redirect: redirected_to,
+            } => {
+                reconnecting = true;
+
+                // A server that accepts connections only to drop them again is backed off from
+                let stable = connected_for.is_some_and(|connected_for| {
+                    reconnect
+                        .as_ref()
+                        .is_none_or(|reconnect| reconnect.policy.is_stable(connected_for))
+                });
+                if stable {
+                    failed_attempts = 0;
+                } else {
+                    failed_attempts += 1;
+                }
+
+                if let Some(redirected_to) = &redirected_to {
+                    if redirected_to.permanent {
+                        moved_to = Some(redirected_to.server_reference.clone());
+                    }
+                }
+                redirect = redirected_to;
             }
+            ConnectionEnd::Disconnected => break,
         }
     }
+
+    tracing::trace!("Setting state to Unconnected");
+    *connection_state.lock().await = ConnectionState::Unconnected { session };
+    running.send_replace(false);
+}
+
+/// Wait until the client is asked to disconnect
+async fn stopped(stopping: &mut tokio::sync::watch::Receiver<bool>) {
+    // The connection task keeps the sender alive, so this cannot fail
+    let _ = stopping.wait_for(|stopping| *stopping).await;
+}
+
+/// How a connection ended
+enum ConnectionEnd {
+    /// The connection was lost, or closed by the server
+    Lost {
+        /// How long the connection was up since the server accepted it, if it did
+        connected_for: Option<Duration>,
+
+        /// Where the server sent the client instead, if it did
+        redirect: Option<Redirect>,
+    },
+
+    /// The client disconnected on request, and must not reconnect
+    Disconnected,
+}
+
+/// Another server the server sent the client to
+struct Redirect {
+    server_reference: String,
+
+    /// Whether the server moved for good, instead of only for this connection
+    permanent: bool,
+}
+
+impl Redirect {
+    fn new(
+        permanent: Option<bool>,
+        server_reference: Option<&mqtt_format::v5::variable_header::ServerReference<'_>>,
+    ) -> Option<Self> {
+        Some(Redirect {
+            server_reference: server_reference?.0.to_string(),
+            permanent: permanent?,
+        })
+    }
 }
 
+/// A disconnect waiting for the publishes in flight to be acknowledged
+struct Disconnecting {
+    packet: MqttPacket,
+    deadline: Instant,
+    disconnected: tokio::sync::oneshot::Sender<Result<(), Error>>,
+}
+
+/// Speak MQTT over the given connection until it closes
 async fn handle_connection<Read, Write>(
     reader: Read,
     writer: Write,
-    incoming_sender: tokio::sync::mpsc::Sender<MqttPacket>,
+    incoming_sender: tokio::sync::mpsc::Sender<Message>,
     mut receiver: tokio::sync::mpsc::Receiver<SendUsage>,
-    start: Instant,
-    mut fsm: MqttClientFSM,
-) -> MqttClientFSM
+    session: &mut Session,
+    events: &tokio::sync::broadcast::Sender<ConnectionEvent>,
+    stopping: &mut tokio::sync::watch::Receiver<bool>,
+) -> ConnectionEnd
 where
     Read: tokio::io::AsyncRead + Send + 'static,
     Write: tokio::io::AsyncWrite + Send + 'static,
@@ -171,117 +827,2825 @@ async fn handle_connection<Read, Write>(
     let reader = std::pin::pin!(reader);
     let mut writer = FramedWrite::new(writer, MqttPacketCodec);
     let mut reader = FramedRead::new(reader, MqttPacketCodec);
+    let start = session.start;
+    let mut established: Option<Instant> = None;
+    let mut disconnect_reason = DisconnectReason::Closed;
+    let mut disconnecting: Option<Disconnecting> = None;
+    let mut disconnected = false;
+    let mut redirect = None;
+    let mut write_error = None;
 
-    tracing::trace!("Calling FSM to handle connect");
-    let action = fsm.handle_connect(
-        since(start),
-        mqtt_format::v5::packets::connect::MConnect {
-            client_identifier: "cloudmqtt-0",
-            username: None,
-            password: None,
-            clean_start: true,
-            will: None,
-            properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
-            keep_alive: 0,
-        },
-    );
+    let (acknowledgement_sender, mut acknowledgements) = tokio::sync::mpsc::unbounded_channel();
+    let mut incoming = Incoming {
+        sender: incoming_sender,
+        acknowledgements: acknowledgement_sender,
+        manual_acknowledgement: session.options.manual_acknowledgement(),
+        unacknowledged: BTreeSet::new(),
+    };
+    let mut topic_aliases = TopicAliases::new(session.options.topic_alias_maximum());
 
-    match action {
-        ExpectedAction::SendPacket(packet) => {
-            tracing::trace!(?packet, "Handling expected action after connect");
-            writer.send(packet).await.expect("Could not send message");
+    let _ = events.send(ConnectionEvent::Connecting);
+
+    // Created per connection, so that no authentication state leaks into the next one
+    let mut authenticator = session.options.authenticator();
+
+    tracing::trace!(resume = session.resume, "Calling FSM to handle connect");
+    let clean_start = !session.resume && session.options.clean_start();
+    let connect = session.options.as_connect(clean_start);
+    let action = match authenticator.as_deref_mut() {
+        Some(authenticator) => {
+            session
+                .fsm
+                .handle_connect_with_authenticator(since(start), connect, authenticator)
+        }
+        None => session.fsm.handle_connect(since(start), connect),
+    };
+
+    // Connecting again cannot fix the session, so the client gives up for good
+    let action = match action {
+        Ok(action) => action,
+        Err(error) => {
+            tracing::error!(%error, "Could not start connecting");
+            let _ = events.send(ConnectionEvent::Disconnected(DisconnectReason::Connect(
+                error,
+            )));
+            return ConnectionEnd::Disconnected;
+        }
+    };
+
+    if let ExpectedAction::SendPacket(packet) = action {
+        tracing::trace!(?packet, "Handling expected action after connect");
+        if let Err(error) = writer.send(packet).await {
+            tracing::debug!(?error, "Could not send CONNECT");
+            let _ = events.send(ConnectionEvent::Disconnected(io_error(error)));
+            session.fsm.connection_lost(since(start));
+            return ConnectionEnd::Lost {
+                connected_for: None,
+                redirect,
+            };
         }
-        _ => unreachable!(),
     }
 
+    let mut pending_subscribes = BTreeMap::new();
+    let mut pending_unsubscribes = BTreeMap::new();
+    let mut pending_reauthentication: Option<tokio::sync::oneshot::Sender<Result<(), Error>>> =
+        None;
+
     tracing::trace!("Entering handling loop");
     loop {
+        let disconnect_due = |disconnecting: &mut Disconnecting| {
+            session.outstanding.in_flight.is_empty() || disconnecting.deadline <= Instant::now()
+        };
+        if let Some(disconnecting) = disconnecting.take_if(disconnect_due) {
+            let mqtt_format::v5::packets::MqttPacket::Disconnect(disconnect) =
+                disconnecting.packet.get_packet()
+            else {
+                unreachable!("Only DISCONNECT packets are sent to disconnect");
+            };
+
+            tracing::debug!(
+                in_flight = session.outstanding.in_flight.len(),
+                "Disconnecting"
+            );
+            send_disconnect(&mut writer, session, disconnect.clone()).await;
+            disconnect_reason = DisconnectReason::Client(disconnect.reason_code);
+
+            // Publishes still in flight are left to the next client to send
+            session.outstanding.store.sync().await;
+            let _ = disconnecting.disconnected.send(Ok(()));
+            disconnected = true;
+            break;
+        }
+
+        #[allow(clippy::large_enum_variant)]
         enum GotPacket {
             Incoming(MqttPacket),
             ToSend(SendUsage),
+            Acknowledge(Acknowledgement),
+            KeepAlive,
         }
 
+        // The FSM decides when a ping is due, it only needs to be run again by then
+        let keep_alive_deadline = session
+            .fsm
+            .next_deadline()
+            .map(|deadline| start + Duration::from_millis(deadline.as_millis()));
+
         let action = tokio::select! {
             packet = reader.next() => {
-                if let Some(Ok(packet)) = packet {
-                    tracing::trace!(?packet, "Received incoming packet");
-                    GotPacket::Incoming(packet)
-                } else {
-                    tracing::trace!("Reader closed, breaking handle loop");
-                    break;
+                match packet {
+                    Some(Ok(packet)) => {
+                        tracing::trace!(?packet, "Received incoming packet");
+                        GotPacket::Incoming(packet)
+                    }
+                    Some(Err(error)) => {
+                        tracing::debug!(?error, "Could not read from connection, breaking handle loop");
+                        if let MqttPacketCodecError::Parsing(_) = error {
+                            close_connection(&mut writer, session, DisconnectReasonCode::MalformedPacket).await;
+                        }
+                        disconnect_reason = io_error(error);
+                        break;
+                    }
+                    None => {
+                        tracing::trace!("Reader closed, breaking handle loop");
+                        break;
+                    }
                 }
             }
-            Some(packet) = receiver.recv(), if fsm.is_connected() => {
+            // Publishes wait for the server to acknowledge one in flight, anything sent after them too
+            Some(packet) = receiver.recv(), if session.fsm.is_connected() && disconnecting.is_none() && !session.fsm.receive_maximum_reached() => {
                 tracing::trace!("Received packet to send");
                 GotPacket::ToSend(packet)
             }
+            Some(request) = session.requests.recv(), if session.fsm.is_connected() && disconnecting.is_none() => {
+                tracing::trace!("Received subscription request to send");
+                GotPacket::ToSend(request)
+            }
+            // Queued publishes stay queued until the server accepts another one
+            Some(queued) = next_queued(session.queue.as_deref()), if session.fsm.is_connected() && disconnecting.is_none() && !session.fsm.receive_maximum_reached() => {
+                tracing::trace!("Flushing queued publish");
+                GotPacket::ToSend(SendUsage::Publish(queued.packet, queued.delivered))
+            }
+            Some(acknowledgement) = acknowledgements.recv(), if session.fsm.is_connected() => {
+                tracing::trace!("Received acknowledgement to send");
+                GotPacket::Acknowledge(acknowledgement)
+            }
+            _ = tokio::time::sleep_until(keep_alive_deadline.unwrap_or_else(Instant::now)), if keep_alive_deadline.is_some() => {
+                GotPacket::KeepAlive
+            }
+            () = stopped(stopping), if !session.fsm.is_connected() => {
+                tracing::debug!("Asked to disconnect before the server accepted the connection");
+                disconnected = true;
+                break;
+            }
+            _ = tokio::time::sleep_until(disconnecting.as_ref().map_or_else(Instant::now, |disconnecting| disconnecting.deadline)), if disconnecting.is_some() => {
+                tracing::debug!("Publishes are still in flight, disconnecting anyway");
+                continue;
+            }
         };
 
-        tracing::trace!("Processing next action");
-        let action = match &action {
+        // Aliases are resolved before the publish is routed anywhere
+        let action = match action {
             GotPacket::Incoming(packet) => {
-                fsm.consume(packet.get_packet().clone()).run(since(start))
-            }
-            GotPacket::ToSend(send_usage) => match send_usage {
-                SendUsage::Publish(packet) => {
-                    tracing::trace!("Publishing packet to FSM");
-                    let mut publisher =
-                        fsm.publish(packet.get_packet().clone().try_into().unwrap());
-
-                    tracing::trace!("Consuming publisher actions");
-                    while let Some(action) = publisher.run(since(start)) {
-                        tracing::trace!(?action, "Handling action");
-                        handle_action(&mut writer, action, &incoming_sender).await;
+                let resolved = match packet.get_packet() {
+                    mqtt_format::v5::packets::MqttPacket::Publish(publish) => {
+                        topic_aliases.resolve(publish)
                     }
+                    _ => Ok(None),
+                };
 
-                    tracing::trace!("Running FSM");
-                    fsm.run(since(start))
-                }
-                SendUsage::Subscribe(packet) => {
-                    tracing::trace!(?packet, "Subscribing in FSM");
-                    Some(fsm.subscribe(
-                        since(start),
-                        packet.get_packet().clone().try_into().unwrap(),
-                    ))
+                match resolved {
+                    Ok(resolved) => GotPacket::Incoming(resolved.unwrap_or(packet)),
+                    Err(violation) => {
+                        tracing::warn!(%violation, "Server used an invalid topic alias");
+                        send_disconnect(&mut writer, session, violation.disconnect()).await;
+                        disconnect_reason = DisconnectReason::ProtocolViolation(violation);
+                        break;
+                    }
                 }
+            }
+            action => action,
+        };
+
+        tracing::trace!("Processing next action");
+        let was_connected = session.fsm.is_connected();
+        let connack = match &action {
+            GotPacket::Incoming(packet) => match packet.get_packet() {
+                mqtt_format::v5::packets::MqttPacket::Connack(..) => Some(packet.clone()),
+                _ => None,
             },
+            GotPacket::ToSend(_) | GotPacket::Acknowledge(_) | GotPacket::KeepAlive => None,
         };
+        let action = match action {
+            GotPacket::Incoming(ref packet) => {
+                match packet.get_packet() {
+                    mqtt_format::v5::packets::MqttPacket::Connack(connack)
+                        if u8::from(connack.reason_code) >= 0x80 =>
+                    {
+                        tracing::warn!(reason_code = ?connack.reason_code, "Server refused the connection");
+                        disconnect_reason = DisconnectReason::Refused(connack.reason_code);
 
-        {
-            if let Some(action) = action {
-                handle_action(&mut writer, action, &incoming_sender).await;
-            }
-        }
-    }
+                        let permanent = match connack.reason_code {
+                            ConnackReasonCode::UseAnotherServer => Some(false),
+                            ConnackReasonCode::ServerMoved => Some(true),
+                            _ => None,
+                        };
+                        redirect = Redirect::new(permanent, connack.properties.server_reference());
+                        break;
+                    }
+                    mqtt_format::v5::packets::MqttPacket::Disconnect(disconnect) => {
+                        tracing::debug!(reason_code = ?disconnect.reason_code, "Server disconnected");
+                        let server_reference = disconnect.properties.server_reference();
+                        disconnect_reason = DisconnectReason::Server {
+                            reason_code: disconnect.reason_code,
+                            reason_string: disconnect
+                                .properties
+                                .reason_string()
+                                .map(|reason_string| reason_string.0.to_string()),
+                            server_reference: server_reference
+                                .map(|server_reference| server_reference.0.to_string()),
+                        };
 
-    fsm
-}
+                        let permanent = match disconnect.reason_code {
+                            DisconnectReasonCode::UseAnotherServer => Some(false),
+                            DisconnectReasonCode::ServerMoved => Some(true),
+                            _ => None,
+                        };
+                        redirect = Redirect::new(permanent, server_reference);
+                    }
+                    _ => {}
+                }
 
-async fn handle_action<W>(
-    writer: &mut FramedWrite<W, MqttPacketCodec>,
-    action: ExpectedAction<'_>,
-    incoming_sender: &tokio::sync::mpsc::Sender<MqttPacket>,
-) where
-    W: tokio::io::AsyncWrite + Unpin,
-{
-    let mut writer = std::pin::pin!(writer);
+                fail_rejected_delivery(&mut session.outstanding.in_flight, packet.get_packet());
+                if let mqtt_format::v5::packets::MqttPacket::Pubrel(pubrel) = packet.get_packet() {
+                    session.outstanding.released(pubrel.packet_identifier.0);
+                }
+                complete_subscribe(
+                    &mut pending_subscribes,
+                    &mut session.subscriptions,
+                    packet.get_packet(),
+                );
+                complete_unsubscribe(&mut pending_unsubscribes, packet.get_packet());
 
-    match action {
-        ExpectedAction::SendPacket(mqtt_packet) => {
-            writer
-                .send(mqtt_packet)
-                .await
-                .expect("Could not send packet");
-        }
-        ExpectedAction::ReceivePacket(cloudmqtt_core::client::ReceivePacket::NoFurtherAction(
-            received_packet,
+                let reauthenticated = matches!(
+                    packet.get_packet(),
+                    mqtt_format::v5::packets::MqttPacket::Auth(auth)
+                        if auth.reason == mqtt_format::v5::packets::auth::AuthReasonCode::Success
+                );
+
+                let consumer = session.fsm.consume(packet.get_packet().clone());
+                let action = match authenticator.as_deref_mut() {
+                    Some(authenticator) => {
+                        consumer.run_with_authenticator(since(start), authenticator)
+                    }
+                    None => consumer.run(since(start)),
+                };
+
+                if reauthenticated && session.fsm.is_connected() {
+                    if let Some(reauthenticated) = pending_reauthentication.take() {
+                        let _ = reauthenticated.send(Ok(()));
+                    }
+                }
+
+                action
+            }
+            GotPacket::ToSend(SendUsage::Publish(packet, delivered)) => {
+                tracing::trace!("Publishing packet to FSM");
+                let Ok(publish) = packet.get_packet().clone().try_into() else {
+                    tracing::warn!(?packet, "Dropping a packet that is not a publish");
+                    let _ = delivered.send(Err(Error::NotAPublish));
+                    continue;
+                };
+
+                let mut publisher = match session.fsm.publish(publish) {
+                    Ok(publisher) => publisher,
+                    Err(error) => {
+                        tracing::warn!(%error, "Refusing to publish");
+                        let _ = delivered.send(Err(Error::PublishRefused(error)));
+                        continue;
+                    }
+                };
+
+                tracing::trace!("Consuming publisher actions");
+                let mut stored_id = None;
+                while let Some(action) = publisher.run(since(start)) {
+                    tracing::trace!(?action, "Handling action");
+                    if let ExpectedAction::StorePacket { id } = action {
+                        stored_id = Some(id);
+                    }
+                    // Publishing never asks to acknowledge anything
+                    if let Err(error) = handle_action(
+                        &mut writer,
+                        action,
+                        &mut incoming,
+                        &mut topic_aliases,
+                        &mut session.outstanding,
+                    )
+                    .await
+                    {
+                        write_error = Some(error);
+                        break;
+                    }
+                }
+
+                // QoS 0 publishes are done once written, all others once acknowledged
+                match stored_id.and_then(|id| session.outstanding.in_flight.get_mut(&id.0)) {
+                    Some(in_flight) => in_flight.delivered = Some(delivered),
+                    None if write_error.is_some() => {
+                        let _ = delivered.send(Err(Error::NotConnected));
+                    }
+                    None => {
+                        let _ = delivered.send(Ok(()));
+                    }
+                }
+
+                if let Some(error) = write_error.take() {
+                    disconnect_reason = io_error(error);
+                    break;
+                }
+
+                tracing::trace!("Running FSM");
+                session.fsm.run(since(start))
+            }
+            GotPacket::ToSend(SendUsage::Subscribe(ref packet, subscribed)) => {
+                tracing::trace!(?packet, "Subscribing in FSM");
+                let Ok(subscribe) = packet.get_packet().clone().try_into() else {
+                    tracing::warn!(?packet, "Dropping a packet that is not a subscribe");
+                    let _ = subscribed.send(Err(Error::NotASubscribe));
+                    continue;
+                };
+
+                let action = match session.fsm.subscribe(since(start), subscribe) {
+                    Ok(action) => action,
+                    Err(error) => {
+                        tracing::warn!(%error, "Could not subscribe");
+                        let _ = subscribed.send(Err(Error::RequestRefused(error)));
+                        continue;
+                    }
+                };
+
+                if let ExpectedAction::SendPacket(
+                    mqtt_format::v5::packets::MqttPacket::Subscribe(subscribe),
+                ) = &action
+                {
+                    pending_subscribes.insert(
+                        subscribe.packet_identifier.0,
+                        PendingSubscribe {
+                            packet: packet.clone(),
+                            subscribed,
+                        },
+                    );
+                }
+
+                Some(action)
+            }
+            GotPacket::ToSend(SendUsage::Unsubscribe(ref packet, unsubscribed)) => {
+                tracing::trace!(?packet, "Unsubscribing in FSM");
+                let Ok(unsubscribe) = packet.get_packet().clone().try_into() else {
+                    tracing::warn!(?packet, "Dropping a packet that is not an unsubscribe");
+                    let _ = unsubscribed.send(Err(Error::NotAnUnsubscribe));
+                    continue;
+                };
+
+                // Even if the UNSUBACK never arrives, these filters must not be restored
+                forget_subscriptions(&mut session.subscriptions, &unsubscribe);
+
+                let action = match session.fsm.unsubscribe(since(start), unsubscribe) {
+                    Ok(action) => action,
+                    Err(error) => {
+                        tracing::warn!(%error, "Could not unsubscribe");
+                        let _ = unsubscribed.send(Err(Error::RequestRefused(error)));
+                        continue;
+                    }
+                };
+
+                if let ExpectedAction::SendPacket(
+                    mqtt_format::v5::packets::MqttPacket::Unsubscribe(unsubscribe),
+                ) = &action
+                {
+                    pending_unsubscribes.insert(unsubscribe.packet_identifier.0, unsubscribed);
+                }
+
+                Some(action)
+            }
+            GotPacket::ToSend(SendUsage::Reauthenticate(reauthenticated)) => {
+                let Some(authenticator) = authenticator.as_deref_mut() else {
+                    let _ = reauthenticated.send(Err(Error::NoAuthenticator));
+                    continue;
+                };
+
+                let Some(action) = session.fsm.reauthenticate(since(start), authenticator) else {
+                    let _ = reauthenticated.send(Err(Error::ReauthenticationInProgress));
+                    continue;
+                };
+
+                pending_reauthentication = Some(reauthenticated);
+                Some(action)
+            }
+            GotPacket::ToSend(SendUsage::Disconnect(packet, timeout, disconnected)) => {
+                let mqtt_format::v5::packets::MqttPacket::Disconnect(disconnect) =
+                    packet.get_packet()
+                else {
+                    unreachable!("Only DISCONNECT packets are sent to disconnect");
+                };
+
+                // A session that ends with the connection cannot be made to outlive it
+                if disconnect
+                    .properties
+                    .session_expiry_interval()
+                    .is_some_and(|interval| interval.0 > 0)
+                    && session.options.session_expiry_interval() == 0
+                {
+                    let _ = disconnected.send(Err(Error::SessionExpiryIntervalFixed));
+                    continue;
+                }
+
+                disconnecting = Some(Disconnecting {
+                    packet,
+                    deadline: Instant::now() + timeout,
+                    disconnected,
+                });
+                continue;
+            }
+            GotPacket::Acknowledge(acknowledgement) => {
+                acknowledge(session, &mut incoming.unacknowledged, acknowledgement)
+            }
+            GotPacket::KeepAlive => {
+                let action = session.fsm.run(since(start));
+                if let Some(ExpectedAction::Disconnect) = action {
+                    disconnect_reason = DisconnectReason::KeepAliveTimeout;
+                }
+                action
+            }
+        };
+
+        {
+            if let Some(ExpectedAction::Disconnect) = action {
+                tracing::debug!("FSM requested to close the connection");
+                break;
+            }
+
+            if let Some(ExpectedAction::AuthenticationFailed(error)) = action {
+                tracing::warn!(%error, "Enhanced authentication failed, closing the connection");
+                if let Some(reauthenticated) = pending_reauthentication.take() {
+                    let _ = reauthenticated.send(Err(Error::AuthenticationFailed(error)));
+                }
+                disconnect_reason = DisconnectReason::AuthenticationFailed(error);
+                break;
+            }
+
+            if let Some(ExpectedAction::ProtocolViolation(violation)) = action {
+                tracing::warn!(%violation, "Server violated the protocol, closing the connection");
+                if let Err(error) = writer.send(violation.disconnect().into()).await {
+                    tracing::debug!(?error, "Could not send DISCONNECT");
+                }
+                disconnect_reason = DisconnectReason::ProtocolViolation(violation);
+                break;
+            }
+
+            if let Some(ExpectedAction::SaveClientIdentifier(client_identifier)) = action {
+                tracing::debug!(client_identifier, "Server assigned a client identifier");
+                session.options.assign_client_identifier(client_identifier);
+            } else if let Some(action) = action {
+                let mut handled = handle_action(
+                    &mut writer,
+                    action,
+                    &mut incoming,
+                    &mut topic_aliases,
+                    &mut session.outstanding,
+                )
+                .await;
+
+                if let Some(acknowledge_action) = handled.as_mut().ok().and_then(Option::take) {
+                    let action = acknowledge(
+                        session,
+                        &mut incoming.unacknowledged,
+                        Acknowledgement {
+                            acknowledge: acknowledge_action,
+                            reason: PubackReasonCode::Success,
+                        },
+                    );
+                    if let Some(action) = action {
+                        handled = handle_action(
+                            &mut writer,
+                            action,
+                            &mut incoming,
+                            &mut topic_aliases,
+                            &mut session.outstanding,
+                        )
+                        .await;
+                    }
+                }
+
+                if let Err(error) = handled {
+                    tracing::debug!(
+                        ?error,
+                        "Could not write to connection, breaking handle loop"
+                    );
+                    disconnect_reason = io_error(error);
+                    break;
+                }
+            }
+        }
+
+        if let Some(connack) = connack {
+            if !was_connected && session.fsm.is_connected() {
+                let connected = crate::event::Connected::new(connack);
+                if let Some(limits) = session.fsm.server_limits() {
+                    topic_aliases.set_outbound_maximum(limits.topic_alias_maximum());
+                }
+                established = Some(Instant::now());
+                session.resume = true;
+                if let Err(error) =
+                    resume_session(&mut writer, session, connected.session_present()).await
+                {
+                    tracing::debug!(?error, "Could not resume the session, breaking handle loop");
+                    disconnect_reason = io_error(error);
+                    break;
+                }
+                let _ = events.send(ConnectionEvent::Connected(connected));
+            }
+        }
+    }
+
+    // Acknowledgements still to come are lost with the connection, the server sends these again
+    for id in std::mem::take(&mut incoming.unacknowledged) {
+        session
+            .fsm
+            .forget_received(mqtt_format::v5::variable_header::PacketIdentifier(id));
+    }
+
+    let _ = events.send(ConnectionEvent::Disconnected(disconnect_reason));
+
+    // Losing the connection while waiting to disconnect does not change that the client should
+    if disconnected || disconnecting.is_some() {
+        ConnectionEnd::Disconnected
+    } else {
+        ConnectionEnd::Lost {
+            connected_for: established.map(|established| established.elapsed()),
+            redirect,
+        }
+    }
+}
+
+/// Queues unsubscribes for the connection task, see [`CoreClient::unsubscribe_detached`]
+#[derive(Clone)]
+pub(crate) struct DetachedUnsubscriber {
+    requests: tokio::sync::mpsc::UnboundedSender<SendUsage>,
+}
+
+impl DetachedUnsubscriber {
+    pub(crate) fn unsubscribe(&self, packet: MqttPacket) {
+        let (unsubscribed_sender, _) = tokio::sync::oneshot::channel();
+
+        if self
+            .requests
+            .send(SendUsage::Unsubscribe(packet, unsubscribed_sender))
+            .is_err()
+        {
+            tracing::debug!("Connection task is gone, cannot unsubscribe");
+        }
+    }
+}
+
+/// Where incoming publishes go on one connection, and how they are acknowledged
+struct Incoming {
+    sender: tokio::sync::mpsc::Sender<Message>,
+
+    /// Where publishes are acknowledged once they were routed, or by the application
+    acknowledgements: tokio::sync::mpsc::UnboundedSender<Acknowledgement>,
+
+    /// Whether the application acknowledges publishes instead of the router
+    manual_acknowledgement: bool,
+
+    /// Incoming QoS 2 publishes handed out, but not acknowledged yet
+    unacknowledged: BTreeSet<NonZeroU16>,
+}
+
+/// Tell the server why the connection is closed, after it violated the protocol
+async fn close_connection<W>(
+    writer: &mut FramedWrite<W, MqttPacketCodec>,
+    session: &mut Session,
+    reason_code: mqtt_format::v5::packets::disconnect::DisconnectReasonCode,
+) where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    send_disconnect(
+        writer,
+        session,
+        mqtt_format::v5::packets::disconnect::MDisconnect {
+            reason_code,
+            properties: mqtt_format::v5::packets::disconnect::DisconnectProperties::new(),
+        },
+    )
+    .await
+}
+
+async fn send_disconnect<W>(
+    writer: &mut FramedWrite<W, MqttPacketCodec>,
+    session: &mut Session,
+    disconnect: mqtt_format::v5::packets::disconnect::MDisconnect<'_>,
+) where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut disconnecter = session.fsm.disconnect(disconnect);
+
+    while let Some(action) = disconnecter.run(since(session.start)) {
+        if let ExpectedAction::SendPacket(packet) = action {
+            if let Err(error) = writer.send(packet).await {
+                tracing::debug!(?error, "Could not send DISCONNECT");
+            }
+        }
+    }
+}
+
+/// Let the FSM acknowledge an incoming publish
+fn acknowledge(
+    session: &mut Session,
+    unacknowledged: &mut BTreeSet<NonZeroU16>,
+    Acknowledgement {
+        acknowledge,
+        reason,
+    }: Acknowledgement,
+) -> Option<ExpectedAction<'static>> {
+    if acknowledge.quality_of_service() == mqtt_format::v5::qos::QualityOfService::ExactlyOnce {
+        let id = acknowledge.packet_identifier().0;
+        unacknowledged.remove(&id);
+
+        // Recorded before acknowledging, so that a re-delivery is recognized even after a
+        // restart. A rejected publish is not released by the server, so there is nothing to record.
+        if u8::from(reason) < 0x80 {
+            session.outstanding.receive(id);
+        }
+    }
+
+    session
+        .fsm
+        .acknowledge_with_reason(since(session.start), acknowledge, reason)
+}
+
+/// The next publish from the offline queue, if the client has one
+async fn next_queued(queue: Option<&PublishQueue>) -> Option<crate::queue::Queued> {
+    match queue {
+        Some(queue) => Some(queue.pop().await),
+        None => None,
+    }
+}
+
+/// Bring a freshly established connection up to date with our session
+///
+/// Unacknowledged publishes are sent again, with the DUP flag set. Even if the server did not keep
+/// the session they are resent, so that no message is lost. Releases are only resent if the server
+/// kept the session, otherwise it has no publish left to release and the publish counts as
+/// delivered. Subscriptions on the other hand only need to be restored if the server lost them.
+async fn resume_session<W>(
+    writer: &mut FramedWrite<W, MqttPacketCodec>,
+    session: &mut Session,
+    session_present: bool,
+) -> Result<(), MqttPacketCodecError>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    if !session_present {
+        session.outstanding.forget_received();
+
+        // The server received these publishes already, only their release was lost
+        let released = session
+            .outstanding
+            .in_flight
+            .iter()
+            .filter(|(_, in_flight)| {
+                matches!(
+                    in_flight.packet.get_packet(),
+                    mqtt_format::v5::packets::MqttPacket::Pubrel(..)
+                )
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in released {
+            tracing::debug!(
+                ?id,
+                "Completing publish the server has nothing left to release of"
+            );
+            session
+                .fsm
+                .forget_publish(mqtt_format::v5::variable_header::PacketIdentifier(id));
+            if let Some(mut in_flight) = session.outstanding.release(id) {
+                in_flight.deliver(Ok(()));
+            }
+        }
+    }
+
+    for (id, InFlight { packet, .. }) in session.outstanding.in_flight.iter() {
+        tracing::debug!(?id, "Resending unacknowledged packet");
+        writer.send(packet.get_packet().clone()).await?;
+    }
+
+    if session_present {
+        return Ok(());
+    }
+
+    for subscription in session.subscriptions.iter() {
+        tracing::debug!(?subscription, "Restoring subscription");
+        let Ok(subscribe) = subscription.get_packet().clone().try_into() else {
+            tracing::warn!(
+                ?subscription,
+                "Not restoring a packet that is not a subscribe"
+            );
+            continue;
+        };
+
+        let action = session.fsm.subscribe(since(session.start), subscribe);
+
+        match action {
+            Ok(ExpectedAction::SendPacket(packet)) => writer.send(packet).await?,
+            Ok(_) => {}
+            Err(error) => tracing::warn!(%error, "Could not restore subscription"),
+        }
+    }
+
+    Ok(())
+}
+
+/// A subscription waiting for its SUBACK
+struct PendingSubscribe {
+    packet: MqttPacket,
+    subscribed: tokio::sync::oneshot::Sender<Result<Vec<SubackReasonCode>, Error>>,
+}
+
+/// Report the SUBACK to whoever subscribed, and remember the subscription if it was granted
+fn complete_subscribe(
+    pending_subscribes: &mut BTreeMap<NonZeroU16, PendingSubscribe>,
+    subscriptions: &mut Vec<MqttPacket>,
+    packet: &mqtt_format::v5::packets::MqttPacket<'_>,
+) {
+    let mqtt_format::v5::packets::MqttPacket::Suback(suback) = packet else {
+        return;
+    };
+
+    let Some(pending) = pending_subscribes.remove(&suback.packet_identifier.0) else {
+        // Restored subscriptions are not waited for
+        return;
+    };
+
+    if suback.reasons.iter().any(|reason| u8::from(*reason) < 0x80) {
+        subscriptions.push(pending.packet);
+    }
+
+    let _ = pending.subscribed.send(Ok(suback.reasons.to_vec()));
+}
+
+/// Report the UNSUBACK to whoever unsubscribed
+fn complete_unsubscribe(
+    pending_unsubscribes: &mut BTreeMap<
+        NonZeroU16,
+        tokio::sync::oneshot::Sender<Result<Vec<UnsubackReasonCode>, Error>>,
+    >,
+    packet: &mqtt_format::v5::packets::MqttPacket<'_>,
+) {
+    let mqtt_format::v5::packets::MqttPacket::Unsuback(unsuback) = packet else {
+        return;
+    };
+
+    if let Some(unsubscribed) = pending_unsubscribes.remove(&unsuback.packet_identifier.0) {
+        let _ = unsubscribed.send(Ok(unsuback.reasons.to_vec()));
+    }
+}
+
+/// Remove the unsubscribed topic filters from the subscriptions to restore
+fn forget_subscriptions(
+    subscriptions: &mut Vec<MqttPacket>,
+    unsubscribe: &mqtt_format::v5::packets::unsubscribe::MUnsubscribe<'_>,
+) {
+    let is_unsubscribed = |topic_filter: &str| {
+        unsubscribe
+            .unsubscriptions
+            .iter()
+            .any(|unsubscription| unsubscription.topic_filter == topic_filter)
+    };
+
+    subscriptions.retain_mut(|packet| {
+        let mqtt_format::v5::packets::MqttPacket::Subscribe(subscribe) = packet.get_packet() else {
+            return false;
+        };
+
+        if !subscribe
+            .subscriptions
+            .iter()
+            .any(|subscription| is_unsubscribed(subscription.topic_filter))
+        {
+            return true;
+        }
+
+        let mut bytes = tokio_util::bytes::BytesMut::new();
+        for subscription in subscribe.subscriptions.iter() {
+            if !is_unsubscribed(subscription.topic_filter) {
+                subscription
+                    .write(&mut crate::codec::BytesMutWriter(&mut bytes))
+                    .unwrap();
+            }
+        }
+
+        if bytes.is_empty() {
+            return false;
+        }
+
+        *packet = MqttPacket::new(mqtt_format::v5::packets::MqttPacket::Subscribe(
+            mqtt_format::v5::packets::subscribe::MSubscribe {
+                subscriptions: mqtt_format::v5::packets::subscribe::Subscriptions::parse_complete(
+                    &bytes,
+                )
+                .unwrap(),
+                ..subscribe.clone()
+            },
+        ));
+
+        true
+    });
+}
+
+/// Report publishes the server acknowledged with an error reason as failed
+fn fail_rejected_delivery(
+    in_flight: &mut BTreeMap<NonZeroU16, InFlight>,
+    packet: &mqtt_format::v5::packets::MqttPacket<'_>,
+) {
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+
+    let (id, error) = match packet {
+        FormatMqttPacket::Puback(puback) if u8::from(puback.reason) >= 0x80 => (
+            puback.packet_identifier,
+            Error::PubackRejected(puback.reason),
+        ),
+        FormatMqttPacket::Pubrec(pubrec) if u8::from(pubrec.reason) >= 0x80 => (
+            pubrec.packet_identifier,
+            Error::PubrecRejected(pubrec.reason),
+        ),
+        FormatMqttPacket::Pubcomp(pubcomp) if u8::from(pubcomp.reason) >= 0x80 => (
+            pubcomp.packet_identifier,
+            Error::PubcompRejected(pubcomp.reason),
+        ),
+        _ => return,
+    };
+
+    if let Some(in_flight) = in_flight.get_mut(&id.0) {
+        tracing::debug!(?id, ?error, "Server rejected publish");
+        in_flight.deliver(Err(error));
+    }
+}
+
+async fn handle_action<W>(
+    writer: &mut FramedWrite<W, MqttPacketCodec>,
+    action: ExpectedAction<'_>,
+    incoming: &mut Incoming,
+    topic_aliases: &mut TopicAliases,
+    outstanding: &mut Outstanding,
+) -> Result<Option<cloudmqtt_core::client::AcknowledgeAction>, MqttPacketCodecError>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut writer = std::pin::pin!(writer);
+
+    match action {
+        ExpectedAction::SendPacket(mqtt_packet) => {
+            match &mqtt_packet {
+                mqtt_format::v5::packets::MqttPacket::Publish(
+                    publish @ mqtt_format::v5::packets::publish::MPublish {
+                        packet_identifier: Some(id),
+                        ..
+                    },
+                ) => {
+                    let resend = mqtt_format::v5::packets::publish::MPublish {
+                        duplicate: true,
+                        ..publish.clone()
+                    };
+                    outstanding.send(id.0, MqttPacket::new(resend.into()));
+                }
+                mqtt_format::v5::packets::MqttPacket::Pubrel(pubrel) => {
+                    // Once the server received our publish, only the release has to be resent
+                    let id = pubrel.packet_identifier.0;
+                    if outstanding.in_flight.contains_key(&id) {
+                        outstanding.send(id, MqttPacket::new(pubrel.clone().into()));
+                    }
+                }
+                _ => {}
+            }
+
+            // Resent publishes must carry their topic name, aliases do not outlive the connection
+            let mqtt_packet = match mqtt_packet {
+                mqtt_format::v5::packets::MqttPacket::Publish(publish) => {
+                    topic_aliases.alias(publish).into()
+                }
+                mqtt_packet => mqtt_packet,
+            };
+
+            writer.send(mqtt_packet).await?;
+        }
+        ExpectedAction::StorePacket { id } => {
+            tracing::trace!(?id, "Packet will be stored once it is sent");
+        }
+        ExpectedAction::ReleasePacket { id } => {
+            tracing::trace!(?id, "Releasing acknowledged packet");
+            if let Some(mut in_flight) = outstanding.release(id.0) {
+                in_flight.deliver(Ok(()));
+            }
+        }
+        ExpectedAction::ReceivePacket(cloudmqtt_core::client::ReceivePacket::NoFurtherAction(
+            received_packet,
         )) => {
-            // TODO: Don't await in the FSM loop
-            incoming_sender
-                .send(MqttPacket::new(received_packet))
+            if let Some(message) = received_message(received_packet) {
+                if incoming.sender.send(message).await.is_err() {
+                    tracing::debug!("Nobody routes incoming messages anymore");
+                }
+            }
+        }
+        ExpectedAction::ReceivePacket(
+            cloudmqtt_core::client::ReceivePacket::AcknowledgeNeeded {
+                packet,
+                acknowledge,
+            },
+        ) => {
+            // Messages that cannot be received are acknowledged right away, nobody else would
+            let Some(message) = received_message(packet) else {
+                return Ok(Some(acknowledge));
+            };
+
+            if acknowledge.quality_of_service()
+                == mqtt_format::v5::qos::QualityOfService::ExactlyOnce
+            {
+                incoming
+                    .unacknowledged
+                    .insert(acknowledge.packet_identifier().0);
+            }
+
+            // Holding back the acknowledgement until the message was routed keeps the server from
+            // sending more than the receive maximum, while subscriptions are not keeping up
+            let acknowledger = Acknowledger::new(acknowledge, incoming.acknowledgements.clone());
+            let message = if incoming.manual_acknowledgement {
+                message.with_acknowledger(acknowledger)
+            } else {
+                message.with_routed_acknowledger(acknowledger)
+            };
+
+            if let Err(error) = incoming.sender.send(message).await {
+                tracing::debug!("Nobody routes incoming messages anymore");
+                error.0.ack();
+            }
+        }
+        action => {
+            tracing::debug!(?action, "Action is handled by the connection itself");
+        }
+    }
+
+    Ok(None)
+}
+
+/// Why the connection was lost, if reading or writing a packet failed
+fn io_error(error: MqttPacketCodecError) -> DisconnectReason {
+    DisconnectReason::Io(Arc::new(match error {
+        MqttPacketCodecError::Io(error) => error,
+        error => std::io::Error::new(std::io::ErrorKind::InvalidData, error),
+    }))
+}
+
+/// The message an incoming publish carries, if it is valid
+fn received_message(packet: mqtt_format::v5::packets::MqttPacket<'_>) -> Option<Message> {
+    match Message::new(MqttPacket::new(packet)) {
+        Ok(message) => Some(message),
+        Err(error) => {
+            tracing::warn!(?error, "Invalid topic name");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use cloudmqtt_core::client::ProtocolViolation;
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio_util::codec::Framed;
+
+    use super::CoreClient;
+    use super::Reconnect;
+    use crate::codec::MqttPacket;
+    use crate::codec::MqttPacketCodec;
+    use crate::connect::ConnectOptions;
+    use crate::event::ConnectionEvent;
+    use crate::event::DisconnectReason;
+    use crate::reconnect::ReconnectPolicy;
+
+    async fn next_packet(
+        server: &mut Framed<tokio::io::DuplexStream, MqttPacketCodec>,
+    ) -> MqttPacket {
+        tokio::time::timeout(Duration::from_secs(5), server.next())
+            .await
+            .expect("Timed out waiting for a packet")
+            .expect("Connection closed")
+            .expect("Could not decode packet")
+    }
+
+    async fn send_connack(
+        server: &mut Framed<tokio::io::DuplexStream, MqttPacketCodec>,
+        session_present: bool,
+    ) {
+        server
+            .send(FormatMqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+    }
+
+    async fn send_connack_with_receive_maximum(
+        server: &mut Framed<tokio::io::DuplexStream, MqttPacketCodec>,
+        receive_maximum: u16,
+    ) {
+        server
+            .send(FormatMqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties {
+                        receive_maximum: Some(mqtt_format::v5::variable_header::ReceiveMaximum(
+                            receive_maximum.try_into().unwrap(),
+                        )),
+                        ..mqtt_format::v5::packets::connack::ConnackProperties::new()
+                    },
+                },
+            ))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn check_keep_alive_pings_and_times_out() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client = CoreClient::new_and_connect(
+            client,
+            incoming_sender,
+            ConnectOptions::new("idle-client").with_keep_alive(2),
+        );
+        let mut events = client.connection_events();
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        let connect = next_packet(&mut server).await;
+        assert!(matches!(
+            connect.get_packet(),
+            FormatMqttPacket::Connect(connect) if connect.keep_alive == 2
+        ));
+        send_connack(&mut server, false).await;
+
+        let ping = next_packet(&mut server).await;
+        assert!(matches!(ping.get_packet(), FormatMqttPacket::Pingreq(..)));
+        server
+            .send(FormatMqttPacket::Pingresp(
+                mqtt_format::v5::packets::pingresp::MPingresp,
+            ))
+            .await
+            .unwrap();
+
+        let ping = next_packet(&mut server).await;
+        assert!(matches!(ping.get_packet(), FormatMqttPacket::Pingreq(..)));
+
+        // Without an answer to the ping, the client gives up on the connection
+        let closed = tokio::time::timeout(Duration::from_secs(5), server.next())
+            .await
+            .expect("Client did not close the connection");
+        assert!(closed.is_none(), "Expected the connection to be closed");
+
+        loop {
+            match events.recv().await.unwrap() {
+                ConnectionEvent::Disconnected(reason) => {
+                    assert!(matches!(reason, DisconnectReason::KeepAliveTimeout));
+                    break;
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn check_reconnect_resumes_session() {
+        let (client_one, server_one) = tokio::io::duplex(1024);
+        let (client_two, server_two) = tokio::io::duplex(1024);
+        let transports = Arc::new(std::sync::Mutex::new(VecDeque::from([
+            client_one, client_two,
+        ])));
+
+        let factory = crate::reconnect::boxed_connection_factory(move || {
+            let transport = transports.lock().unwrap().pop_front();
+            async move {
+                transport.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+            }
+        });
+
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client = CoreClient::new_with_reconnect(
+            incoming_sender,
+            Reconnect::new(
+                ReconnectPolicy::new()
+                    .with_initial_backoff(Duration::from_millis(10))
+                    .with_jitter(false),
+                factory,
+            ),
+            ConnectOptions::new("reconnecting-client"),
+        );
+
+        let mut server = Framed::new(server_one, MqttPacketCodec);
+        let connect = next_packet(&mut server).await;
+        assert!(matches!(
+            connect.get_packet(),
+            FormatMqttPacket::Connect(connect)
+                if connect.clean_start && connect.client_identifier == "reconnecting-client"
+        ));
+        send_connack(&mut server, false).await;
+
+        let subscribe = client.subscribe(MqttPacket::new(FormatMqttPacket::Subscribe(
+            mqtt_format::v5::packets::subscribe::MSubscribe {
+                packet_identifier: mqtt_format::v5::variable_header::PacketIdentifier(
+                    1.try_into().unwrap(),
+                ),
+                properties: mqtt_format::v5::packets::subscribe::SubscribeProperties::new(),
+                subscriptions: mqtt_format::v5::packets::subscribe::Subscriptions::parse_complete(
+                    &[0x00, 0x03, b'a', b'/', b'b', 0x00],
+                )
+                .unwrap(),
+            },
+        )));
+        let broker = async {
+            let packet = next_packet(&mut server).await;
+            let FormatMqttPacket::Subscribe(subscribe) = packet.get_packet() else {
+                panic!("Expected a subscribe, got: {packet:?}");
+            };
+            server
+                .send(FormatMqttPacket::Suback(
+                    mqtt_format::v5::packets::suback::MSuback {
+                        packet_identifier: subscribe.packet_identifier,
+                        properties: mqtt_format::v5::packets::suback::SubackProperties::new(),
+                        reasons: &[mqtt_format::v5::packets::suback::SubackReasonCode::GrantedQoS0],
+                    },
+                ))
                 .await
                 .unwrap();
+        };
+        let (reasons, ()) = tokio::join!(subscribe, broker);
+        assert_eq!(
+            reasons.unwrap(),
+            [mqtt_format::v5::packets::suback::SubackReasonCode::GrantedQoS0]
+        );
+
+        let client = Arc::new(client);
+        let delivery = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client
+                    .publish(MqttPacket::new(FormatMqttPacket::Publish(
+                        mqtt_format::v5::packets::publish::MPublish {
+                            duplicate: false,
+                            quality_of_service: mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                            retain: false,
+                            topic_name: "a/b",
+                            // Replaced by the FSM with a free identifier
+                            packet_identifier: Some(
+                                mqtt_format::v5::variable_header::PacketIdentifier(
+                                    1.try_into().unwrap(),
+                                ),
+                            ),
+                            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                            payload: b"hello",
+                        },
+                    )))
+                    .await
+            }
+        });
+
+        let publish = next_packet(&mut server).await;
+        let FormatMqttPacket::Publish(first_publish) = publish.get_packet() else {
+            panic!("Expected a publish, got: {publish:?}");
+        };
+        assert!(!first_publish.duplicate);
+
+        // The connection drops before the server acknowledged the publish
+        drop(server);
+
+        let mut server = Framed::new(server_two, MqttPacketCodec);
+        let connect = next_packet(&mut server).await;
+        assert!(matches!(
+            connect.get_packet(),
+            FormatMqttPacket::Connect(connect) if !connect.clean_start
+        ));
+        send_connack(&mut server, false).await;
+
+        let publish = next_packet(&mut server).await;
+        let FormatMqttPacket::Publish(resent_publish) = publish.get_packet() else {
+            panic!("Expected a publish, got: {publish:?}");
+        };
+        assert!(resent_publish.duplicate);
+        assert_eq!(
+            resent_publish.packet_identifier,
+            first_publish.packet_identifier
+        );
+        assert_eq!(resent_publish.payload, b"hello");
+
+        assert!(matches!(
+            next_packet(&mut server).await.get_packet(),
+            FormatMqttPacket::Subscribe(..)
+        ));
+        assert!(!delivery.is_finished());
+
+        server
+            .send(FormatMqttPacket::Puback(
+                mqtt_format::v5::packets::puback::MPuback {
+                    packet_identifier: resent_publish.packet_identifier.unwrap(),
+                    reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), delivery)
+            .await
+            .expect("Publish was not reported as delivered")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_lost_session_completes_pending_release() {
+        let (client_one, server_one) = tokio::io::duplex(1024);
+        let (client_two, server_two) = tokio::io::duplex(1024);
+        let transports = Arc::new(std::sync::Mutex::new(VecDeque::from([
+            client_one, client_two,
+        ])));
+
+        let factory = crate::reconnect::boxed_connection_factory(move || {
+            let transport = transports.lock().unwrap().pop_front();
+            async move {
+                transport.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+            }
+        });
+
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client = Arc::new(CoreClient::new_with_reconnect(
+            incoming_sender,
+            Reconnect::new(
+                ReconnectPolicy::new()
+                    .with_initial_backoff(Duration::from_millis(10))
+                    .with_jitter(false),
+                factory,
+            ),
+            ConnectOptions::new("releasing-client"),
+        ));
+
+        let mut server = Framed::new(server_one, MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack(&mut server, false).await;
+
+        let delivery = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client
+                    .publish(MqttPacket::new(FormatMqttPacket::Publish(
+                        mqtt_format::v5::packets::publish::MPublish {
+                            duplicate: false,
+                            quality_of_service: mqtt_format::v5::qos::QualityOfService::ExactlyOnce,
+                            retain: false,
+                            topic_name: "a/b",
+                            packet_identifier: Some(
+                                mqtt_format::v5::variable_header::PacketIdentifier(
+                                    1.try_into().unwrap(),
+                                ),
+                            ),
+                            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                            payload: b"hello",
+                        },
+                    )))
+                    .await
+            }
+        });
+
+        let publish = next_packet(&mut server).await;
+        let FormatMqttPacket::Publish(publish) = publish.get_packet() else {
+            panic!("Expected a publish, got: {publish:?}");
+        };
+        server
+            .send(FormatMqttPacket::Pubrec(
+                mqtt_format::v5::packets::pubrec::MPubrec {
+                    packet_identifier: publish.packet_identifier.unwrap(),
+                    reason: mqtt_format::v5::packets::pubrec::PubrecReasonCode::Success,
+                    properties: mqtt_format::v5::packets::pubrec::PubrecProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_packet(&mut server).await.get_packet(),
+            FormatMqttPacket::Pubrel(..)
+        ));
+
+        // The connection drops before the server completed the publish, and so does its session
+        drop(server);
+
+        let mut server = Framed::new(server_two, MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack(&mut server, false).await;
+
+        tokio::time::timeout(Duration::from_secs(5), delivery)
+            .await
+            .expect("Publish was not reported as delivered")
+            .unwrap()
+            .unwrap();
+
+        // The server would only answer a release with PacketIdentifierNotFound
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), server.next())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn check_backoff_when_accepted_connections_drop() {
+        let (transports, servers): (VecDeque<_>, Vec<_>) =
+            (0..4).map(|_| tokio::io::duplex(1024)).unzip();
+        let transports = Arc::new(std::sync::Mutex::new(transports));
+        let attempts = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let factory = crate::reconnect::boxed_connection_factory({
+            let attempts = attempts.clone();
+            move || {
+                attempts.lock().unwrap().push(tokio::time::Instant::now());
+                let transport = transports.lock().unwrap().pop_front();
+                async move {
+                    transport
+                        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+                }
+            }
+        });
+
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let _client = CoreClient::new_with_reconnect(
+            incoming_sender,
+            Reconnect::new(
+                ReconnectPolicy::new()
+                    .with_initial_backoff(Duration::from_millis(100))
+                    .with_stable_after(Duration::from_secs(10))
+                    .with_jitter(false),
+                factory,
+            ),
+            ConnectOptions::new("dropped-client"),
+        );
+
+        let mut servers = servers.into_iter();
+
+        // The server accepts every connection, only to close it right away
+        for _ in 0..3 {
+            let mut server = Framed::new(servers.next().unwrap(), MqttPacketCodec);
+            next_packet(&mut server).await;
+            send_connack(&mut server, false).await;
+        }
+
+        // The last connection stays up until the backoff starts over
+        let mut server = Framed::new(servers.next().unwrap(), MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack(&mut server, false).await;
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        drop(server);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let attempts = attempts.lock().unwrap();
+        let delays = attempts
+            .windows(2)
+            .map(|attempts| attempts[1] - attempts[0])
+            .collect::<Vec<_>>();
+        assert!(delays[0] >= Duration::from_millis(100), "{delays:?}");
+        assert!(delays[1] >= Duration::from_millis(200), "{delays:?}");
+        assert!(delays[2] >= Duration::from_millis(400), "{delays:?}");
+
+        // Even after a stable connection, the client does not reconnect right away
+        let stable_for = delays[3] - Duration::from_secs(10);
+        assert!(stable_for >= Duration::from_millis(100), "{delays:?}");
+        assert!(stable_for < Duration::from_millis(200), "{delays:?}");
+    }
+
+    fn disconnect_packet() -> MqttPacket {
+        MqttPacket::new(FormatMqttPacket::Disconnect(
+            mqtt_format::v5::packets::disconnect::MDisconnect {
+                reason_code:
+                    mqtt_format::v5::packets::disconnect::DisconnectReasonCode::NormalDisconnection,
+                properties: mqtt_format::v5::packets::disconnect::DisconnectProperties::new(),
+            },
+        ))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn check_disconnect_during_backoff() {
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let factory = crate::reconnect::boxed_connection_factory({
+            let attempts = attempts.clone();
+            move || {
+                attempts.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                async {
+                    Err::<tokio::io::DuplexStream, _>(std::io::Error::from(
+                        std::io::ErrorKind::ConnectionRefused,
+                    ))
+                }
+            }
+        });
+
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client = CoreClient::new_with_reconnect(
+            incoming_sender,
+            Reconnect::new(
+                ReconnectPolicy::new()
+                    .with_initial_backoff(Duration::from_secs(1))
+                    .with_jitter(false),
+                factory,
+            ),
+            ConnectOptions::new("refused-client"),
+        );
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(attempts.load(std::sync::atomic::Ordering::Relaxed) >= 2);
+
+        tokio::time::timeout(
+            Duration::from_millis(100),
+            client.disconnect(disconnect_packet(), Duration::from_secs(10)),
+        )
+        .await
+        .expect("Disconnecting waited for the backoff")
+        .unwrap();
+        tokio::time::timeout(Duration::from_millis(100), client.wait_for_shutdown())
+            .await
+            .expect("Client kept reconnecting");
+
+        let attempted = attempts.load(std::sync::atomic::Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(
+            attempts.load(std::sync::atomic::Ordering::Relaxed),
+            attempted
+        );
+    }
+
+    #[tokio::test]
+    async fn check_disconnect_during_handshake() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client =
+            CoreClient::new_and_connect(client, incoming_sender, ConnectOptions::default());
+
+        // The server never accepts the connection
+        let mut server = Framed::new(server, MqttPacketCodec);
+        next_packet(&mut server).await;
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            client.disconnect(disconnect_packet(), Duration::from_secs(10)),
+        )
+        .await
+        .expect("Disconnecting waited for the CONNACK")
+        .unwrap();
+
+        assert!(server.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn check_requests_that_are_not_requests() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client =
+            CoreClient::new_and_connect(client, incoming_sender, ConnectOptions::default());
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack(&mut server, false).await;
+
+        let pingreq = || {
+            MqttPacket::new(FormatMqttPacket::Pingreq(
+                mqtt_format::v5::packets::pingreq::MPingreq,
+            ))
+        };
+        assert!(matches!(
+            client.subscribe(pingreq()).await,
+            Err(crate::error::Error::NotASubscribe)
+        ));
+        assert!(matches!(
+            client.unsubscribe(pingreq()).await,
+            Err(crate::error::Error::NotAnUnsubscribe)
+        ));
+
+        // Neither was sent to the server
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), server.next())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn check_connection_events() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client =
+            CoreClient::new_and_connect(client, incoming_sender, ConnectOptions::default());
+        let mut events = client.connection_events();
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack(&mut server, true).await;
+
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ConnectionEvent::Connecting
+        ));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ConnectionEvent::Connected(connected) if connected.session_present()
+        ));
+
+        server
+            .send(FormatMqttPacket::Disconnect(
+                mqtt_format::v5::packets::disconnect::MDisconnect {
+                    reason_code:
+                        mqtt_format::v5::packets::disconnect::DisconnectReasonCode::ServerShuttingDown,
+                    properties: mqtt_format::v5::packets::disconnect::DisconnectProperties {
+                        reason_string: Some(mqtt_format::v5::variable_header::ReasonString(
+                            "maintenance",
+                        )),
+                        ..mqtt_format::v5::packets::disconnect::DisconnectProperties::new()
+                    },
+                },
+            ))
+            .await
+            .unwrap();
+
+        let ConnectionEvent::Disconnected(DisconnectReason::Server {
+            reason_code,
+            reason_string,
+            server_reference,
+        }) = events.recv().await.unwrap()
+        else {
+            panic!("Expected to be disconnected by the server");
+        };
+        assert_eq!(
+            reason_code,
+            mqtt_format::v5::packets::disconnect::DisconnectReasonCode::ServerShuttingDown
+        );
+        assert_eq!(reason_string.as_deref(), Some("maintenance"));
+        assert_eq!(server_reference, None);
+
+        tokio::time::timeout(Duration::from_secs(5), client.wait_for_shutdown())
+            .await
+            .expect("Client did not shut down");
+    }
+
+    #[tokio::test]
+    async fn check_refused_connection_event() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client =
+            CoreClient::new_and_connect(client, incoming_sender, ConnectOptions::default());
+        let mut events = client.connection_events();
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        next_packet(&mut server).await;
+        server
+            .send(FormatMqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code:
+                        mqtt_format::v5::packets::connack::ConnackReasonCode::NotAuthorized,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ConnectionEvent::Connecting
+        ));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ConnectionEvent::Disconnected(DisconnectReason::Refused(
+                mqtt_format::v5::packets::connack::ConnackReasonCode::NotAuthorized
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn check_hundreds_of_publishes_in_flight() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client = Arc::new(CoreClient::new_and_connect(
+            client,
+            incoming_sender,
+            ConnectOptions::new("bulk-uploader"),
+        ));
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack(&mut server, false).await;
+
+        let deliveries = (0..300)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    client
+                        .publish(MqttPacket::new(FormatMqttPacket::Publish(
+                            mqtt_format::v5::packets::publish::MPublish {
+                                duplicate: false,
+                                quality_of_service:
+                                    mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                                retain: false,
+                                topic_name: "uploads",
+                                packet_identifier: Some(
+                                    mqtt_format::v5::variable_header::PacketIdentifier(
+                                        1.try_into().unwrap(),
+                                    ),
+                                ),
+                                properties:
+                                    mqtt_format::v5::packets::publish::PublishProperties::new(),
+                                payload: b"chunk",
+                            },
+                        )))
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Every publish is sent before the first one is acknowledged
+        let mut identifiers = std::collections::BTreeSet::new();
+        for _ in 0..300 {
+            let packet = next_packet(&mut server).await;
+            let FormatMqttPacket::Publish(publish) = packet.get_packet() else {
+                panic!("Expected a publish, got: {packet:?}");
+            };
+            assert!(identifiers.insert(publish.packet_identifier.unwrap().0));
+        }
+
+        for packet_identifier in identifiers {
+            server
+                .send(FormatMqttPacket::Puback(
+                    mqtt_format::v5::packets::puback::MPuback {
+                        packet_identifier: mqtt_format::v5::variable_header::PacketIdentifier(
+                            packet_identifier,
+                        ),
+                        reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
+                        properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                    },
+                ))
+                .await
+                .unwrap();
+        }
+
+        for delivery in deliveries {
+            tokio::time::timeout(Duration::from_secs(5), delivery)
+                .await
+                .expect("Publish was not reported as delivered")
+                .unwrap()
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn check_receive_maximum_holds_back_publishes() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client = Arc::new(CoreClient::new_and_connect(
+            client,
+            incoming_sender,
+            ConnectOptions::new("flow-controlled-publisher"),
+        ));
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack_with_receive_maximum(&mut server, 2).await;
+
+        let deliveries = (0..5)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    client
+                        .publish(MqttPacket::new(FormatMqttPacket::Publish(
+                            mqtt_format::v5::packets::publish::MPublish {
+                                duplicate: false,
+                                quality_of_service:
+                                    mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                                retain: false,
+                                topic_name: "uploads",
+                                packet_identifier: Some(
+                                    mqtt_format::v5::variable_header::PacketIdentifier(
+                                        1.try_into().unwrap(),
+                                    ),
+                                ),
+                                properties:
+                                    mqtt_format::v5::packets::publish::PublishProperties::new(),
+                                payload: b"chunk",
+                            },
+                        )))
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let next_publish = async |server: &mut Framed<_, _>| {
+            let packet = next_packet(server).await;
+            let FormatMqttPacket::Publish(publish) = packet.get_packet() else {
+                panic!("Expected a publish, got: {packet:?}");
+            };
+            publish.packet_identifier.unwrap()
+        };
+        let puback = |packet_identifier| {
+            FormatMqttPacket::Puback(mqtt_format::v5::packets::puback::MPuback {
+                packet_identifier,
+                reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
+                properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+            })
+        };
+
+        let mut in_flight = std::collections::VecDeque::new();
+        in_flight.push_back(next_publish(&mut server).await);
+        in_flight.push_back(next_publish(&mut server).await);
+
+        // Every further publish waits for one in flight to be acknowledged
+        for _ in 2..5 {
+            assert!(
+                tokio::time::timeout(Duration::from_millis(100), server.next())
+                    .await
+                    .is_err()
+            );
+            server
+                .send(puback(in_flight.pop_front().unwrap()))
+                .await
+                .unwrap();
+            in_flight.push_back(next_publish(&mut server).await);
+        }
+
+        for packet_identifier in in_flight {
+            server.send(puback(packet_identifier)).await.unwrap();
+        }
+
+        for delivery in deliveries {
+            tokio::time::timeout(Duration::from_secs(5), delivery)
+                .await
+                .expect("Publish was not reported as delivered")
+                .unwrap()
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn check_protocol_violation_disconnects() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client =
+            CoreClient::new_and_connect(client, incoming_sender, ConnectOptions::default());
+        let mut events = client.connection_events();
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack(&mut server, false).await;
+
+        // Nothing was published, so there is nothing to acknowledge
+        server
+            .send(FormatMqttPacket::Puback(
+                mqtt_format::v5::packets::puback::MPuback {
+                    packet_identifier: mqtt_format::v5::variable_header::PacketIdentifier(
+                        9.try_into().unwrap(),
+                    ),
+                    reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+
+        let packet = next_packet(&mut server).await;
+        assert!(matches!(
+            packet.get_packet(),
+            FormatMqttPacket::Disconnect(disconnect)
+                if disconnect.reason_code
+                    == mqtt_format::v5::packets::disconnect::DisconnectReasonCode::ProtocolError
+        ));
+
+        loop {
+            if let ConnectionEvent::Disconnected(reason) = events.recv().await.unwrap() {
+                assert!(matches!(
+                    reason,
+                    DisconnectReason::ProtocolViolation(ProtocolViolation::UnknownPacketIdentifier)
+                ));
+                break;
+            }
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), client.wait_for_shutdown())
+            .await
+            .expect("Client did not shut down");
+    }
+
+    #[tokio::test]
+    async fn check_follow_redirect() {
+        let (client_one, server_one) = tokio::io::duplex(1024);
+        let (client_two, server_two) = tokio::io::duplex(1024);
+        let redirected = Arc::new(std::sync::Mutex::new(Some(client_two)));
+        let server_references = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client = CoreClient::new_and_connect(
+            client_one,
+            incoming_sender,
+            ConnectOptions::new("redirected-client").with_redirect({
+                let server_references = server_references.clone();
+                move |server_reference| {
+                    server_references.lock().unwrap().push(server_reference);
+                    let transport = redirected.lock().unwrap().take();
+                    async move {
+                        transport.ok_or_else(|| {
+                            std::io::Error::from(std::io::ErrorKind::ConnectionRefused)
+                        })
+                    }
+                }
+            }),
+        );
+        let mut events = client.connection_events();
+
+        let mut server = Framed::new(server_one, MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack(&mut server, false).await;
+        server
+            .send(FormatMqttPacket::Disconnect(
+                mqtt_format::v5::packets::disconnect::MDisconnect {
+                    reason_code:
+                        mqtt_format::v5::packets::disconnect::DisconnectReasonCode::UseAnotherServer,
+                    properties: mqtt_format::v5::packets::disconnect::DisconnectProperties {
+                        server_reference: Some(mqtt_format::v5::variable_header::ServerReference(
+                            "other.example.com:1883",
+                        )),
+                        ..mqtt_format::v5::packets::disconnect::DisconnectProperties::new()
+                    },
+                },
+            ))
+            .await
+            .unwrap();
+
+        let mut server = Framed::new(server_two, MqttPacketCodec);
+        let connect = next_packet(&mut server).await;
+        assert!(matches!(
+            connect.get_packet(),
+            FormatMqttPacket::Connect(connect)
+                if connect.client_identifier == "redirected-client"
+        ));
+        assert_eq!(
+            *server_references.lock().unwrap(),
+            ["other.example.com:1883"]
+        );
+
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ConnectionEvent::Connecting
+        ));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ConnectionEvent::Connected(..)
+        ));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ConnectionEvent::Disconnected(DisconnectReason::Server {
+                reason_code:
+                    mqtt_format::v5::packets::disconnect::DisconnectReasonCode::UseAnotherServer,
+                server_reference: Some(server_reference),
+                ..
+            }) if server_reference == "other.example.com:1883"
+        ));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ConnectionEvent::Redirecting { server_reference }
+                if server_reference == "other.example.com:1883"
+        ));
+    }
+
+    #[tokio::test]
+    async fn check_keep_assigned_client_identifier() {
+        let (client_one, server_one) = tokio::io::duplex(1024);
+        let (client_two, server_two) = tokio::io::duplex(1024);
+        let transports = Arc::new(std::sync::Mutex::new(VecDeque::from([
+            client_one, client_two,
+        ])));
+
+        let factory = crate::reconnect::boxed_connection_factory(move || {
+            let transport = transports.lock().unwrap().pop_front();
+            async move {
+                transport.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+            }
+        });
+
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client = CoreClient::new_with_reconnect(
+            incoming_sender,
+            Reconnect::new(
+                ReconnectPolicy::new()
+                    .with_initial_backoff(Duration::from_millis(10))
+                    .with_jitter(false),
+                factory,
+            ),
+            ConnectOptions::new(""),
+        );
+        let mut events = client.connection_events();
+
+        let mut server = Framed::new(server_one, MqttPacketCodec);
+        let connect = next_packet(&mut server).await;
+        assert!(matches!(
+            connect.get_packet(),
+            FormatMqttPacket::Connect(connect) if connect.client_identifier.is_empty()
+        ));
+        server
+            .send(FormatMqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties {
+                        assigned_client_identifier: Some(
+                            mqtt_format::v5::variable_header::AssignedClientIdentifier(
+                                "assigned-by-server",
+                            ),
+                        ),
+                        ..mqtt_format::v5::packets::connack::ConnackProperties::new()
+                    },
+                },
+            ))
+            .await
+            .unwrap();
+
+        loop {
+            if let ConnectionEvent::Connected(connected) = events.recv().await.unwrap() {
+                assert_eq!(
+                    connected.assigned_client_identifier(),
+                    Some("assigned-by-server")
+                );
+                break;
+            }
+        }
+
+        // The connection drops, and the client resumes the session under the assigned identifier
+        drop(server);
+
+        let mut server = Framed::new(server_two, MqttPacketCodec);
+        let connect = next_packet(&mut server).await;
+        assert!(matches!(
+            connect.get_packet(),
+            FormatMqttPacket::Connect(connect)
+                if !connect.clean_start && connect.client_identifier == "assigned-by-server"
+        ));
+    }
+
+    #[tokio::test]
+    async fn check_manual_acknowledgement() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, mut incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let _client = CoreClient::new_and_connect(
+            client,
+            incoming_sender,
+            ConnectOptions::new("acknowledging-client").with_manual_acknowledgement(true),
+        );
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack(&mut server, false).await;
+
+        let publish = |quality_of_service, id: u16| {
+            FormatMqttPacket::Publish(mqtt_format::v5::packets::publish::MPublish {
+                duplicate: false,
+                quality_of_service,
+                retain: false,
+                topic_name: "a/b",
+                packet_identifier: Some(mqtt_format::v5::variable_header::PacketIdentifier(
+                    id.try_into().unwrap(),
+                )),
+                properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                payload: b"hello",
+            })
+        };
+
+        server
+            .send(publish(
+                mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                5,
+            ))
+            .await
+            .unwrap();
+        let at_least_once = incoming_receiver.recv().await.unwrap();
+        server
+            .send(publish(
+                mqtt_format::v5::qos::QualityOfService::ExactlyOnce,
+                6,
+            ))
+            .await
+            .unwrap();
+        let exactly_once = incoming_receiver.recv().await.unwrap();
+
+        // Nothing is acknowledged before the application did so
+        assert!(at_least_once.acknowledger().is_some());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), server.next())
+                .await
+                .is_err()
+        );
+
+        exactly_once.ack();
+        let pubrec = next_packet(&mut server).await;
+        assert!(matches!(
+            pubrec.get_packet(),
+            FormatMqttPacket::Pubrec(pubrec)
+                if pubrec.packet_identifier.0.get() == 6
+                    && pubrec.reason == mqtt_format::v5::packets::pubrec::PubrecReasonCode::Success
+        ));
+
+        at_least_once.ack_with_reason(
+            mqtt_format::v5::packets::puback::PubackReasonCode::ImplementationSpecificError,
+        );
+        let puback = next_packet(&mut server).await;
+        assert!(matches!(
+            puback.get_packet(),
+            FormatMqttPacket::Puback(puback)
+                if puback.packet_identifier.0.get() == 5
+                    && puback.reason
+                        == mqtt_format::v5::packets::puback::PubackReasonCode::ImplementationSpecificError
+        ));
+    }
+
+    #[tokio::test]
+    async fn check_receive_maximum_holds_back_acknowledgements() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, mut incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client = CoreClient::new_and_connect(
+            client,
+            incoming_sender,
+            ConnectOptions::new("flow-controlled-client")
+                .with_receive_maximum(1.try_into().unwrap()),
+        );
+        let mut events = client.connection_events();
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack(&mut server, false).await;
+
+        let publish = |id: u16| {
+            FormatMqttPacket::Publish(mqtt_format::v5::packets::publish::MPublish {
+                duplicate: false,
+                quality_of_service: mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                retain: false,
+                topic_name: "a/b",
+                packet_identifier: Some(mqtt_format::v5::variable_header::PacketIdentifier(
+                    id.try_into().unwrap(),
+                )),
+                properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                payload: b"hello",
+            })
+        };
+
+        server.send(publish(1)).await.unwrap();
+        let first = incoming_receiver.recv().await.unwrap();
+
+        // Not acknowledged before the message was routed
+        assert!(first.acknowledger().is_none());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), server.next())
+                .await
+                .is_err()
+        );
+
+        first.ack();
+        let puback = next_packet(&mut server).await;
+        assert!(matches!(
+            puback.get_packet(),
+            FormatMqttPacket::Puback(puback) if puback.packet_identifier.0.get() == 1
+        ));
+
+        // A second unacknowledged publish is more than the client allows
+        server.send(publish(2)).await.unwrap();
+        let _second = incoming_receiver.recv().await.unwrap();
+        server.send(publish(3)).await.unwrap();
+
+        let disconnect = next_packet(&mut server).await;
+        assert!(matches!(
+            disconnect.get_packet(),
+            FormatMqttPacket::Disconnect(disconnect)
+                if disconnect.reason_code
+                    == mqtt_format::v5::packets::disconnect::DisconnectReasonCode::ReceiveMaximumExceeded
+        ));
+
+        loop {
+            match events.recv().await.unwrap() {
+                ConnectionEvent::Disconnected(reason) => {
+                    assert!(matches!(
+                        reason,
+                        DisconnectReason::ProtocolViolation(
+                            ProtocolViolation::ReceiveMaximumExceeded
+                        )
+                    ));
+                    break;
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn check_many_unreleased_qos2_publishes() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, mut incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let _client = CoreClient::new_and_connect(
+            client,
+            incoming_sender,
+            ConnectOptions::new("qos2-receiver").with_receive_maximum(1000.try_into().unwrap()),
+        );
+        tokio::spawn(async move {
+            while let Some(message) = incoming_receiver.recv().await {
+                message.ack();
+            }
+        });
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        let connect = next_packet(&mut server).await;
+        assert!(matches!(
+            connect.get_packet(),
+            FormatMqttPacket::Connect(connect)
+                if connect.properties.receive_maximum().map(|maximum| maximum.0.get()) == Some(1000)
+        ));
+        send_connack(&mut server, false).await;
+
+        // None of them is released, so the client has to remember every identifier
+        for id in 1..=100u16 {
+            server
+                .send(FormatMqttPacket::Publish(
+                    mqtt_format::v5::packets::publish::MPublish {
+                        duplicate: false,
+                        quality_of_service: mqtt_format::v5::qos::QualityOfService::ExactlyOnce,
+                        retain: false,
+                        topic_name: "a/b",
+                        packet_identifier: Some(
+                            mqtt_format::v5::variable_header::PacketIdentifier(
+                                id.try_into().unwrap(),
+                            ),
+                        ),
+                        properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                        payload: b"hello",
+                    },
+                ))
+                .await
+                .unwrap();
+
+            let pubrec = next_packet(&mut server).await;
+            assert!(
+                matches!(
+                    pubrec.get_packet(),
+                    FormatMqttPacket::Pubrec(pubrec) if pubrec.packet_identifier.0.get() == id
+                ),
+                "Expected a PUBREC, got: {pubrec:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn check_topic_aliases() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, mut incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client = CoreClient::new_and_connect(
+            client,
+            incoming_sender,
+            ConnectOptions::new("aliasing-client").with_topic_alias_maximum(1),
+        );
+        let mut events = client.connection_events();
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        next_packet(&mut server).await;
+        server
+            .send(FormatMqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties {
+                        topic_alias_maximum: Some(
+                            mqtt_format::v5::variable_header::TopicAliasMaximum(1),
+                        ),
+                        ..mqtt_format::v5::packets::connack::ConnackProperties::new()
+                    },
+                },
+            ))
+            .await
+            .unwrap();
+
+        let publish =
+            |topic_name, alias: Option<u16>| mqtt_format::v5::packets::publish::MPublish {
+                duplicate: false,
+                quality_of_service: mqtt_format::v5::qos::QualityOfService::AtMostOnce,
+                retain: false,
+                topic_name,
+                packet_identifier: None,
+                properties: mqtt_format::v5::packets::publish::PublishProperties {
+                    topic_alias: alias.map(|alias| {
+                        mqtt_format::v5::variable_header::TopicAlias(alias.try_into().unwrap())
+                    }),
+                    ..mqtt_format::v5::packets::publish::PublishProperties::new()
+                },
+                payload: b"21.5",
+            };
+
+        // The server sets an alias, and then only uses it
+        for topic_name in ["sensors/kitchen/temperature", ""] {
+            server
+                .send(FormatMqttPacket::Publish(publish(topic_name, Some(1))))
+                .await
+                .unwrap();
+            let message = incoming_receiver.recv().await.unwrap();
+            assert_eq!(message.topic().to_string(), "sensors/kitchen/temperature");
+            assert_eq!(message.topic_alias(), Some(1.try_into().unwrap()));
+        }
+
+        // Only as many topics are aliased as the server accepts
+        for (topic_name, expected) in [
+            (
+                "sensors/hall/temperature",
+                publish("sensors/hall/temperature", Some(1)),
+            ),
+            ("sensors/hall/temperature", publish("", Some(1))),
+            (
+                "sensors/hall/humidity",
+                publish("sensors/hall/humidity", None),
+            ),
+        ] {
+            client
+                .publish(MqttPacket::new(FormatMqttPacket::Publish(publish(
+                    topic_name, None,
+                ))))
+                .await
+                .unwrap();
+
+            let sent = next_packet(&mut server).await;
+            assert_eq!(*sent.get_packet(), FormatMqttPacket::Publish(expected));
+        }
+
+        // Aliases above the advertised maximum close the connection
+        server
+            .send(FormatMqttPacket::Publish(publish("", Some(2))))
+            .await
+            .unwrap();
+
+        let disconnect = next_packet(&mut server).await;
+        assert!(matches!(
+            disconnect.get_packet(),
+            FormatMqttPacket::Disconnect(disconnect)
+                if disconnect.reason_code
+                    == mqtt_format::v5::packets::disconnect::DisconnectReasonCode::TopicAliasInvalid
+        ));
+
+        loop {
+            if let ConnectionEvent::Disconnected(reason) = events.recv().await.unwrap() {
+                assert!(matches!(
+                    reason,
+                    DisconnectReason::ProtocolViolation(ProtocolViolation::InvalidTopicAlias)
+                ));
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn check_offline_queue_flushes_in_order() {
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client = CoreClient::new(
+            incoming_sender,
+            ConnectOptions::new("queueing-client")
+                .with_offline_queue(crate::queue::OfflineQueue::new()),
+        );
+
+        let publish = |payload: &'static [u8]| {
+            let client = client.clone();
+            let packet = MqttPacket::new(FormatMqttPacket::Publish(
+                mqtt_format::v5::packets::publish::MPublish {
+                    duplicate: false,
+                    quality_of_service: mqtt_format::v5::qos::QualityOfService::AtMostOnce,
+                    retain: false,
+                    topic_name: "a/b",
+                    packet_identifier: None,
+                    properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                    payload,
+                },
+            ));
+            tokio::spawn(async move { client.publish(packet).await })
+        };
+
+        let first = publish(b"first");
+        tokio::task::yield_now().await;
+        let not_a_publish = tokio::spawn({
+            let client = client.clone();
+            async move {
+                let packet = MqttPacket::new(FormatMqttPacket::Pingreq(
+                    mqtt_format::v5::packets::pingreq::MPingreq,
+                ));
+                client.publish(packet).await
+            }
+        });
+        tokio::task::yield_now().await;
+        let second = publish(b"second");
+        tokio::task::yield_now().await;
+
+        let (transport, server) = tokio::io::duplex(1024);
+        client.connect(transport).await.unwrap();
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack(&mut server, false).await;
+
+        for expected in [&b"first"[..], b"second"] {
+            let packet = next_packet(&mut server).await;
+            assert!(matches!(
+                packet.get_packet(),
+                FormatMqttPacket::Publish(publish) if publish.payload == expected
+            ));
+        }
+
+        first.await.unwrap().unwrap();
+        assert!(matches!(
+            not_a_publish.await.unwrap(),
+            Err(crate::error::Error::NotAPublish)
+        ));
+        second.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_offline_queue_respects_receive_maximum() {
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client = CoreClient::new(
+            incoming_sender,
+            ConnectOptions::new("queueing-client")
+                .with_offline_queue(crate::queue::OfflineQueue::new()),
+        );
+
+        let payloads: [&'static [u8]; 5] = [b"0", b"1", b"2", b"3", b"4"];
+        let mut deliveries = Vec::new();
+        for payload in payloads {
+            let client = client.clone();
+            let packet = MqttPacket::new(FormatMqttPacket::Publish(
+                mqtt_format::v5::packets::publish::MPublish {
+                    duplicate: false,
+                    quality_of_service: mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                    retain: false,
+                    topic_name: "a/b",
+                    packet_identifier: Some(mqtt_format::v5::variable_header::PacketIdentifier(
+                        1.try_into().unwrap(),
+                    )),
+                    properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                    payload,
+                },
+            ));
+            deliveries.push(tokio::spawn(async move { client.publish(packet).await }));
+            tokio::task::yield_now().await;
+        }
+
+        let (transport, server) = tokio::io::duplex(1024);
+        client.connect(transport).await.unwrap();
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack_with_receive_maximum(&mut server, 2).await;
+
+        let mut in_flight = std::collections::VecDeque::new();
+        for expected in payloads {
+            // More than the server allows would be refused, and the publish lost
+            if in_flight.len() == 2 {
+                assert!(
+                    tokio::time::timeout(Duration::from_millis(100), server.next())
+                        .await
+                        .is_err()
+                );
+                server
+                    .send(FormatMqttPacket::Puback(
+                        mqtt_format::v5::packets::puback::MPuback {
+                            packet_identifier: in_flight.pop_front().unwrap(),
+                            reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
+                            properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                        },
+                    ))
+                    .await
+                    .unwrap();
+            }
+
+            let packet = next_packet(&mut server).await;
+            let FormatMqttPacket::Publish(publish) = packet.get_packet() else {
+                panic!("Expected a publish, got: {packet:?}");
+            };
+            assert_eq!(publish.payload, expected);
+            in_flight.push_back(publish.packet_identifier.unwrap());
+        }
+
+        for packet_identifier in in_flight {
+            server
+                .send(FormatMqttPacket::Puback(
+                    mqtt_format::v5::packets::puback::MPuback {
+                        packet_identifier,
+                        reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
+                        properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                    },
+                ))
+                .await
+                .unwrap();
+        }
+
+        for delivery in deliveries {
+            tokio::time::timeout(Duration::from_secs(5), delivery)
+                .await
+                .expect("Queued publish was not reported as delivered")
+                .unwrap()
+                .unwrap();
+        }
+    }
+
+    /// A session store that can still be inspected after it was handed to the client
+    #[derive(Clone, Default)]
+    struct InspectableStore(
+        Arc<std::sync::Mutex<crate::session::MemorySessionStore>>,
+        /// How often the store was synced
+        Arc<std::sync::atomic::AtomicUsize>,
+    );
+
+    impl crate::session::SessionStore for InspectableStore {
+        fn load(&mut self) -> std::io::Result<crate::session::StoredSession> {
+            self.0.lock().unwrap().load()
+        }
+
+        fn store_outgoing(
+            &mut self,
+            id: std::num::NonZeroU16,
+            packet: &[u8],
+        ) -> std::io::Result<()> {
+            self.0.lock().unwrap().store_outgoing(id, packet)
+        }
+
+        fn release_outgoing(&mut self, id: std::num::NonZeroU16) -> std::io::Result<()> {
+            self.0.lock().unwrap().release_outgoing(id)
+        }
+
+        fn store_incoming(&mut self, id: std::num::NonZeroU16) -> std::io::Result<()> {
+            self.0.lock().unwrap().store_incoming(id)
+        }
+
+        fn release_incoming(&mut self, id: std::num::NonZeroU16) -> std::io::Result<()> {
+            self.0.lock().unwrap().release_incoming(id)
+        }
+
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.1.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn check_session_store_outlives_client() {
+        use crate::session::SessionStore;
+
+        let mut store = InspectableStore::default();
+        let options = ConnectOptions::new("persistent-client")
+            .with_clean_start(false)
+            .with_session_store(store.clone());
+
+        let incoming_publish = mqtt_format::v5::packets::publish::MPublish {
+            duplicate: false,
+            quality_of_service: mqtt_format::v5::qos::QualityOfService::ExactlyOnce,
+            retain: false,
+            topic_name: "a/b",
+            packet_identifier: Some(mqtt_format::v5::variable_header::PacketIdentifier(
+                42.try_into().unwrap(),
+            )),
+            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+            payload: b"incoming",
+        };
+
+        // The first client publishes and receives, but loses the connection before either completes
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, mut incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let first_client = CoreClient::new_and_connect(client, incoming_sender, options.clone());
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack(&mut server, false).await;
+
+        let publishing = tokio::spawn({
+            let client = first_client.clone();
+            async move {
+                client
+                    .publish(MqttPacket::new(FormatMqttPacket::Publish(
+                        mqtt_format::v5::packets::publish::MPublish {
+                            duplicate: false,
+                            quality_of_service: mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                            retain: false,
+                            topic_name: "c/d",
+                            // The FSM replaces the identifier with a free one
+                            packet_identifier: Some(
+                                mqtt_format::v5::variable_header::PacketIdentifier(
+                                    1.try_into().unwrap(),
+                                ),
+                            ),
+                            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                            payload: b"outgoing",
+                        },
+                    )))
+                    .await
+            }
+        });
+
+        let publish = next_packet(&mut server).await;
+        let FormatMqttPacket::Publish(publish) = publish.get_packet() else {
+            panic!("Expected a publish, got: {publish:?}");
+        };
+        let outgoing_id = publish.packet_identifier.unwrap();
+
+        server
+            .send(FormatMqttPacket::Publish(incoming_publish.clone()))
+            .await
+            .unwrap();
+        let received = incoming_receiver.recv().await.unwrap();
+        assert_eq!(received.topic().to_string(), incoming_publish.topic_name);
+        received.ack();
+        let pubrec = next_packet(&mut server).await;
+        assert!(matches!(pubrec.get_packet(), FormatMqttPacket::Pubrec(..)));
+
+        let stored = store.load().unwrap();
+        assert_eq!(stored.outgoing.keys().collect::<Vec<_>>(), [&outgoing_id.0]);
+        assert_eq!(
+            stored.incoming.iter().collect::<Vec<_>>(),
+            [&incoming_publish.packet_identifier.unwrap().0]
+        );
+
+        drop(server);
+        first_client.wait_for_shutdown().await;
+        publishing.abort();
+        drop(first_client);
+
+        // A new client picks up the session from the store
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, mut incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let _second_client = CoreClient::new_and_connect(client, incoming_sender, options);
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        let connect = next_packet(&mut server).await;
+        assert!(matches!(
+            connect.get_packet(),
+            FormatMqttPacket::Connect(connect) if !connect.clean_start
+        ));
+        send_connack(&mut server, true).await;
+
+        let resent = next_packet(&mut server).await;
+        assert!(matches!(
+            resent.get_packet(),
+            FormatMqttPacket::Publish(publish)
+                if publish.duplicate
+                    && publish.packet_identifier == Some(outgoing_id)
+                    && publish.payload == b"outgoing"
+        ));
+
+        // The server did not see the PUBREC, the publish must not be handed out again
+        server
+            .send(FormatMqttPacket::Publish(
+                mqtt_format::v5::packets::publish::MPublish {
+                    duplicate: true,
+                    ..incoming_publish.clone()
+                },
+            ))
+            .await
+            .unwrap();
+        let pubrec = next_packet(&mut server).await;
+        assert!(matches!(pubrec.get_packet(), FormatMqttPacket::Pubrec(..)));
+        assert!(incoming_receiver.try_recv().is_err());
+
+        server
+            .send(FormatMqttPacket::Puback(
+                mqtt_format::v5::packets::puback::MPuback {
+                    packet_identifier: outgoing_id,
+                    reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+        server
+            .send(FormatMqttPacket::Pubrel(
+                mqtt_format::v5::packets::pubrel::MPubrel {
+                    packet_identifier: incoming_publish.packet_identifier.unwrap(),
+                    reason: mqtt_format::v5::packets::pubrel::PubrelReasonCode::Success,
+                    properties: mqtt_format::v5::packets::pubrel::PubrelProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+        let pubcomp = next_packet(&mut server).await;
+        assert!(matches!(
+            pubcomp.get_packet(),
+            FormatMqttPacket::Pubcomp(..)
+        ));
+
+        assert!(store.load().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn check_clean_disconnect_syncs_session_store() {
+        use crate::session::SessionStore;
+
+        let mut store = InspectableStore::default();
+        let (client, server) = tokio::io::duplex(1024);
+        let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+        let client = Arc::new(CoreClient::new_and_connect(
+            client,
+            incoming_sender,
+            ConnectOptions::new("persistent-client")
+                .with_clean_start(false)
+                .with_session_store(store.clone()),
+        ));
+
+        let mut server = Framed::new(server, MqttPacketCodec);
+        next_packet(&mut server).await;
+        send_connack(&mut server, false).await;
+
+        let _publishing = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client
+                    .publish(MqttPacket::new(FormatMqttPacket::Publish(
+                        mqtt_format::v5::packets::publish::MPublish {
+                            duplicate: false,
+                            quality_of_service: mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                            retain: false,
+                            topic_name: "c/d",
+                            packet_identifier: Some(
+                                mqtt_format::v5::variable_header::PacketIdentifier(
+                                    1.try_into().unwrap(),
+                                ),
+                            ),
+                            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                            payload: b"outgoing",
+                        },
+                    )))
+                    .await
+            }
+        });
+        next_packet(&mut server).await;
+        assert_eq!(store.1.load(std::sync::atomic::Ordering::Relaxed), 0);
+
+        // The server never acknowledges the publish
+        client
+            .disconnect(disconnect_packet(), Duration::from_millis(50))
+            .await
+            .unwrap();
+
+        assert_eq!(store.1.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert_eq!(store.load().unwrap().outgoing.len(), 1);
+    }
+
+    #[cfg(feature = "scram")]
+    mod scram {
+        use futures::SinkExt;
+        use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+        use mqtt_format::v5::packets::auth::AuthReasonCode;
+        use mqtt_format::v5::variable_header::AuthenticationData;
+        use mqtt_format::v5::variable_header::AuthenticationMethod;
+        use tokio_util::codec::Framed;
+
+        use super::next_packet;
+        use crate::client::CoreClient;
+        use crate::codec::MqttPacketCodec;
+        use crate::connect::AuthenticationError;
+        use crate::connect::ConnectOptions;
+        use crate::connect::ScramSha256;
+        use crate::event::ConnectionEvent;
+        use crate::event::DisconnectReason;
+
+        // The example exchange of RFC 7677
+        const CLIENT_FIRST: &[u8] = b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+        const SERVER_FIRST: &[u8] = b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        const CLIENT_FINAL: &[u8] = b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+        const SERVER_FINAL: &[u8] = b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+
+        fn options() -> ConnectOptions {
+            ConnectOptions::default().with_authenticator(|| {
+                ScramSha256::new("user", "pencil").with_client_nonce("rOprNGfwEbeRWgbNEkqO")
+            })
+        }
+
+        fn auth_packet(reason: AuthReasonCode, data: &[u8]) -> FormatMqttPacket<'_> {
+            let mut properties = mqtt_format::v5::packets::auth::AuthProperties::new();
+            properties.authentication_method = Some(AuthenticationMethod("SCRAM-SHA-256"));
+            properties.authentication_data = Some(AuthenticationData(data));
+
+            FormatMqttPacket::Auth(mqtt_format::v5::packets::auth::MAuth { reason, properties })
+        }
+
+        /// Answer the client-first message with the server-first, and expect the client-final
+        async fn challenge(server: &mut Framed<tokio::io::DuplexStream, MqttPacketCodec>) {
+            server
+                .send(auth_packet(
+                    AuthReasonCode::ContinueAuthentication,
+                    SERVER_FIRST,
+                ))
+                .await
+                .unwrap();
+
+            let packet = next_packet(server).await;
+            let FormatMqttPacket::Auth(auth) = packet.get_packet() else {
+                panic!("Expected an auth packet, got: {packet:?}");
+            };
+            assert_eq!(auth.reason, AuthReasonCode::ContinueAuthentication);
+            assert_eq!(
+                auth.properties.authentication_data().unwrap().0,
+                CLIENT_FINAL
+            );
+        }
+
+        fn connack(server_final: &[u8]) -> FormatMqttPacket<'_> {
+            let mut properties = mqtt_format::v5::packets::connack::ConnackProperties::new();
+            properties.authentication_method = Some(AuthenticationMethod("SCRAM-SHA-256"));
+            properties.authentication_data = Some(AuthenticationData(server_final));
+
+            FormatMqttPacket::Connack(mqtt_format::v5::packets::connack::MConnack {
+                session_present: false,
+                reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                properties,
+            })
+        }
+
+        #[tokio::test]
+        async fn check_scram_authentication() {
+            let (client, server) = tokio::io::duplex(1024);
+            let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+            let client = CoreClient::new_and_connect(client, incoming_sender, options());
+            let mut events = client.connection_events();
+
+            let mut server = Framed::new(server, MqttPacketCodec);
+            let packet = next_packet(&mut server).await;
+            let FormatMqttPacket::Connect(connect) = packet.get_packet() else {
+                panic!("Expected a connect packet, got: {packet:?}");
+            };
+            assert_eq!(
+                connect.properties.authentication_method().unwrap().0,
+                "SCRAM-SHA-256"
+            );
+            assert_eq!(
+                connect.properties.authentication_data().unwrap().0,
+                CLIENT_FIRST
+            );
+
+            challenge(&mut server).await;
+            server.send(connack(SERVER_FINAL)).await.unwrap();
+
+            assert!(matches!(
+                events.recv().await.unwrap(),
+                ConnectionEvent::Connecting
+            ));
+            assert!(matches!(
+                events.recv().await.unwrap(),
+                ConnectionEvent::Connected(..)
+            ));
+
+            let broker = async {
+                let packet = next_packet(&mut server).await;
+                let FormatMqttPacket::Auth(auth) = packet.get_packet() else {
+                    panic!("Expected an auth packet, got: {packet:?}");
+                };
+                assert_eq!(auth.reason, AuthReasonCode::ReAuthenticate);
+                assert_eq!(
+                    auth.properties.authentication_data().unwrap().0,
+                    CLIENT_FIRST
+                );
+
+                challenge(&mut server).await;
+                server
+                    .send(auth_packet(AuthReasonCode::Success, SERVER_FINAL))
+                    .await
+                    .unwrap();
+            };
+
+            let (reauthenticated, ()) = tokio::join!(client.reauthenticate(), broker);
+            reauthenticated.unwrap();
+        }
+
+        #[tokio::test]
+        async fn check_scram_unverified_server() {
+            let (client, server) = tokio::io::duplex(1024);
+            let (incoming_sender, _incoming_receiver) = tokio::sync::mpsc::channel(16);
+            let client = CoreClient::new_and_connect(client, incoming_sender, options());
+            let mut events = client.connection_events();
+
+            let mut server = Framed::new(server, MqttPacketCodec);
+            next_packet(&mut server).await;
+            challenge(&mut server).await;
+            server
+                .send(connack(b"v=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="))
+                .await
+                .unwrap();
+
+            assert!(matches!(
+                events.recv().await.unwrap(),
+                ConnectionEvent::Connecting
+            ));
+            assert!(matches!(
+                events.recv().await.unwrap(),
+                ConnectionEvent::Disconnected(DisconnectReason::AuthenticationFailed(
+                    AuthenticationError::ServerNotVerified
+                ))
+            ));
         }
-        _ => unreachable!(),
     }
 }
@@ -41,6 +41,41 @@ fn write_slice(&mut self, u: &[u8]) -> mqtt_format::v5::write::WResult<Self> {
     }
 }
 
+/// Owned user properties, encoded the way [`UserProperties`] expects them on the wire
+///
+/// [`UserProperties`]: mqtt_format::v5::variable_header::UserProperties
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UserPropertiesBuf(Vec<u8>);
+
+impl UserPropertiesBuf {
+    pub(crate) fn push(&mut self, key: &str, value: &str) {
+        use mqtt_format::v5::variable_header::MqttProperties;
+        use mqtt_format::v5::variable_header::UserProperties;
+        use mqtt_format::v5::variable_header::UserProperty;
+
+        let mut buffer = tokio_util::bytes::BytesMut::new();
+        let mut writer = BytesMutWriter(&mut buffer);
+
+        // The first property identifier is written as part of the properties, not the value
+        if !self.0.is_empty() {
+            mqtt_format::v5::integers::write_variable_u32(&mut writer, UserProperties::IDENTIFIER)
+                .expect("Writing to a buffer cannot fail");
+        }
+
+        UserProperty { key, value }
+            .write(&mut writer)
+            .expect("User property keys and values must be at most 65535 bytes long");
+
+        self.0.extend_from_slice(&buffer);
+    }
+
+    pub(crate) fn as_user_properties(
+        &self,
+    ) -> Option<mqtt_format::v5::variable_header::UserProperties<'_>> {
+        (!self.0.is_empty()).then(|| mqtt_format::v5::variable_header::UserProperties(&self.0))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MqttPacket {
     packet: Yoke<FormatMqttPacket<'static>, Arc<[u8]>>,
@@ -64,6 +99,29 @@ pub fn new(packet: FormatMqttPacket<'_>) -> MqttPacket {
     pub fn get_packet(&self) -> &FormatMqttPacket<'_> {
         self.packet.get()
     }
+
+    /// Parse a packet previously encoded with [`as_bytes`](Self::as_bytes)
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<MqttPacket> {
+        let cart: Arc<[u8]> = Arc::from(bytes);
+
+        Yoke::try_attach_to_cart(cart, |data| FormatMqttPacket::parse_complete(data))
+            .ok()
+            .map(|packet| MqttPacket { packet })
+    }
+
+    /// The packet, as it is sent on the wire
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.packet.backing_cart()
+    }
+
+    /// Share a part of the packet, without copying it
+    ///
+    /// # Panics
+    ///
+    /// If `part` does not point into the packet
+    pub(crate) fn slice_ref(&self, part: &[u8]) -> tokio_util::bytes::Bytes {
+        tokio_util::bytes::Bytes::from_owner(self.packet.backing_cart().clone()).slice_ref(part)
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
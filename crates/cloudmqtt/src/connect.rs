@@ -0,0 +1,526 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Options sent to the server when connecting
+
+use std::num::NonZeroU16;
+use std::sync::Arc;
+
+pub use cloudmqtt_core::client::AuthenticationError;
+pub use cloudmqtt_core::client::Authenticator;
+#[cfg(feature = "scram")]
+pub use cloudmqtt_core::client::ScramSha256;
+use mqtt_format::v5::packets::connect::ConnectProperties;
+use mqtt_format::v5::packets::connect::ConnectWillProperties;
+use mqtt_format::v5::packets::connect::MConnect;
+use mqtt_format::v5::qos::QualityOfService;
+
+use crate::codec::UserPropertiesBuf;
+use crate::queue::OfflineQueue;
+use crate::reconnect::RedirectFactory;
+use crate::reconnect::Transport;
+use crate::reconnect::boxed_redirect_factory;
+use crate::session::SessionStore;
+use crate::session::SharedSessionStore;
+
+/// The contents of the CONNECT packet a client sends
+///
+/// The options are kept for the whole lifetime of a client, so that every reconnection
+/// identifies and authenticates itself the same way.
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    client_identifier: String,
+    username: Option<String>,
+    password: Option<Vec<u8>>,
+    clean_start: bool,
+    keep_alive: u16,
+    will: Option<Will>,
+
+    session_expiry_interval: Option<u32>,
+    receive_maximum: Option<NonZeroU16>,
+    maximum_packet_size: Option<u32>,
+    topic_alias_maximum: Option<u16>,
+    request_response_information: Option<bool>,
+    request_problem_information: Option<bool>,
+    user_properties: UserPropertiesBuf,
+    authentication_method: Option<String>,
+    authentication_data: Option<Vec<u8>>,
+    authenticator: Option<AuthenticatorFactory>,
+    session_store: Option<SharedSessionStore>,
+    offline_queue: Option<OfflineQueue>,
+    manual_acknowledgement: bool,
+    redirect: Option<Redirect>,
+}
+
+/// Creates the authenticator for each connection, so that no state is shared between them
+#[derive(Clone)]
+struct AuthenticatorFactory(Arc<dyn Fn() -> Box<dyn Authenticator + Send> + Send + Sync>);
+
+impl std::fmt::Debug for AuthenticatorFactory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthenticatorFactory")
+            .finish_non_exhaustive()
+    }
+}
+
+/// Follows the server to the server it sent the client to
+#[derive(Clone)]
+struct Redirect(RedirectFactory);
+
+impl std::fmt::Debug for Redirect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Redirect").finish_non_exhaustive()
+    }
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self::new("cloudmqtt-0")
+    }
+}
+
+impl ConnectOptions {
+    pub fn new(client_identifier: impl Into<String>) -> Self {
+        Self {
+            client_identifier: client_identifier.into(),
+            username: None,
+            password: None,
+            clean_start: true,
+            keep_alive: 0,
+            will: None,
+            session_expiry_interval: None,
+            receive_maximum: None,
+            maximum_packet_size: None,
+            topic_alias_maximum: None,
+            request_response_information: None,
+            request_problem_information: None,
+            user_properties: UserPropertiesBuf::default(),
+            authentication_method: None,
+            authentication_data: None,
+            authenticator: None,
+            session_store: None,
+            offline_queue: None,
+            manual_acknowledgement: false,
+            redirect: None,
+        }
+    }
+
+    pub fn client_identifier(&self) -> &str {
+        &self.client_identifier
+    }
+
+    pub fn with_client_identifier(mut self, client_identifier: impl Into<String>) -> Self {
+        self.client_identifier = client_identifier.into();
+        self
+    }
+
+    pub fn with_username(mut self, username: impl Into<String>) -> Self {
+        self.username = Some(username.into());
+        self
+    }
+
+    pub fn with_password(mut self, password: impl Into<Vec<u8>>) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    pub fn with_credentials(
+        self,
+        username: impl Into<String>,
+        password: impl Into<Vec<u8>>,
+    ) -> Self {
+        self.with_username(username).with_password(password)
+    }
+
+    /// Whether the first connection should discard any session the server still has
+    ///
+    /// Reconnections always try to resume the session.
+    pub fn with_clean_start(mut self, clean_start: bool) -> Self {
+        self.clean_start = clean_start;
+        self
+    }
+
+    /// The keep alive interval in seconds, `0` disables keep alive
+    pub fn with_keep_alive(mut self, keep_alive: u16) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn with_will(mut self, will: Will) -> Self {
+        self.will = Some(will);
+        self
+    }
+
+    /// How long, in seconds, the server should keep the session after the connection closed
+    pub fn with_session_expiry_interval(mut self, session_expiry_interval: u32) -> Self {
+        self.session_expiry_interval = Some(session_expiry_interval);
+        self
+    }
+
+    /// How many QoS 1 and 2 publishes the server may have in flight towards this client
+    pub fn with_receive_maximum(mut self, receive_maximum: NonZeroU16) -> Self {
+        self.receive_maximum = Some(receive_maximum);
+        self
+    }
+
+    pub fn with_maximum_packet_size(mut self, maximum_packet_size: u32) -> Self {
+        self.maximum_packet_size = Some(maximum_packet_size);
+        self
+    }
+
+    /// The highest topic alias the server may send publishes with, `0` to not use any
+    ///
+    /// Topic aliases for publishes to the server are used as far as the server allows it, no
+    /// matter this setting.
+    pub fn with_topic_alias_maximum(mut self, topic_alias_maximum: u16) -> Self {
+        self.topic_alias_maximum = Some(topic_alias_maximum);
+        self
+    }
+
+    pub fn with_request_response_information(mut self, request: bool) -> Self {
+        self.request_response_information = Some(request);
+        self
+    }
+
+    pub fn with_request_problem_information(mut self, request: bool) -> Self {
+        self.request_problem_information = Some(request);
+        self
+    }
+
+    /// Add a user property, may be called multiple times
+    ///
+    /// # Panics
+    ///
+    /// If the key or value is longer than 65535 bytes
+    pub fn with_user_property(mut self, key: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        self.user_properties.push(key.as_ref(), value.as_ref());
+        self
+    }
+
+    pub fn with_authentication_method(mut self, authentication_method: impl Into<String>) -> Self {
+        self.authentication_method = Some(authentication_method.into());
+        self
+    }
+
+    pub fn with_authentication_data(mut self, authentication_data: impl Into<Vec<u8>>) -> Self {
+        self.authentication_data = Some(authentication_data.into());
+        self
+    }
+
+    /// Use enhanced authentication, with a new authenticator created for every connection
+    ///
+    /// The authenticator provides the authentication method and data, replacing those set
+    /// through [`with_authentication_method`](Self::with_authentication_method) and
+    /// [`with_authentication_data`](Self::with_authentication_data).
+    pub fn with_authenticator<A, F>(mut self, authenticator: F) -> Self
+    where
+        A: Authenticator + Send + 'static,
+        F: Fn() -> A + Send + Sync + 'static,
+    {
+        self.authenticator = Some(AuthenticatorFactory(Arc::new(move || {
+            Box::new(authenticator())
+        })));
+        self
+    }
+
+    pub(crate) fn authenticator(&self) -> Option<Box<dyn Authenticator + Send>> {
+        self.authenticator.as_ref().map(|factory| (factory.0)())
+    }
+
+    /// Persist the session in the given store, and resume the session it holds
+    ///
+    /// All clients created from these options share the store. Without a store the session is
+    /// only kept for the lifetime of the client.
+    pub fn with_session_store(mut self, store: impl SessionStore + 'static) -> Self {
+        self.session_store = Some(SharedSessionStore::new(store));
+        self
+    }
+
+    pub(crate) fn session_store(&self) -> SharedSessionStore {
+        self.session_store.clone().unwrap_or_else(|| {
+            SharedSessionStore::new(crate::session::MemorySessionStore::default())
+        })
+    }
+
+    /// Queue publishes while not connected, instead of failing them
+    pub fn with_offline_queue(mut self, offline_queue: OfflineQueue) -> Self {
+        self.offline_queue = Some(offline_queue);
+        self
+    }
+
+    pub(crate) fn offline_queue(&self) -> Option<&OfflineQueue> {
+        self.offline_queue.as_ref()
+    }
+
+    /// Only acknowledge incoming QoS 1 and 2 publishes once the application did so
+    ///
+    /// See [`acknowledge`](crate::acknowledge) for how publishes are acknowledged.
+    pub fn with_manual_acknowledgement(mut self, manual: bool) -> Self {
+        self.manual_acknowledgement = manual;
+        self
+    }
+
+    pub(crate) fn manual_acknowledgement(&self) -> bool {
+        self.manual_acknowledgement
+    }
+
+    /// Follow the server when it sends the client to another server
+    ///
+    /// A server can ask the client to use another server, temporarily or because it moved for
+    /// good, when refusing or closing a connection. The `factory` is then called with the server
+    /// reference it gave, to open a transport to that server. The client connects through it
+    /// with the same session, and keeps connecting to a server that moved whenever it reconnects.
+    pub fn with_redirect<F, Fut, C>(mut self, factory: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = std::io::Result<C>> + Send + 'static,
+        C: Transport,
+    {
+        self.redirect = Some(Redirect(boxed_redirect_factory(factory)));
+        self
+    }
+
+    pub(crate) fn redirect(&self) -> Option<&RedirectFactory> {
+        self.redirect.as_ref().map(|redirect| &redirect.0)
+    }
+
+    /// Keep the client identifier the server assigned, so that reconnections resume the session
+    pub(crate) fn assign_client_identifier(&mut self, client_identifier: &str) {
+        self.client_identifier = client_identifier.to_string();
+    }
+
+    pub(crate) fn session_expiry_interval(&self) -> u32 {
+        self.session_expiry_interval.unwrap_or(0)
+    }
+
+    pub(crate) fn topic_alias_maximum(&self) -> u16 {
+        self.topic_alias_maximum.unwrap_or(0)
+    }
+
+    pub(crate) fn clean_start(&self) -> bool {
+        self.clean_start
+    }
+
+    pub(crate) fn as_connect(&self, clean_start: bool) -> MConnect<'_> {
+        use mqtt_format::v5::variable_header::AuthenticationData;
+        use mqtt_format::v5::variable_header::AuthenticationMethod;
+        use mqtt_format::v5::variable_header::MaximumPacketSize;
+        use mqtt_format::v5::variable_header::ReceiveMaximum;
+        use mqtt_format::v5::variable_header::RequestProblemInformation;
+        use mqtt_format::v5::variable_header::RequestResponseInformation;
+        use mqtt_format::v5::variable_header::SessionExpiryInterval;
+        use mqtt_format::v5::variable_header::TopicAliasMaximum;
+
+        MConnect {
+            client_identifier: &self.client_identifier,
+            username: self.username.as_deref(),
+            password: self.password.as_deref(),
+            clean_start,
+            will: self.will.as_ref().map(Will::as_will),
+            properties: ConnectProperties {
+                session_expiry_interval: self.session_expiry_interval.map(SessionExpiryInterval),
+                receive_maximum: self.receive_maximum.map(ReceiveMaximum),
+                maximum_packet_size: self.maximum_packet_size.map(MaximumPacketSize),
+                topic_alias_maximum: self.topic_alias_maximum.map(TopicAliasMaximum),
+                request_response_information: self
+                    .request_response_information
+                    .map(|request| RequestResponseInformation(request.into())),
+                request_problem_information: self
+                    .request_problem_information
+                    .map(|request| RequestProblemInformation(request.into())),
+                user_properties: self.user_properties.as_user_properties(),
+                authentication_method: self
+                    .authentication_method
+                    .as_deref()
+                    .map(AuthenticationMethod),
+                authentication_data: self.authentication_data.as_deref().map(AuthenticationData),
+            },
+            keep_alive: self.keep_alive,
+        }
+    }
+}
+
+/// The message the server publishes on behalf of the client if it disappears
+#[derive(Debug, Clone)]
+pub struct Will {
+    topic: String,
+    payload: Vec<u8>,
+    quality_of_service: QualityOfService,
+    retain: bool,
+
+    will_delay_interval: Option<u32>,
+    payload_format_indicator: Option<bool>,
+    message_expiry_interval: Option<u32>,
+    content_type: Option<String>,
+    response_topic: Option<String>,
+    correlation_data: Option<Vec<u8>>,
+    user_properties: UserPropertiesBuf,
+}
+
+impl Will {
+    pub fn new(topic: impl Into<String>, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            topic: topic.into(),
+            payload: payload.into(),
+            quality_of_service: QualityOfService::AtMostOnce,
+            retain: false,
+            will_delay_interval: None,
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            content_type: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: UserPropertiesBuf::default(),
+        }
+    }
+
+    pub fn with_quality_of_service(mut self, quality_of_service: QualityOfService) -> Self {
+        self.quality_of_service = quality_of_service;
+        self
+    }
+
+    pub fn with_retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// How long, in seconds, the server waits before publishing the will
+    pub fn with_will_delay_interval(mut self, will_delay_interval: u32) -> Self {
+        self.will_delay_interval = Some(will_delay_interval);
+        self
+    }
+
+    /// Whether the payload is UTF-8 encoded character data
+    pub fn with_payload_format_indicator(mut self, is_utf8: bool) -> Self {
+        self.payload_format_indicator = Some(is_utf8);
+        self
+    }
+
+    pub fn with_message_expiry_interval(mut self, message_expiry_interval: u32) -> Self {
+        self.message_expiry_interval = Some(message_expiry_interval);
+        self
+    }
+
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    pub fn with_response_topic(mut self, response_topic: impl Into<String>) -> Self {
+        self.response_topic = Some(response_topic.into());
+        self
+    }
+
+    pub fn with_correlation_data(mut self, correlation_data: impl Into<Vec<u8>>) -> Self {
+        self.correlation_data = Some(correlation_data.into());
+        self
+    }
+
+    /// Add a user property, may be called multiple times
+    ///
+    /// # Panics
+    ///
+    /// If the key or value is longer than 65535 bytes
+    pub fn with_user_property(mut self, key: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        self.user_properties.push(key.as_ref(), value.as_ref());
+        self
+    }
+
+    fn as_will(&self) -> mqtt_format::v5::packets::connect::Will<'_> {
+        use mqtt_format::v5::variable_header::ContentType;
+        use mqtt_format::v5::variable_header::CorrelationData;
+        use mqtt_format::v5::variable_header::MessageExpiryInterval;
+        use mqtt_format::v5::variable_header::PayloadFormatIndicator;
+        use mqtt_format::v5::variable_header::ResponseTopic;
+        use mqtt_format::v5::variable_header::WillDelayInterval;
+
+        mqtt_format::v5::packets::connect::Will {
+            properties: ConnectWillProperties {
+                will_delay_interval: self.will_delay_interval.map(WillDelayInterval),
+                payload_format_indicator: self
+                    .payload_format_indicator
+                    .map(|is_utf8| PayloadFormatIndicator(is_utf8.into())),
+                message_expiry_interval: self.message_expiry_interval.map(MessageExpiryInterval),
+                content_type: self.content_type.as_deref().map(ContentType),
+                response_topic: self.response_topic.as_deref().map(ResponseTopic),
+                correlation_data: self.correlation_data.as_deref().map(CorrelationData),
+                user_properties: self.user_properties.as_user_properties(),
+            },
+            topic: &self.topic,
+            payload: &self.payload,
+            will_qos: self.quality_of_service,
+            will_retain: self.retain,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+
+    use super::ConnectOptions;
+    use super::Will;
+    use crate::codec::MqttPacket;
+
+    #[test]
+    fn check_connect_roundtrip() {
+        let options = ConnectOptions::new("sensor-17")
+            .with_credentials("user", b"secret".to_vec())
+            .with_keep_alive(30)
+            .with_session_expiry_interval(3600)
+            .with_receive_maximum(10.try_into().unwrap())
+            .with_request_problem_information(true)
+            .with_user_property("site", "north")
+            .with_user_property("rack", "4")
+            .with_will(
+                Will::new("sensors/17/status", b"offline".to_vec())
+                    .with_quality_of_service(mqtt_format::v5::qos::QualityOfService::AtLeastOnce)
+                    .with_retain(true)
+                    .with_will_delay_interval(5)
+                    .with_user_property("reason", "lost"),
+            );
+
+        let packet = MqttPacket::new(FormatMqttPacket::Connect(options.as_connect(false)));
+        let FormatMqttPacket::Connect(connect) = packet.get_packet() else {
+            panic!("Expected a connect packet, got: {packet:?}");
+        };
+
+        assert_eq!(connect.client_identifier, "sensor-17");
+        assert_eq!(connect.username, Some("user"));
+        assert_eq!(connect.password, Some(&b"secret"[..]));
+        assert!(!connect.clean_start);
+        assert_eq!(connect.keep_alive, 30);
+        assert_eq!(
+            connect.properties.session_expiry_interval().map(|s| s.0),
+            Some(3600)
+        );
+        assert_eq!(
+            connect.properties.receive_maximum().map(|r| r.0.get()),
+            Some(10)
+        );
+        assert_eq!(
+            connect
+                .properties
+                .request_problem_information()
+                .map(|r| r.0),
+            Some(1)
+        );
+
+        let user_properties = connect.properties.user_properties().unwrap();
+        let user_properties = user_properties
+            .iter()
+            .map(|up| (up.key, up.value))
+            .collect::<Vec<_>>();
+        assert_eq!(user_properties, [("site", "north"), ("rack", "4")]);
+
+        let will = connect.will.as_ref().unwrap();
+        assert_eq!(will.topic, "sensors/17/status");
+        assert_eq!(will.payload, b"offline");
+        assert!(will.will_retain);
+        assert_eq!(will.properties.will_delay_interval().map(|w| w.0), Some(5));
+        assert!(will.properties.user_properties().is_some());
+    }
+}
@@ -0,0 +1,301 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Disconnecting a [`CloudmqttClient`](crate::CloudmqttClient) from the server
+
+use std::time::Duration;
+
+use mqtt_format::v5::packets::disconnect::DisconnectProperties;
+use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
+use mqtt_format::v5::packets::disconnect::MDisconnect;
+
+use crate::CloudmqttClient;
+use crate::codec::MqttPacket;
+use crate::codec::UserPropertiesBuf;
+use crate::error::Error;
+
+/// How long to wait for publishes in flight, unless the builder says otherwise
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A disconnect from the server, created with [`CloudmqttClient::disconnect_builder`]
+pub struct DisconnectBuilder<'a> {
+    client: &'a CloudmqttClient,
+    reason_code: DisconnectReasonCode,
+    timeout: Duration,
+
+    session_expiry_interval: Option<u32>,
+    reason_string: Option<String>,
+    user_properties: UserPropertiesBuf,
+}
+
+impl<'a> DisconnectBuilder<'a> {
+    pub(crate) fn new(client: &'a CloudmqttClient) -> Self {
+        Self {
+            client,
+            reason_code: DisconnectReasonCode::NormalDisconnection,
+            timeout: DEFAULT_TIMEOUT,
+            session_expiry_interval: None,
+            reason_string: None,
+            user_properties: UserPropertiesBuf::default(),
+        }
+    }
+
+    /// Why the client disconnects, defaults to [`DisconnectReasonCode::NormalDisconnection`]
+    ///
+    /// The server only publishes the will message for
+    /// [`DisconnectReasonCode::DisconnectWithWillMessage`].
+    pub fn with_reason_code(mut self, reason_code: DisconnectReasonCode) -> Self {
+        self.reason_code = reason_code;
+        self
+    }
+
+    /// How long to wait for the server to acknowledge the publishes in flight, defaults to 10
+    /// seconds
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long, in seconds, the server should keep the session, instead of what was set when
+    /// connecting
+    ///
+    /// If the session expiry interval was 0 when connecting, it cannot be changed anymore.
+    pub fn with_session_expiry_interval(mut self, session_expiry_interval: u32) -> Self {
+        self.session_expiry_interval = Some(session_expiry_interval);
+        self
+    }
+
+    pub fn with_reason_string(mut self, reason_string: impl Into<String>) -> Self {
+        self.reason_string = Some(reason_string.into());
+        self
+    }
+
+    /// Add a user property, may be called multiple times
+    ///
+    /// # Panics
+    ///
+    /// If the key or value is longer than 65535 bytes
+    pub fn with_user_property(mut self, key: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        self.user_properties.push(key.as_ref(), value.as_ref());
+        self
+    }
+
+    /// Disconnect and close the connection
+    ///
+    /// No more packets are sent once this is called. Until the server acknowledged every QoS 1
+    /// and 2 publish in flight, or the timeout passed, the connection stays open. Publishes that
+    /// are still in flight then are kept in the session. The client does not reconnect
+    /// afterwards.
+    ///
+    /// If the client is waiting to reconnect, or for the server to accept the connection, it
+    /// stops connecting without sending a DISCONNECT.
+    pub async fn send(self) -> Result<(), Error> {
+        self.client
+            .core_client
+            .disconnect(self.as_packet(), self.timeout)
+            .await
+    }
+
+    fn as_packet(&self) -> MqttPacket {
+        use mqtt_format::v5::variable_header::ReasonString;
+        use mqtt_format::v5::variable_header::SessionExpiryInterval;
+
+        MqttPacket::new(mqtt_format::v5::packets::MqttPacket::Disconnect(
+            MDisconnect {
+                reason_code: self.reason_code,
+                properties: DisconnectProperties {
+                    session_expiry_interval: self
+                        .session_expiry_interval
+                        .map(SessionExpiryInterval),
+                    reason_string: self.reason_string.as_deref().map(ReasonString),
+                    user_properties: self.user_properties.as_user_properties(),
+                    ..DisconnectProperties::new()
+                },
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
+    use mqtt_format::v5::qos::QualityOfService;
+    use tokio_util::codec::Framed;
+
+    use crate::CloudmqttClient;
+    use crate::codec::MqttPacket;
+    use crate::codec::MqttPacketCodec;
+    use crate::connect::ConnectOptions;
+    use crate::error::Error;
+
+    async fn next_packet(
+        server: &mut Framed<tokio::io::DuplexStream, MqttPacketCodec>,
+    ) -> MqttPacket {
+        server.next().await.unwrap().unwrap()
+    }
+
+    async fn connected_client(
+        options: ConnectOptions,
+    ) -> (
+        CloudmqttClient,
+        Framed<tokio::io::DuplexStream, MqttPacketCodec>,
+    ) {
+        let (client, server) = tokio::io::duplex(1024);
+        let client = CloudmqttClient::new_from_connection_with_options(client, options);
+        let mut events = client.connection_events();
+        let mut server = Framed::new(server, MqttPacketCodec);
+
+        next_packet(&mut server).await;
+        server
+            .send(FormatMqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+
+        // Disconnecting before the CONNACK arrived would only give up on the connection
+        while !matches!(
+            events.recv().await.unwrap(),
+            crate::event::ConnectionEvent::Connected(..)
+        ) {}
+
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn check_disconnect_waits_for_publishes_in_flight() {
+        let (client, mut server) =
+            connected_client(ConnectOptions::new("disconnecting").with_session_expiry_interval(60))
+                .await;
+        let client = std::sync::Arc::new(client);
+
+        let publish = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client
+                    .publish_builder("status/sensor", "offline")
+                    .with_quality_of_service(QualityOfService::AtLeastOnce)
+                    .send()
+                    .await
+            }
+        });
+        let packet = next_packet(&mut server).await;
+        let FormatMqttPacket::Publish(publish_packet) = packet.get_packet() else {
+            panic!("Expected a publish, got: {packet:?}");
+        };
+
+        let disconnect = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client
+                    .disconnect_builder()
+                    .with_session_expiry_interval(0)
+                    .with_reason_string("shutting down")
+                    .send()
+                    .await
+            }
+        });
+
+        // Nothing is sent until the publish was acknowledged
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), server.next())
+                .await
+                .is_err()
+        );
+
+        server
+            .send(FormatMqttPacket::Puback(
+                mqtt_format::v5::packets::puback::MPuback {
+                    packet_identifier: publish_packet.packet_identifier.unwrap(),
+                    reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+        publish.await.unwrap().unwrap();
+
+        let packet = next_packet(&mut server).await;
+        let FormatMqttPacket::Disconnect(disconnect_packet) = packet.get_packet() else {
+            panic!("Expected a disconnect, got: {packet:?}");
+        };
+        assert_eq!(
+            disconnect_packet.reason_code,
+            DisconnectReasonCode::NormalDisconnection
+        );
+        assert_eq!(
+            disconnect_packet
+                .properties
+                .session_expiry_interval()
+                .map(|interval| interval.0),
+            Some(0)
+        );
+        assert_eq!(
+            disconnect_packet
+                .properties
+                .reason_string()
+                .map(|reason| reason.0),
+            Some("shutting down")
+        );
+
+        disconnect.await.unwrap().unwrap();
+        client.wait_for_shutdown().await;
+        assert!(server.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn check_disconnect_times_out() {
+        let (client, mut server) = connected_client(ConnectOptions::default()).await;
+        let client = std::sync::Arc::new(client);
+
+        let _publish = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client
+                    .publish_builder("status/sensor", "offline")
+                    .with_quality_of_service(QualityOfService::AtLeastOnce)
+                    .send()
+                    .await
+            }
+        });
+        next_packet(&mut server).await;
+
+        let disconnect = client
+            .disconnect_builder()
+            .with_reason_code(DisconnectReasonCode::DisconnectWithWillMessage)
+            .with_timeout(Duration::from_millis(50))
+            .send();
+        let (disconnected, packet) = tokio::join!(disconnect, next_packet(&mut server));
+        disconnected.unwrap();
+
+        assert!(matches!(
+            packet.get_packet(),
+            FormatMqttPacket::Disconnect(disconnect)
+                if disconnect.reason_code == DisconnectReasonCode::DisconnectWithWillMessage
+        ));
+    }
+
+    #[tokio::test]
+    async fn check_session_expiry_interval_fixed() {
+        let (client, _server) = connected_client(ConnectOptions::default()).await;
+
+        let result = client
+            .disconnect_builder()
+            .with_session_expiry_interval(60)
+            .send()
+            .await;
+        assert!(matches!(result, Err(Error::SessionExpiryIntervalFixed)));
+    }
+}
@@ -9,9 +9,73 @@ pub enum Error {
     #[error("Client not connected")]
     NotConnected,
 
+    #[error("Could not connect to the server")]
+    Connect(#[source] std::io::Error),
+
+    #[error("The URL of the server is invalid")]
+    InvalidUrl(#[from] crate::url::UrlError),
+
     #[error("Client is already connected")]
     AlreadyConnected,
 
     #[error("Internal channel closed")]
     TokioChannel,
+
+    #[error("The publish exceeds what the server allows")]
+    PublishRefused(#[source] cloudmqtt_core::client::PublishError),
+
+    #[error("The request could not be sent")]
+    RequestRefused(#[source] cloudmqtt_core::client::RequestError),
+
+    #[error("Only PUBLISH packets can be published")]
+    NotAPublish,
+
+    #[error("Only SUBSCRIBE packets can be sent to subscribe")]
+    NotASubscribe,
+
+    #[error("Only UNSUBSCRIBE packets can be sent to unsubscribe")]
+    NotAnUnsubscribe,
+
+    #[error("The topic filter {topic_filter:?} is invalid")]
+    InvalidTopicFilter {
+        topic_filter: String,
+        #[source]
+        error: crate::topic::TopicError,
+    },
+
+    #[error("The publish was dropped, the offline queue was full")]
+    OfflineQueueFull,
+
+    #[error("The message expired before it could be published")]
+    MessageExpired,
+
+    #[error("The server rejected the publish: {0:?}")]
+    PubackRejected(mqtt_format::v5::packets::puback::PubackReasonCode),
+
+    #[error("The server rejected the publish: {0:?}")]
+    PubrecRejected(mqtt_format::v5::packets::pubrec::PubrecReasonCode),
+
+    #[error("The server could not complete the publish: {0:?}")]
+    PubcompRejected(mqtt_format::v5::packets::pubcomp::PubcompReasonCode),
+
+    #[error("Subscription identifiers must be at most 268435455, got {0}")]
+    InvalidSubscriptionIdentifier(u32),
+
+    #[error("The server rejected every topic filter: {0:?}")]
+    SubscribeRejected(Vec<mqtt_format::v5::packets::suback::SubackReasonCode>),
+
+    #[error("The server rejected the unsubscribe: {0:?}")]
+    UnsubscribeRejected(Vec<mqtt_format::v5::packets::unsuback::UnsubackReasonCode>),
+
+    #[error("Enhanced authentication failed")]
+    AuthenticationFailed(#[source] cloudmqtt_core::client::AuthenticationError),
+
+    #[error("No authenticator was configured")]
+    NoAuthenticator,
+
+    #[error("A re-authentication is already going on")]
+    ReauthenticationInProgress,
+
+    #[error("The session expiry interval was 0 when connecting, it cannot be changed anymore")]
+    SessionExpiryIntervalFixed,
 }
@@ -0,0 +1,131 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Events about the connection of a [`CloudmqttClient`](crate::CloudmqttClient)
+
+use std::sync::Arc;
+
+use cloudmqtt_core::client::AuthenticationError;
+use cloudmqtt_core::client::ConnectError;
+use cloudmqtt_core::client::ProtocolViolation;
+use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+use mqtt_format::v5::packets::connack::ConnackProperties;
+use mqtt_format::v5::packets::connack::ConnackReasonCode;
+use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
+
+use crate::codec::MqttPacket;
+
+/// What happened to the connection, see [`CloudmqttClient::connection_events`]
+///
+/// [`CloudmqttClient::connection_events`]: crate::CloudmqttClient::connection_events
+#[derive(Debug, Clone)]
+pub enum ConnectionEvent {
+    /// A transport is open and the client is sending its CONNECT
+    Connecting,
+
+    /// The server accepted the connection
+    Connected(Connected),
+
+    /// The connection is gone
+    Disconnected(DisconnectReason),
+
+    /// The server sent the client to another server, and the client is opening a transport to it
+    Redirecting { server_reference: String },
+
+    /// The client is opening a new transport through its reconnect policy
+    Reconnecting {
+        /// Counted from 1 since the last established connection
+        attempt: u32,
+    },
+}
+
+/// The CONNACK with which the server accepted a connection
+#[derive(Debug, Clone)]
+pub struct Connected {
+    // Boxed, as a parsed packet is much larger than the other events
+    connack: Box<MqttPacket>,
+}
+
+impl Connected {
+    pub(crate) fn new(connack: MqttPacket) -> Self {
+        debug_assert!(matches!(
+            connack.get_packet(),
+            FormatMqttPacket::Connack(..)
+        ));
+
+        Self {
+            connack: Box::new(connack),
+        }
+    }
+
+    /// Whether the server resumed a previous session
+    pub fn session_present(&self) -> bool {
+        let FormatMqttPacket::Connack(connack) = self.connack.get_packet() else {
+            unreachable!("Only created from a CONNACK");
+        };
+
+        connack.session_present
+    }
+
+    /// The client identifier the server assigned, if the client connected without one
+    pub fn assigned_client_identifier(&self) -> Option<&str> {
+        self.properties()
+            .assigned_client_identifier()
+            .map(|client_identifier| client_identifier.0)
+    }
+
+    /// What the client can build response topics from, if it requested response information
+    pub fn response_information(&self) -> Option<&str> {
+        self.properties()
+            .response_information()
+            .map(|response_information| response_information.0)
+    }
+
+    pub fn properties(&self) -> &ConnackProperties<'_> {
+        let FormatMqttPacket::Connack(connack) = self.connack.get_packet() else {
+            unreachable!("Only created from a CONNACK");
+        };
+
+        &connack.properties
+    }
+}
+
+/// Why a connection was lost
+#[derive(Debug, Clone)]
+pub enum DisconnectReason {
+    /// The server sent a DISCONNECT
+    Server {
+        reason_code: DisconnectReasonCode,
+        reason_string: Option<String>,
+
+        /// The server to use instead, if the server sent the client elsewhere
+        server_reference: Option<String>,
+    },
+
+    /// The client sent a DISCONNECT when asked to
+    Client(DisconnectReasonCode),
+
+    /// The client sent a DISCONNECT, as the server violated the protocol
+    ProtocolViolation(ProtocolViolation),
+
+    /// The client could not start connecting, and does not try again
+    Connect(ConnectError),
+
+    /// The server refused the connection in its CONNACK
+    Refused(ConnackReasonCode),
+
+    /// Enhanced authentication with the server failed
+    AuthenticationFailed(AuthenticationError),
+
+    /// The server did not answer a ping within the keep alive
+    KeepAliveTimeout,
+
+    /// Reading from the connection failed
+    Io(Arc<std::io::Error>),
+
+    /// The connection was closed without a DISCONNECT
+    Closed,
+}
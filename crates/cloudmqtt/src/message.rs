@@ -0,0 +1,280 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Messages received through a [`Subscription`](crate::Subscription)
+
+use std::num::NonZeroU16;
+
+use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+use mqtt_format::v5::packets::puback::PubackReasonCode;
+use mqtt_format::v5::packets::publish::MPublish;
+use mqtt_format::v5::qos::QualityOfService;
+pub use tokio_util::bytes::Bytes;
+
+use crate::acknowledge::Acknowledger;
+use crate::codec::MqttPacket;
+use crate::topic::TopicError;
+use crate::topic::TopicNameBuf;
+
+/// A publish received from the server
+///
+/// The payload and properties are not copied out of the received packet, they are borrowed from
+/// it or, like the payload, share its buffer.
+///
+/// Unless the client acknowledges publishes manually, there is nothing left to do with a message
+/// once it was received.
+#[derive(Debug)]
+pub struct Message {
+    packet: MqttPacket,
+    topic: TopicNameBuf,
+    payload: Bytes,
+    acknowledger: Option<Acknowledger>,
+
+    /// Acknowledges the publish once it was handed to every subscription, unless the application
+    /// acknowledges it
+    routed: Option<Acknowledger>,
+}
+
+impl Message {
+    /// Take a received publish apart
+    ///
+    /// # Panics
+    ///
+    /// If the packet is not a publish
+    pub(crate) fn new(packet: MqttPacket) -> Result<Self, TopicError> {
+        let FormatMqttPacket::Publish(publish) = packet.get_packet() else {
+            panic!("Only publishes can be received as messages");
+        };
+
+        let topic = TopicNameBuf::new(publish.topic_name)?;
+        let payload = packet.slice_ref(publish.payload);
+
+        Ok(Self {
+            packet,
+            topic,
+            payload,
+            acknowledger: None,
+            routed: None,
+        })
+    }
+
+    /// Let the application acknowledge the message
+    pub(crate) fn with_acknowledger(mut self, acknowledger: Acknowledger) -> Self {
+        self.acknowledger = Some(acknowledger);
+        self
+    }
+
+    /// Acknowledge the message once it was routed
+    ///
+    /// Until then, the server can only send as many other publishes as the receive maximum
+    /// allows.
+    pub(crate) fn with_routed_acknowledger(mut self, acknowledger: Acknowledger) -> Self {
+        self.routed = Some(acknowledger);
+        self
+    }
+
+    /// The same message for another subscription, with its own handle to acknowledge it
+    pub(crate) fn share(&self) -> Self {
+        Self {
+            packet: self.packet.clone(),
+            topic: self.topic.clone(),
+            payload: self.payload.clone(),
+            acknowledger: self.acknowledger.as_ref().map(Acknowledger::share),
+            routed: None,
+        }
+    }
+
+    /// Another handle to acknowledge the message for the router with, if it has one
+    pub(crate) fn routed_acknowledger(&self) -> Option<Acknowledger> {
+        self.routed.as_ref().map(Acknowledger::share)
+    }
+
+    fn publish(&self) -> &MPublish<'_> {
+        match self.packet.get_packet() {
+            FormatMqttPacket::Publish(publish) => publish,
+            _ => unreachable!("Messages are only created from publishes"),
+        }
+    }
+
+    pub fn topic(&self) -> &TopicNameBuf {
+        &self.topic
+    }
+
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    pub fn quality_of_service(&self) -> QualityOfService {
+        self.publish().quality_of_service
+    }
+
+    /// Whether the server sent a retained message, because it matches a new subscription
+    pub fn retain(&self) -> bool {
+        self.publish().retain
+    }
+
+    /// Whether the server may have sent this message before
+    pub fn duplicate(&self) -> bool {
+        self.publish().duplicate
+    }
+
+    /// Whether the payload is UTF-8 encoded character data, if the publisher said so
+    pub fn payload_format_indicator(&self) -> Option<bool> {
+        self.publish()
+            .properties
+            .payload_format_indicator()
+            .map(|indicator| indicator.0 == 1)
+    }
+
+    /// How many seconds are left until the message expires
+    pub fn message_expiry_interval(&self) -> Option<u32> {
+        self.publish()
+            .properties
+            .message_expiry_interval()
+            .map(|interval| interval.0)
+    }
+
+    /// The topic alias the server sent the message with
+    pub fn topic_alias(&self) -> Option<NonZeroU16> {
+        self.publish().properties.topic_alias().map(|alias| alias.0)
+    }
+
+    /// Where the publisher expects a response
+    pub fn response_topic(&self) -> Option<&str> {
+        self.publish()
+            .properties
+            .response_topic()
+            .map(|response_topic| response_topic.0)
+    }
+
+    /// Identifies the request a response is for
+    pub fn correlation_data(&self) -> Option<&[u8]> {
+        self.publish()
+            .properties
+            .correlation_data()
+            .map(|correlation_data| correlation_data.0)
+    }
+
+    /// The user properties as key-value pairs, in the order they were sent
+    pub fn user_properties(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.publish()
+            .properties
+            .user_properties()
+            .into_iter()
+            .flat_map(|user_properties| user_properties.iter())
+            .map(|user_property| (user_property.key, user_property.value))
+    }
+
+    /// The identifier of the subscription the message matched, if it was made with one
+    pub fn subscription_identifier(&self) -> Option<u32> {
+        self.publish()
+            .properties
+            .subscription_identifier()
+            .map(|subscription_identifier| subscription_identifier.0)
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.publish()
+            .properties
+            .content_type()
+            .map(|content_type| content_type.0)
+    }
+
+    /// The handle to acknowledge the message with, if it has to be acknowledged manually
+    pub fn acknowledger(&self) -> Option<&Acknowledger> {
+        self.acknowledger.as_ref()
+    }
+
+    /// Take the handle to acknowledge the message with, to acknowledge it after letting go of it
+    pub fn take_acknowledger(&mut self) -> Option<Acknowledger> {
+        self.acknowledger.take()
+    }
+
+    /// Acknowledge the message as received, if it has to be acknowledged manually
+    pub fn ack(self) {
+        self.ack_with_reason(PubackReasonCode::Success)
+    }
+
+    /// Acknowledge the message with the given reason, if it has to be acknowledged manually
+    pub fn ack_with_reason(self, reason: PubackReasonCode) {
+        for acknowledger in self.acknowledger.into_iter().chain(self.routed) {
+            acknowledger.ack_with_reason(reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use mqtt_format::v5::packets::publish::MPublish;
+    use mqtt_format::v5::packets::publish::PublishProperties;
+    use mqtt_format::v5::qos::QualityOfService;
+
+    use super::Message;
+    use crate::codec::MqttPacket;
+    use crate::codec::UserPropertiesBuf;
+
+    #[test]
+    fn check_message_accessors() {
+        let mut user_properties = UserPropertiesBuf::default();
+        user_properties.push("unit", "celsius");
+        user_properties.push("sensor", "kitchen");
+
+        let packet = MqttPacket::new(FormatMqttPacket::Publish(MPublish {
+            duplicate: true,
+            quality_of_service: QualityOfService::AtLeastOnce,
+            retain: true,
+            topic_name: "home/kitchen/temperature",
+            packet_identifier: Some(mqtt_format::v5::variable_header::PacketIdentifier(
+                3.try_into().unwrap(),
+            )),
+            properties: PublishProperties {
+                payload_format_indicator: Some(
+                    mqtt_format::v5::variable_header::PayloadFormatIndicator(1),
+                ),
+                message_expiry_interval: Some(
+                    mqtt_format::v5::variable_header::MessageExpiryInterval(60),
+                ),
+                response_topic: Some(mqtt_format::v5::variable_header::ResponseTopic(
+                    "home/responses",
+                )),
+                correlation_data: Some(mqtt_format::v5::variable_header::CorrelationData(
+                    b"request-1",
+                )),
+                user_properties: user_properties.as_user_properties(),
+                content_type: Some(mqtt_format::v5::variable_header::ContentType("text/plain")),
+                ..PublishProperties::new()
+            },
+            payload: b"21.5",
+        }));
+
+        let message = Message::new(packet.clone()).unwrap();
+        assert_eq!(message.topic().to_string(), "home/kitchen/temperature");
+        assert_eq!(&message.payload()[..], b"21.5");
+        assert_eq!(message.quality_of_service(), QualityOfService::AtLeastOnce);
+        assert!(message.retain());
+        assert!(message.duplicate());
+        assert_eq!(message.payload_format_indicator(), Some(true));
+        assert_eq!(message.message_expiry_interval(), Some(60));
+        assert_eq!(message.topic_alias(), None);
+        assert_eq!(message.response_topic(), Some("home/responses"));
+        assert_eq!(message.correlation_data(), Some(&b"request-1"[..]));
+        assert_eq!(
+            message.user_properties().collect::<Vec<_>>(),
+            [("unit", "celsius"), ("sensor", "kitchen")]
+        );
+        assert_eq!(message.subscription_identifier(), None);
+        assert_eq!(message.content_type(), Some("text/plain"));
+
+        // The payload shares the buffer of the received packet
+        assert!(
+            packet
+                .as_bytes()
+                .as_ptr_range()
+                .contains(&message.payload().as_ptr())
+        );
+    }
+}
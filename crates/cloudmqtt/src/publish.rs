@@ -0,0 +1,282 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Publishing messages with a [`CloudmqttClient`](crate::CloudmqttClient)
+
+use mqtt_format::v5::packets::publish::MPublish;
+use mqtt_format::v5::packets::publish::PublishProperties;
+use mqtt_format::v5::qos::QualityOfService;
+
+use crate::CloudmqttClient;
+use crate::codec::MqttPacket;
+use crate::codec::UserPropertiesBuf;
+use crate::error::Error;
+
+/// A message to publish, created with [`CloudmqttClient::publish_builder`]
+pub struct PublishBuilder<'a> {
+    client: &'a CloudmqttClient,
+    topic: String,
+    payload: Vec<u8>,
+    quality_of_service: QualityOfService,
+    retain: bool,
+
+    payload_format_indicator: Option<bool>,
+    message_expiry_interval: Option<u32>,
+    content_type: Option<String>,
+    response_topic: Option<String>,
+    correlation_data: Option<Vec<u8>>,
+    user_properties: UserPropertiesBuf,
+}
+
+impl<'a> PublishBuilder<'a> {
+    pub(crate) fn new(
+        client: &'a CloudmqttClient,
+        topic: impl Into<String>,
+        payload: impl Into<Vec<u8>>,
+    ) -> Self {
+        Self {
+            client,
+            topic: topic.into(),
+            payload: payload.into(),
+            quality_of_service: QualityOfService::AtMostOnce,
+            retain: false,
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            content_type: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: UserPropertiesBuf::default(),
+        }
+    }
+
+    pub fn with_quality_of_service(mut self, quality_of_service: QualityOfService) -> Self {
+        self.quality_of_service = quality_of_service;
+        self
+    }
+
+    pub fn with_retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Whether the payload is UTF-8 encoded character data
+    pub fn with_payload_format_indicator(mut self, is_utf8: bool) -> Self {
+        self.payload_format_indicator = Some(is_utf8);
+        self
+    }
+
+    /// How long, in seconds, the server keeps the message for subscribers that are offline
+    pub fn with_message_expiry_interval(mut self, message_expiry_interval: u32) -> Self {
+        self.message_expiry_interval = Some(message_expiry_interval);
+        self
+    }
+
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    pub fn with_response_topic(mut self, response_topic: impl Into<String>) -> Self {
+        self.response_topic = Some(response_topic.into());
+        self
+    }
+
+    pub fn with_correlation_data(mut self, correlation_data: impl Into<Vec<u8>>) -> Self {
+        self.correlation_data = Some(correlation_data.into());
+        self
+    }
+
+    /// Add a user property, may be called multiple times
+    ///
+    /// # Panics
+    ///
+    /// If the key or value is longer than 65535 bytes
+    pub fn with_user_property(mut self, key: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        self.user_properties.push(key.as_ref(), value.as_ref());
+        self
+    }
+
+    /// Publish the message
+    ///
+    /// Completes once the message is delivered: as soon as it is written for QoS 0, when the
+    /// server sent a PUBACK for QoS 1, and when it sent a PUBCOMP for QoS 2.
+    ///
+    /// While not connected, this fails unless the client has an
+    /// [offline queue](crate::connect::ConnectOptions::with_offline_queue).
+    pub async fn send(self) -> Result<(), Error> {
+        self.client.core_client.publish(self.as_packet()).await
+    }
+
+    fn as_packet(&self) -> MqttPacket {
+        use mqtt_format::v5::variable_header::ContentType;
+        use mqtt_format::v5::variable_header::CorrelationData;
+        use mqtt_format::v5::variable_header::MessageExpiryInterval;
+        use mqtt_format::v5::variable_header::PacketIdentifier;
+        use mqtt_format::v5::variable_header::PayloadFormatIndicator;
+        use mqtt_format::v5::variable_header::ResponseTopic;
+
+        // The FSM replaces the identifier with a free one
+        let packet_identifier = (self.quality_of_service != QualityOfService::AtMostOnce)
+            .then(|| PacketIdentifier(1.try_into().unwrap()));
+
+        MqttPacket::new(mqtt_format::v5::packets::MqttPacket::Publish(MPublish {
+            duplicate: false,
+            quality_of_service: self.quality_of_service,
+            retain: self.retain,
+            topic_name: &self.topic,
+            packet_identifier,
+            properties: PublishProperties {
+                payload_format_indicator: self
+                    .payload_format_indicator
+                    .map(|is_utf8| PayloadFormatIndicator(is_utf8.into())),
+                message_expiry_interval: self.message_expiry_interval.map(MessageExpiryInterval),
+                content_type: self.content_type.as_deref().map(ContentType),
+                response_topic: self.response_topic.as_deref().map(ResponseTopic),
+                correlation_data: self.correlation_data.as_deref().map(CorrelationData),
+                user_properties: self.user_properties.as_user_properties(),
+                ..PublishProperties::new()
+            },
+            payload: &self.payload,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use mqtt_format::v5::qos::QualityOfService;
+    use tokio_util::codec::Framed;
+
+    use crate::CloudmqttClient;
+    use crate::codec::MqttPacket;
+    use crate::codec::MqttPacketCodec;
+    use crate::error::Error;
+
+    async fn next_packet(
+        server: &mut Framed<tokio::io::DuplexStream, MqttPacketCodec>,
+    ) -> MqttPacket {
+        server.next().await.unwrap().unwrap()
+    }
+
+    async fn connected_client() -> (
+        CloudmqttClient,
+        Framed<tokio::io::DuplexStream, MqttPacketCodec>,
+    ) {
+        let (client, server) = tokio::io::duplex(1024);
+        let client = CloudmqttClient::new_from_connection(client);
+        let mut server = Framed::new(server, MqttPacketCodec);
+
+        next_packet(&mut server).await;
+        server
+            .send(FormatMqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn check_qos2_publish_completes_on_pubcomp() {
+        let (client, mut server) = connected_client().await;
+
+        let publish = client
+            .publish_builder("status/sensor", "online")
+            .with_quality_of_service(QualityOfService::ExactlyOnce)
+            .with_retain(true)
+            .with_content_type("text/plain")
+            .with_user_property("site", "north")
+            .send();
+
+        let broker = async {
+            let packet = next_packet(&mut server).await;
+            let FormatMqttPacket::Publish(publish) = packet.get_packet() else {
+                panic!("Expected a publish, got: {packet:?}");
+            };
+            assert_eq!(publish.quality_of_service, QualityOfService::ExactlyOnce);
+            assert!(publish.retain);
+            assert_eq!(publish.topic_name, "status/sensor");
+            assert_eq!(publish.payload, b"online");
+            assert_eq!(
+                publish.properties.content_type().map(|ct| ct.0),
+                Some("text/plain")
+            );
+            assert!(publish.properties.user_properties().is_some());
+            let packet_identifier = publish.packet_identifier.unwrap();
+
+            server
+                .send(FormatMqttPacket::Pubrec(
+                    mqtt_format::v5::packets::pubrec::MPubrec {
+                        packet_identifier,
+                        reason: mqtt_format::v5::packets::pubrec::PubrecReasonCode::Success,
+                        properties: mqtt_format::v5::packets::pubrec::PubrecProperties::new(),
+                    },
+                ))
+                .await
+                .unwrap();
+
+            let packet = next_packet(&mut server).await;
+            assert!(matches!(packet.get_packet(), FormatMqttPacket::Pubrel(..)));
+
+            server
+                .send(FormatMqttPacket::Pubcomp(
+                    mqtt_format::v5::packets::pubcomp::MPubcomp {
+                        packet_identifier,
+                        reason: mqtt_format::v5::packets::pubcomp::PubcompReasonCode::Success,
+                        properties: mqtt_format::v5::packets::pubcomp::PubcompProperties::new(),
+                    },
+                ))
+                .await
+                .unwrap();
+        };
+
+        let (result, ()) = tokio::join!(publish, broker);
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_rejected_publish_fails() {
+        let (client, mut server) = connected_client().await;
+
+        let publish = client
+            .publish_builder("forbidden", "data")
+            .with_quality_of_service(QualityOfService::AtLeastOnce)
+            .send();
+
+        let broker = async {
+            let packet = next_packet(&mut server).await;
+            let FormatMqttPacket::Publish(publish) = packet.get_packet() else {
+                panic!("Expected a publish, got: {packet:?}");
+            };
+
+            server
+                .send(FormatMqttPacket::Puback(
+                    mqtt_format::v5::packets::puback::MPuback {
+                        packet_identifier: publish.packet_identifier.unwrap(),
+                        reason: mqtt_format::v5::packets::puback::PubackReasonCode::NotAuthorized,
+                        properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                    },
+                ))
+                .await
+                .unwrap();
+        };
+
+        let (result, ()) = tokio::join!(publish, broker);
+        assert!(matches!(
+            result,
+            Err(Error::PubackRejected(
+                mqtt_format::v5::packets::puback::PubackReasonCode::NotAuthorized
+            ))
+        ));
+    }
+}
@@ -0,0 +1,421 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Holding on to publishes while the client is not connected
+//!
+//! Without an offline queue, publishing while disconnected fails with
+//! [`Error::NotConnected`](crate::error::Error::NotConnected). With one, publishes are buffered
+//! and sent in order once the client is connected again. A publish carrying a message expiry
+//! interval is dropped if it expires while queued, and otherwise sent with the time it waited
+//! deducted from the interval.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::codec::MqttPacket;
+use crate::error::Error;
+
+/// What to do with a publish that does not fit into a full offline queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued publishes until the new one fits
+    DropOldest,
+
+    /// Drop the new publish
+    DropNewest,
+
+    /// Wait for the queue to be flushed far enough for the new publish to fit
+    Wait,
+}
+
+/// How many publishes an offline queue holds, and what happens once it is full
+///
+/// By default up to 1000 publishes taking up at most 1 MiB are queued, and the oldest are dropped
+/// to make room for new ones.
+#[derive(Debug, Clone)]
+pub struct OfflineQueue {
+    max_messages: usize,
+    max_bytes: usize,
+    overflow_policy: OverflowPolicy,
+}
+
+impl Default for OfflineQueue {
+    fn default() -> Self {
+        Self {
+            max_messages: 1000,
+            max_bytes: 1024 * 1024,
+            overflow_policy: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+impl OfflineQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_messages(mut self, max_messages: usize) -> Self {
+        self.max_messages = max_messages;
+        self
+    }
+
+    /// The most bytes the queued publishes may take up, as encoded on the wire
+    ///
+    /// A publish that is larger on its own is never queued.
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn with_overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+}
+
+/// A publish waiting to be sent
+pub(crate) struct Queued {
+    pub(crate) packet: MqttPacket,
+    pub(crate) delivered: tokio::sync::oneshot::Sender<Result<(), Error>>,
+    expires_at: Option<Instant>,
+}
+
+impl Queued {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    fn fail(self, error: Error) {
+        let _ = self.delivered.send(Err(error));
+    }
+
+    /// Deduct the time spent in the queue from the message expiry interval
+    fn update_expiry_interval(&mut self, now: Instant) {
+        let Some(expires_at) = self.expires_at else {
+            return;
+        };
+
+        let mqtt_format::v5::packets::MqttPacket::Publish(publish) = self.packet.get_packet()
+        else {
+            return;
+        };
+
+        let remaining = expires_at
+            .saturating_duration_since(now)
+            .as_secs_f64()
+            .ceil() as u32;
+        let publish = mqtt_format::v5::packets::publish::MPublish {
+            properties: mqtt_format::v5::packets::publish::PublishProperties {
+                message_expiry_interval: Some(
+                    mqtt_format::v5::variable_header::MessageExpiryInterval(remaining),
+                ),
+                ..publish.properties.clone()
+            },
+            ..publish.clone()
+        };
+
+        self.packet = MqttPacket::new(publish.into());
+    }
+}
+
+/// The publishes of a client waiting for a connection
+pub(crate) struct PublishQueue {
+    options: OfflineQueue,
+    state: Mutex<QueueState>,
+
+    /// Notified whenever a publish was queued
+    filled: tokio::sync::Notify,
+
+    /// Notified whenever publishes left the queue
+    drained: tokio::sync::Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    publishes: VecDeque<Queued>,
+    bytes: usize,
+}
+
+impl QueueState {
+    fn pop_front(&mut self) -> Option<Queued> {
+        let queued = self.publishes.pop_front()?;
+        self.bytes -= queued.packet.as_bytes().len();
+        Some(queued)
+    }
+
+    fn drop_expired(&mut self, now: Instant) {
+        let (expired, publishes) = std::mem::take(&mut self.publishes)
+            .into_iter()
+            .partition(|queued| queued.is_expired(now));
+        self.publishes = publishes;
+
+        for queued in expired {
+            tracing::debug!("Dropping queued publish, its message expired");
+            self.bytes -= queued.packet.as_bytes().len();
+            queued.fail(Error::MessageExpired);
+        }
+    }
+}
+
+impl PublishQueue {
+    pub(crate) fn new(options: OfflineQueue) -> Self {
+        Self {
+            options,
+            state: Mutex::new(QueueState::default()),
+            filled: tokio::sync::Notify::new(),
+            drained: tokio::sync::Notify::new(),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, QueueState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.state().publishes.is_empty()
+    }
+
+    /// Queue a publish, applying the overflow policy if the queue is full
+    pub(crate) async fn push(
+        &self,
+        packet: MqttPacket,
+        delivered: tokio::sync::oneshot::Sender<Result<(), Error>>,
+    ) -> Result<(), Error> {
+        let size = packet.as_bytes().len();
+        if size > self.options.max_bytes || self.options.max_messages == 0 {
+            return Err(Error::OfflineQueueFull);
+        }
+
+        let expires_at = match packet.get_packet() {
+            mqtt_format::v5::packets::MqttPacket::Publish(publish) => publish
+                .properties
+                .message_expiry_interval
+                .as_ref()
+                .map(|interval| Instant::now() + Duration::from_secs(interval.0.into())),
+            _ => None,
+        };
+
+        loop {
+            // Registered before checking, so that no drain in between is missed
+            let drained = self.drained.notified();
+
+            {
+                let mut state = self.state();
+                state.drop_expired(Instant::now());
+
+                let fits = |state: &QueueState| {
+                    state.publishes.len() < self.options.max_messages
+                        && state.bytes + size <= self.options.max_bytes
+                };
+
+                if !fits(&state) {
+                    match self.options.overflow_policy {
+                        OverflowPolicy::DropNewest => {
+                            tracing::debug!("Offline queue is full, dropping the new publish");
+                            return Err(Error::OfflineQueueFull);
+                        }
+                        OverflowPolicy::DropOldest => {
+                            while !fits(&state) {
+                                tracing::debug!(
+                                    "Offline queue is full, dropping the oldest publish"
+                                );
+                                if let Some(oldest) = state.pop_front() {
+                                    oldest.fail(Error::OfflineQueueFull);
+                                }
+                            }
+                        }
+                        OverflowPolicy::Wait => {}
+                    }
+                }
+
+                if fits(&state) {
+                    state.bytes += size;
+                    state.publishes.push_back(Queued {
+                        packet,
+                        delivered,
+                        expires_at,
+                    });
+                    self.filled.notify_one();
+                    return Ok(());
+                }
+            }
+
+            tracing::trace!("Offline queue is full, waiting for it to drain");
+            drained.await;
+        }
+    }
+
+    /// Take the next publish that has not expired yet, waiting for one if the queue is empty
+    ///
+    /// This is cancel safe, a publish is only taken out of the queue when it is returned.
+    pub(crate) async fn pop(&self) -> Queued {
+        loop {
+            {
+                let mut state = self.state();
+                let now = Instant::now();
+                state.drop_expired(now);
+
+                if let Some(mut queued) = state.pop_front() {
+                    drop(state);
+                    self.drained.notify_waiters();
+
+                    queued.update_expiry_interval(now);
+                    return queued;
+                }
+            }
+
+            self.filled.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+
+    use super::OfflineQueue;
+    use super::OverflowPolicy;
+    use super::PublishQueue;
+    use crate::codec::MqttPacket;
+    use crate::error::Error;
+
+    fn publish(payload: &[u8], message_expiry_interval: Option<u32>) -> MqttPacket {
+        MqttPacket::new(FormatMqttPacket::Publish(
+            mqtt_format::v5::packets::publish::MPublish {
+                duplicate: false,
+                quality_of_service: mqtt_format::v5::qos::QualityOfService::AtMostOnce,
+                retain: false,
+                topic_name: "a/b",
+                packet_identifier: None,
+                properties: mqtt_format::v5::packets::publish::PublishProperties {
+                    message_expiry_interval: message_expiry_interval
+                        .map(mqtt_format::v5::variable_header::MessageExpiryInterval),
+                    ..mqtt_format::v5::packets::publish::PublishProperties::new()
+                },
+                payload,
+            },
+        ))
+    }
+
+    fn payload(packet: &MqttPacket) -> &[u8] {
+        match packet.get_packet() {
+            FormatMqttPacket::Publish(publish) => publish.payload,
+            packet => panic!("Expected a publish, got: {packet:?}"),
+        }
+    }
+
+    async fn push(
+        queue: &PublishQueue,
+        payload: &[u8],
+    ) -> (
+        Result<(), Error>,
+        tokio::sync::oneshot::Receiver<Result<(), Error>>,
+    ) {
+        let (delivered, receiver) = tokio::sync::oneshot::channel();
+        (
+            queue.push(publish(payload, None), delivered).await,
+            receiver,
+        )
+    }
+
+    #[tokio::test]
+    async fn check_drop_oldest() {
+        let queue = PublishQueue::new(OfflineQueue::new().with_max_messages(2));
+
+        let (_, mut first) = push(&queue, b"1").await;
+        push(&queue, b"2").await.0.unwrap();
+        push(&queue, b"3").await.0.unwrap();
+
+        assert!(matches!(first.try_recv(), Ok(Err(Error::OfflineQueueFull))));
+        assert_eq!(payload(&queue.pop().await.packet), b"2");
+        assert_eq!(payload(&queue.pop().await.packet), b"3");
+        assert!(queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn check_drop_newest() {
+        let size = publish(b"1", None).as_bytes().len();
+        let queue = PublishQueue::new(
+            OfflineQueue::new()
+                .with_max_bytes(2 * size)
+                .with_overflow_policy(OverflowPolicy::DropNewest),
+        );
+
+        push(&queue, b"1").await.0.unwrap();
+        push(&queue, b"2").await.0.unwrap();
+        assert!(matches!(
+            push(&queue, b"3").await.0,
+            Err(Error::OfflineQueueFull)
+        ));
+        assert!(matches!(
+            push(&queue, &[0; 64]).await.0,
+            Err(Error::OfflineQueueFull)
+        ));
+
+        assert_eq!(payload(&queue.pop().await.packet), b"1");
+        assert_eq!(payload(&queue.pop().await.packet), b"2");
+    }
+
+    #[tokio::test]
+    async fn check_wait() {
+        let queue = PublishQueue::new(
+            OfflineQueue::new()
+                .with_max_messages(1)
+                .with_overflow_policy(OverflowPolicy::Wait),
+        );
+
+        push(&queue, b"1").await.0.unwrap();
+
+        let waiting = push(&queue, b"2");
+        let draining = async {
+            tokio::task::yield_now().await;
+            queue.pop().await
+        };
+        let ((pushed, _), first) = tokio::join!(waiting, draining);
+        pushed.unwrap();
+
+        assert_eq!(payload(&first.packet), b"1");
+        assert_eq!(payload(&queue.pop().await.packet), b"2");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn check_message_expiry() {
+        let queue = PublishQueue::new(OfflineQueue::new());
+
+        let (delivered, mut expired) = tokio::sync::oneshot::channel();
+        queue.push(publish(b"1", Some(5)), delivered).await.unwrap();
+        let (delivered, _) = tokio::sync::oneshot::channel();
+        queue
+            .push(publish(b"2", Some(60)), delivered)
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+
+        let queued = queue.pop().await;
+        assert!(matches!(expired.try_recv(), Ok(Err(Error::MessageExpired))));
+
+        let FormatMqttPacket::Publish(publish) = queued.packet.get_packet() else {
+            panic!("Expected a publish");
+        };
+        assert_eq!(publish.payload, b"2");
+        assert_eq!(
+            publish
+                .properties
+                .message_expiry_interval
+                .as_ref()
+                .map(|interval| interval.0),
+            Some(50)
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Automatic reconnection of a [`CloudmqttClient`](crate::CloudmqttClient)
+
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+
+/// A bidirectional byte stream that a client can speak MQTT over
+pub trait Transport: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static {}
+
+impl<T> Transport for T where T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static {}
+
+pub type BoxedTransport = Pin<Box<dyn Transport>>;
+
+pub(crate) type ConnectionFactory =
+    Arc<dyn Fn() -> BoxFuture<'static, std::io::Result<BoxedTransport>> + Send + Sync>;
+
+pub(crate) fn boxed_connection_factory<F, Fut, C>(factory: F) -> ConnectionFactory
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = std::io::Result<C>> + Send + 'static,
+    C: Transport,
+{
+    Arc::new(move || {
+        factory()
+            .map(|connection| connection.map(|c| Box::pin(c) as BoxedTransport))
+            .boxed()
+    })
+}
+
+/// Opens a transport to the server another server redirected the client to
+pub(crate) type RedirectFactory =
+    Arc<dyn Fn(String) -> BoxFuture<'static, std::io::Result<BoxedTransport>> + Send + Sync>;
+
+pub(crate) fn boxed_redirect_factory<F, Fut, C>(factory: F) -> RedirectFactory
+where
+    F: Fn(String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = std::io::Result<C>> + Send + 'static,
+    C: Transport,
+{
+    Arc::new(move |server_reference| {
+        factory(server_reference)
+            .map(|connection| connection.map(|c| Box::pin(c) as BoxedTransport))
+            .boxed()
+    })
+}
+
+/// How a client re-establishes a lost connection
+///
+/// The delay between two attempts grows exponentially, starting at the initial backoff and
+/// capped at the maximum backoff. With jitter enabled, each delay is randomly shortened by up to
+/// half its length so that many clients losing their connection at the same time do not all
+/// reconnect at the same instant.
+///
+/// A connection that the server accepted but dropped again before it stayed up for the stable
+/// period counts as a failed attempt, so a server that keeps closing connections right away is
+/// not reconnected to in a tight loop.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    initial_backoff: Duration,
+    maximum_backoff: Duration,
+    multiplier: u32,
+    jitter: bool,
+    max_attempts: Option<u32>,
+    stable_after: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            maximum_backoff: Duration::from_secs(60),
+            multiplier: 2,
+            jitter: true,
+            max_attempts: None,
+            stable_after: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    pub fn with_maximum_backoff(mut self, maximum_backoff: Duration) -> Self {
+        self.maximum_backoff = maximum_backoff;
+        self
+    }
+
+    pub fn with_multiplier(mut self, multiplier: u32) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Give up after this many consecutive failed attempts
+    ///
+    /// By default the client tries to reconnect forever.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// How long a connection has to stay up before the backoff starts over, defaults to 30
+    /// seconds
+    pub fn with_stable_after(mut self, stable_after: Duration) -> Self {
+        self.stable_after = stable_after;
+        self
+    }
+
+    /// Whether a connection that was up for this long resets the backoff
+    pub(crate) fn is_stable(&self, connected_for: Duration) -> bool {
+        connected_for >= self.stable_after
+    }
+
+    pub(crate) fn attempts_exhausted(&self, attempt: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempt >= max)
+    }
+
+    /// The delay before the given (zero-based) attempt
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.checked_pow(attempt).unwrap_or(u32::MAX);
+        let backoff = self
+            .initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.maximum_backoff)
+            .min(self.maximum_backoff);
+
+        if self.jitter {
+            let half = backoff / 2;
+            half + half.mul_f64(random_fraction())
+        } else {
+            backoff
+        }
+    }
+}
+
+/// A random number in `0.0..1.0`
+///
+/// This is only used to spread out reconnection attempts, so the randomness of a freshly seeded
+/// hasher is plenty.
+fn random_fraction() -> f64 {
+    let random = std::collections::hash_map::RandomState::new().hash_one(0u8);
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::ReconnectPolicy;
+
+    #[test]
+    fn check_backoff_grows_until_maximum() {
+        let policy = ReconnectPolicy::new()
+            .with_initial_backoff(Duration::from_millis(100))
+            .with_maximum_backoff(Duration::from_secs(1))
+            .with_jitter(false);
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(800));
+        assert_eq!(policy.backoff(4), Duration::from_secs(1));
+        assert_eq!(policy.backoff(100), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn check_jitter_stays_in_bounds() {
+        let policy = ReconnectPolicy::new()
+            .with_initial_backoff(Duration::from_millis(100))
+            .with_jitter(true);
+
+        for _ in 0..100 {
+            let backoff = policy.backoff(2);
+            assert!(backoff >= Duration::from_millis(200));
+            assert!(backoff <= Duration::from_millis(400));
+        }
+    }
+
+    #[test]
+    fn check_max_attempts() {
+        let policy = ReconnectPolicy::new();
+        assert!(!policy.attempts_exhausted(1000));
+
+        let policy = policy.with_max_attempts(3);
+        assert!(!policy.attempts_exhausted(2));
+        assert!(policy.attempts_exhausted(3));
+    }
+}
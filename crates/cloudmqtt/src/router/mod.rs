@@ -0,0 +1,349 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+mod trie;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use dashmap::DashMap;
+use mqtt_format::v5::packets::puback::PubackReasonCode;
+use tokio::sync::mpsc::error::TrySendError;
+
+use self::trie::TopicTrie;
+use crate::SlowConsumerPolicy;
+use crate::SubscriptionId;
+use crate::SubscriptionSink;
+use crate::acknowledge::Acknowledger;
+use crate::client::DetachedUnsubscriber;
+use crate::message::Message;
+use crate::topic::TopicFilterBuf;
+use crate::topic::TopicNameBuf;
+
+/// What a publish is acknowledged with if a subscription it was for never got it
+///
+/// The server must not take the publish for received, and sending it again is no use either, as
+/// it would be dropped the same way.
+const UNDELIVERED: PubackReasonCode = PubackReasonCode::ImplementationSpecificError;
+
+pub struct Router {
+    _join_handle: tokio::task::JoinHandle<()>,
+    next_subscription_id: std::sync::atomic::AtomicU64,
+    subscriptions: Arc<DashMap<SubscriptionId, Sink>>,
+    routes: Arc<RwLock<Routes>>,
+}
+
+/// How the router hands messages to a subscription, without ever waiting for it
+#[derive(Debug, Clone)]
+enum Sink {
+    /// Straight into the buffer of the subscription, if it has room
+    Buffer(SubscriptionSink),
+
+    /// To a task of its own that waits for room in the buffer of the subscription
+    Forwarder(tokio::sync::mpsc::UnboundedSender<(Message, Option<Acknowledger>)>),
+}
+
+impl Sink {
+    fn new(sink: SubscriptionSink) -> Self {
+        if sink.slow_consumer_policy != SlowConsumerPolicy::Block {
+            return Self::Buffer(sink);
+        }
+
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::task::spawn(forward(receiver, sink.sender));
+        Self::Forwarder(sender)
+    }
+}
+
+/// Hand messages to a subscription as it makes room for them
+///
+/// The publishes are only acknowledged for the router once the subscription took them, so the
+/// server keeps to the receive maximum while the subscription is behind.
+async fn forward(
+    mut messages: tokio::sync::mpsc::UnboundedReceiver<(Message, Option<Acknowledger>)>,
+    sender: tokio::sync::mpsc::Sender<Message>,
+) {
+    while let Some((message, routed)) = messages.recv().await {
+        let reason = match sender.send(message).await {
+            Ok(()) => PubackReasonCode::Success,
+            Err(undelivered) => {
+                undelivered.0.ack_with_reason(UNDELIVERED);
+                UNDELIVERED
+            }
+        };
+
+        if let Some(routed) = routed {
+            routed.ack_with_reason(reason);
+        }
+    }
+}
+
+/// Which subscriptions an incoming publish goes to
+#[derive(Debug, Default)]
+struct Routes {
+    trie: TopicTrie,
+
+    /// The topic filters of every subscription
+    topic_filters: HashMap<SubscriptionId, Vec<TopicFilterBuf>>,
+
+    /// The v5 subscription identifier each subscription was made with, if any
+    identifiers: HashMap<SubscriptionId, u32>,
+    identified: HashMap<u32, Vec<SubscriptionId>>,
+}
+
+impl Routes {
+    /// The subscriptions a publish is for, each only once
+    ///
+    /// If the server tells which subscription the publish matched, subscriptions made with a
+    /// subscription identifier only get it if it is theirs.
+    fn targets(
+        &self,
+        topic_name: &TopicNameBuf,
+        subscription_identifier: Option<u32>,
+    ) -> Vec<SubscriptionId> {
+        let mut targets = self.trie.matches(topic_name);
+
+        if let Some(subscription_identifier) = subscription_identifier {
+            targets.retain(|id| !self.identifiers.contains_key(id));
+            targets.extend(
+                self.identified
+                    .get(&subscription_identifier)
+                    .into_iter()
+                    .flatten(),
+            );
+            targets.sort_unstable();
+            targets.dedup();
+        }
+
+        targets
+    }
+
+    fn remove_subscription(&mut self, subscription_id: SubscriptionId) -> Vec<TopicFilterBuf> {
+        if let Some(subscription_identifier) = self.identifiers.remove(&subscription_id) {
+            if let Some(identified) = self.identified.get_mut(&subscription_identifier) {
+                identified.retain(|id| *id != subscription_id);
+                if identified.is_empty() {
+                    self.identified.remove(&subscription_identifier);
+                }
+            }
+        }
+
+        self.topic_filters
+            .remove(&subscription_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|topic_filter| self.trie.remove(topic_filter, subscription_id))
+            .collect()
+    }
+}
+
+/// Hand a message to every subscription it is for
+///
+/// Returns whether every subscription got it, or will once it made room for it.
+fn route(
+    subscriptions: &DashMap<SubscriptionId, Sink>,
+    routes: &RwLock<Routes>,
+    unsubscriber: &DetachedUnsubscriber,
+    message: &Message,
+) -> bool {
+    let topic_name = message.topic();
+    let subscription_ids = routes
+        .read()
+        .unwrap()
+        .targets(topic_name, message.subscription_identifier());
+
+    if subscription_ids.is_empty() {
+        tracing::debug!(topic = ?topic_name, "Did not find any subscription id for topic");
+        return true;
+    }
+
+    let mut delivered = true;
+    for subscription_id in subscription_ids {
+        let Some(sink) = subscriptions
+            .get(&subscription_id)
+            .map(|r| r.value().clone())
+        else {
+            tracing::debug!(topic = ?topic_name, "Did not find any subscription for topic");
+            continue;
+        };
+
+        let undelivered = match sink {
+            Sink::Forwarder(forwarder) => forwarder
+                .send((message.share(), message.routed_acknowledger()))
+                .err()
+                .map(|e| e.0.0),
+            Sink::Buffer(sink) => match sink.sender.try_send(message.share()) {
+                Ok(()) => None,
+                Err(TrySendError::Full(undelivered)) => {
+                    if sink.slow_consumer_policy == SlowConsumerPolicy::Disconnect {
+                        tracing::debug!(?subscription_id, "Disconnecting slow subscription");
+                        disconnect(subscriptions, routes, unsubscriber, subscription_id);
+                    } else {
+                        tracing::debug!(?subscription_id, "Dropping message for slow subscription");
+                    }
+
+                    Some(undelivered)
+                }
+                Err(TrySendError::Closed(undelivered)) => Some(undelivered),
+            },
+        };
+
+        // Nobody is left to acknowledge it for the subscription
+        if let Some(undelivered) = undelivered {
+            undelivered.ack_with_reason(UNDELIVERED);
+            delivered = false;
+        }
+    }
+
+    delivered
+}
+
+/// Stop routing to a subscription and unsubscribe from the topic filters nobody else uses
+fn disconnect(
+    subscriptions: &DashMap<SubscriptionId, Sink>,
+    routes: &RwLock<Routes>,
+    unsubscriber: &DetachedUnsubscriber,
+    subscription_id: SubscriptionId,
+) {
+    subscriptions.remove(&subscription_id);
+    let topic_filters = routes
+        .write()
+        .unwrap()
+        .remove_subscription(subscription_id)
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    if !topic_filters.is_empty() {
+        tracing::debug!(?topic_filters, "Unsubscribing disconnected subscription");
+        unsubscriber.unsubscribe(crate::unsubscribe_packet(&topic_filters));
+    }
+}
+
+impl Router {
+    pub fn new(
+        mut incoming_receiver: tokio::sync::mpsc::Receiver<Message>,
+        unsubscriber: DetachedUnsubscriber,
+    ) -> Self {
+        let subscriptions = std::sync::Arc::new(dashmap::DashMap::<SubscriptionId, Sink>::new());
+        let routes = Arc::new(RwLock::new(Routes::default()));
+
+        let join_handle = tokio::task::spawn({
+            let subscriptions = subscriptions.clone();
+            let routes = routes.clone();
+            async move {
+                while let Some(message) = incoming_receiver.recv().await {
+                    tracing::info!("Received packet");
+
+                    let delivered = route(&subscriptions, &routes, &unsubscriber, &message);
+
+                    // Every subscription got its own handle, this one is not needed anymore. Unless
+                    // the application acknowledges it, this acknowledges the publish.
+                    if delivered {
+                        message.ack();
+                    } else {
+                        message.ack_with_reason(UNDELIVERED);
+                    }
+                }
+            }
+        });
+
+        Self {
+            _join_handle: join_handle,
+            next_subscription_id: std::sync::atomic::AtomicU64::new(0),
+            subscriptions,
+            routes,
+        }
+    }
+
+    pub(crate) fn add_subscription_sink(&self, sink: SubscriptionSink) -> SubscriptionId {
+        let subscription_id = SubscriptionId(
+            self.next_subscription_id
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        );
+        self.subscriptions.insert(subscription_id, Sink::new(sink));
+
+        subscription_id
+    }
+
+    pub(crate) fn add_subscription_to_topic(
+        &self,
+        subscription_id: SubscriptionId,
+        topic_filter: TopicFilterBuf,
+    ) {
+        let mut routes = self.routes.write().unwrap();
+
+        routes.trie.insert(&topic_filter, subscription_id);
+
+        let topic_filters = routes.topic_filters.entry(subscription_id).or_default();
+        if !topic_filters.contains(&topic_filter) {
+            topic_filters.push(topic_filter);
+        }
+    }
+
+    /// Route publishes carrying the given subscription identifier to the subscription
+    pub(crate) fn add_subscription_identifier(
+        &self,
+        subscription_id: SubscriptionId,
+        subscription_identifier: u32,
+    ) {
+        let mut routes = self.routes.write().unwrap();
+
+        routes
+            .identifiers
+            .insert(subscription_id, subscription_identifier);
+        routes
+            .identified
+            .entry(subscription_identifier)
+            .or_default()
+            .push(subscription_id);
+    }
+
+    pub(crate) fn remove_subscription_from_topic(
+        &self,
+        subscription_id: SubscriptionId,
+        topic_filter: &str,
+    ) {
+        let Ok(topic_filter) = TopicFilterBuf::new(topic_filter) else {
+            return;
+        };
+
+        let mut routes = self.routes.write().unwrap();
+        routes.trie.remove(&topic_filter, subscription_id);
+        if let Some(topic_filters) = routes.topic_filters.get_mut(&subscription_id) {
+            topic_filters.retain(|tf| *tf != topic_filter);
+        }
+    }
+
+    /// Stop routing to the given subscription
+    ///
+    /// Returns the topic filters no other subscription uses anymore.
+    pub(crate) fn remove_subscription(
+        &self,
+        subscription_id: SubscriptionId,
+    ) -> Vec<TopicFilterBuf> {
+        self.subscriptions.remove(&subscription_id);
+
+        self.routes
+            .write()
+            .unwrap()
+            .remove_subscription(subscription_id)
+    }
+
+    /// Stop routing the given topic filter to any subscription
+    pub(crate) fn remove_topic_filter(&self, topic_filter: &str) {
+        let Ok(topic_filter) = TopicFilterBuf::new(topic_filter) else {
+            return;
+        };
+
+        let mut routes = self.routes.write().unwrap();
+        routes.trie.remove_all(&topic_filter);
+        for topic_filters in routes.topic_filters.values_mut() {
+            topic_filters.retain(|tf| *tf != topic_filter);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! A trie of topic filters, to find the subscriptions matching a topic name without comparing
+//! against every filter
+
+use std::collections::HashMap;
+
+use crate::SubscriptionId;
+use crate::topic::TopicFilterBuf;
+use crate::topic::TopicFilterLevel;
+use crate::topic::TopicNameBuf;
+
+#[derive(Debug, Default)]
+pub(crate) struct TopicTrie {
+    root: Node,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    /// Children for literal levels, including the empty level
+    children: HashMap<String, Node>,
+
+    /// Child for a `+` level
+    single_level_wildcard: Option<Box<Node>>,
+
+    /// Subscriptions whose topic filter ends in a `#` after this level
+    multi_level_wildcard: Vec<SubscriptionId>,
+
+    /// Subscriptions whose topic filter ends at this level
+    subscriptions: Vec<SubscriptionId>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.children.is_empty()
+            && self.single_level_wildcard.is_none()
+            && self.multi_level_wildcard.is_empty()
+            && self.subscriptions.is_empty()
+    }
+
+    fn insert(&mut self, levels: &[TopicFilterLevel], subscription_id: SubscriptionId) {
+        let Some((level, rest)) = levels.split_first() else {
+            if !self.subscriptions.contains(&subscription_id) {
+                self.subscriptions.push(subscription_id);
+            }
+            return;
+        };
+
+        let child = match level {
+            TopicFilterLevel::Path(_) | TopicFilterLevel::Empty => {
+                self.children.entry(level.as_str().to_string()).or_default()
+            }
+            TopicFilterLevel::TopicLevelSeperator => {
+                self.single_level_wildcard.get_or_insert_default()
+            }
+            TopicFilterLevel::MultiLevelSeperator => {
+                if !self.multi_level_wildcard.contains(&subscription_id) {
+                    self.multi_level_wildcard.push(subscription_id);
+                }
+                return;
+            }
+        };
+
+        child.insert(rest, subscription_id);
+    }
+
+    /// Apply `update` to the subscriptions of a topic filter, pruning nodes left empty
+    ///
+    /// Returns whether no subscription uses the topic filter anymore.
+    fn update(
+        &mut self,
+        levels: &[TopicFilterLevel],
+        update: impl FnOnce(&mut Vec<SubscriptionId>),
+    ) -> bool {
+        let Some((level, rest)) = levels.split_first() else {
+            update(&mut self.subscriptions);
+            return self.subscriptions.is_empty();
+        };
+
+        match level {
+            TopicFilterLevel::Path(_) | TopicFilterLevel::Empty => {
+                let Some(child) = self.children.get_mut(level.as_str()) else {
+                    return true;
+                };
+
+                let unused = child.update(rest, update);
+                if child.is_empty() {
+                    self.children.remove(level.as_str());
+                }
+                unused
+            }
+            TopicFilterLevel::TopicLevelSeperator => {
+                let Some(child) = self.single_level_wildcard.as_mut() else {
+                    return true;
+                };
+
+                let unused = child.update(rest, update);
+                if child.is_empty() {
+                    self.single_level_wildcard = None;
+                }
+                unused
+            }
+            TopicFilterLevel::MultiLevelSeperator => {
+                update(&mut self.multi_level_wildcard);
+                self.multi_level_wildcard.is_empty()
+            }
+        }
+    }
+
+    fn collect(&self, levels: &[String], wildcards: bool, matches: &mut Vec<SubscriptionId>) {
+        // `#` also matches the parent level
+        if wildcards {
+            matches.extend_from_slice(&self.multi_level_wildcard);
+        }
+
+        let Some((level, rest)) = levels.split_first() else {
+            matches.extend_from_slice(&self.subscriptions);
+            return;
+        };
+
+        if let Some(child) = self.children.get(level) {
+            child.collect(rest, true, matches);
+        }
+
+        if wildcards {
+            if let Some(child) = &self.single_level_wildcard {
+                child.collect(rest, true, matches);
+            }
+        }
+    }
+}
+
+impl TopicTrie {
+    pub(crate) fn insert(
+        &mut self,
+        topic_filter: &TopicFilterBuf,
+        subscription_id: SubscriptionId,
+    ) {
+        self.root.insert(topic_filter.levels(), subscription_id);
+    }
+
+    /// Returns whether no subscription uses the topic filter anymore
+    pub(crate) fn remove(
+        &mut self,
+        topic_filter: &TopicFilterBuf,
+        subscription_id: SubscriptionId,
+    ) -> bool {
+        self.root.update(topic_filter.levels(), |subscriptions| {
+            subscriptions.retain(|id| *id != subscription_id)
+        })
+    }
+
+    /// Remove the topic filter for every subscription
+    pub(crate) fn remove_all(&mut self, topic_filter: &TopicFilterBuf) {
+        self.root
+            .update(topic_filter.levels(), |subscriptions| subscriptions.clear());
+    }
+
+    /// Every subscription with a topic filter matching the topic name, each only once
+    pub(crate) fn matches(&self, topic_name: &TopicNameBuf) -> Vec<SubscriptionId> {
+        let levels = topic_name
+            .levels()
+            .iter()
+            .map(|level| level.to_string())
+            .collect::<Vec<_>>();
+
+        // Wildcards at the first level do not match topic names starting with `$` (4.7.2)
+        let wildcards = !levels.first().is_some_and(|level| level.starts_with('$'));
+
+        let mut matches = Vec::new();
+        self.root.collect(&levels, wildcards, &mut matches);
+
+        matches.sort_unstable();
+        matches.dedup();
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TopicTrie;
+    use crate::SubscriptionId;
+    use crate::topic::TopicFilterBuf;
+    use crate::topic::TopicNameBuf;
+
+    fn trie(filters: &[(&str, u64)]) -> TopicTrie {
+        let mut trie = TopicTrie::default();
+        for (filter, id) in filters {
+            trie.insert(&TopicFilterBuf::new(filter).unwrap(), SubscriptionId(*id));
+        }
+        trie
+    }
+
+    fn matches(trie: &TopicTrie, topic_name: &str) -> Vec<u64> {
+        trie.matches(&TopicNameBuf::new(topic_name).unwrap())
+            .into_iter()
+            .map(|id| id.0)
+            .collect()
+    }
+
+    #[test]
+    fn check_overlapping_filters() {
+        let trie = trie(&[
+            ("a/#", 0),
+            ("a/+/c", 1),
+            ("a/b/c", 2),
+            ("+/b/#", 3),
+            ("a/b", 4),
+            ("#", 5),
+            ("a/+/c", 5),
+        ]);
+
+        assert_eq!(matches(&trie, "a/b/c"), [0, 1, 2, 3, 5]);
+        assert_eq!(matches(&trie, "a/b"), [0, 3, 4, 5]);
+        assert_eq!(matches(&trie, "a"), [0, 5]);
+        assert_eq!(matches(&trie, "x/y"), [5]);
+    }
+
+    #[test]
+    fn check_empty_levels() {
+        let trie = trie(&[("/", 0), ("+/+", 1), ("a/", 2)]);
+
+        assert_eq!(matches(&trie, "/"), [0, 1]);
+        assert_eq!(matches(&trie, "a/"), [1, 2]);
+        assert_eq!(matches(&trie, "a"), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn check_reserved_topics() {
+        let trie = trie(&[("#", 0), ("+/monitor", 1), ("$SYS/#", 2), ("$SYS/+", 3)]);
+
+        assert_eq!(matches(&trie, "$SYS/monitor"), [2, 3]);
+        assert_eq!(matches(&trie, "app/monitor"), [0, 1]);
+    }
+
+    #[test]
+    fn check_removal() {
+        let mut trie = trie(&[("a/+", 0), ("a/+", 1), ("a/#", 1)]);
+
+        assert!(!trie.remove(&TopicFilterBuf::new("a/+").unwrap(), SubscriptionId(0)));
+        assert_eq!(matches(&trie, "a/b"), [1]);
+
+        assert!(trie.remove(&TopicFilterBuf::new("a/+").unwrap(), SubscriptionId(1)));
+        assert_eq!(matches(&trie, "a/b"), [1]);
+
+        trie.remove_all(&TopicFilterBuf::new("a/#").unwrap());
+        assert_eq!(matches(&trie, "a/b"), Vec::<u64>::new());
+        assert!(trie.root.is_empty());
+    }
+}
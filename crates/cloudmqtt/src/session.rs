@@ -0,0 +1,602 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Keeping the state of a session beyond the lifetime of a client
+//!
+//! A session store holds the outgoing QoS 1 and 2 packets the server has not completely
+//! acknowledged yet, and the identifiers of incoming QoS 2 publishes that were received but not
+//! released yet. When a client is created with a store that already holds such a session, the
+//! outgoing packets are sent again once connected and re-deliveries of the incoming publishes
+//! are not handed out a second time. Use it together with
+//! [`ConnectOptions::with_clean_start(false)`](crate::connect::ConnectOptions::with_clean_start),
+//! so that the server resumes the session as well.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::io::Write;
+use std::num::NonZeroU16;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Where the state of a session is persisted
+///
+/// Packets are handed over encoded, as they are sent on the wire.
+pub trait SessionStore: Send {
+    /// The session as it was last stored
+    fn load(&mut self) -> std::io::Result<StoredSession>;
+
+    /// Remember an outgoing packet, replacing the one previously stored with the same identifier
+    fn store_outgoing(&mut self, id: NonZeroU16, packet: &[u8]) -> std::io::Result<()>;
+
+    /// Forget an outgoing packet once the server acknowledged it
+    fn release_outgoing(&mut self, id: NonZeroU16) -> std::io::Result<()>;
+
+    /// Remember the identifier of an incoming QoS 2 publish
+    fn store_incoming(&mut self, id: NonZeroU16) -> std::io::Result<()>;
+
+    /// Forget the identifier of an incoming QoS 2 publish once the server released it
+    fn release_incoming(&mut self, id: NonZeroU16) -> std::io::Result<()>;
+
+    /// Wait until every change so far is persisted
+    ///
+    /// The client calls this once it disconnected on request. Stores that persist every change
+    /// right away have nothing left to do.
+    fn sync(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The state of a session as held by a [`SessionStore`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoredSession {
+    /// The encoded outgoing packets, by packet identifier
+    pub outgoing: BTreeMap<NonZeroU16, Vec<u8>>,
+
+    /// The identifiers of incoming QoS 2 publishes
+    pub incoming: BTreeSet<NonZeroU16>,
+}
+
+impl StoredSession {
+    pub fn is_empty(&self) -> bool {
+        self.outgoing.is_empty() && self.incoming.is_empty()
+    }
+}
+
+/// A session store that keeps the session in memory
+///
+/// The session survives reconnections and clients created from the same
+/// [`ConnectOptions`](crate::connect::ConnectOptions), but not a restart of the process.
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    session: StoredSession,
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&mut self) -> std::io::Result<StoredSession> {
+        Ok(self.session.clone())
+    }
+
+    fn store_outgoing(&mut self, id: NonZeroU16, packet: &[u8]) -> std::io::Result<()> {
+        self.session.outgoing.insert(id, packet.to_vec());
+        Ok(())
+    }
+
+    fn release_outgoing(&mut self, id: NonZeroU16) -> std::io::Result<()> {
+        self.session.outgoing.remove(&id);
+        Ok(())
+    }
+
+    fn store_incoming(&mut self, id: NonZeroU16) -> std::io::Result<()> {
+        self.session.incoming.insert(id);
+        Ok(())
+    }
+
+    fn release_incoming(&mut self, id: NonZeroU16) -> std::io::Result<()> {
+        self.session.incoming.remove(&id);
+        Ok(())
+    }
+}
+
+/// A session store that appends every change to a file
+///
+/// Changes are written and synced to disk on a thread of their own, so that the client is not
+/// held up by the disk. Changes that come in while a sync is going on are synced together with
+/// the next one. Use [`sync`](Self::sync) to wait until every change is on disk; dropping the
+/// store waits for it as well.
+///
+/// A publish is therefore already sent while it may not be on disk yet. Should the process stop
+/// in between, which is usually a matter of milliseconds, the publish is not sent again after a
+/// restart. The client syncs the store once it disconnected on request, so that the publishes
+/// still in flight then survive a restart.
+///
+/// Once the log holds many more records than the session it describes, it is compacted by
+/// atomically replacing it with a snapshot of the session.
+#[derive(Debug)]
+pub struct FileSessionStore {
+    path: PathBuf,
+    session: StoredSession,
+
+    /// How many records the log holds
+    records: usize,
+
+    /// Where the writer thread takes its work from, `None` once the store is dropped
+    commands: Option<std::sync::mpsc::Sender<LogCommand>>,
+    writer: Option<std::thread::JoinHandle<()>>,
+}
+
+/// Work for the thread writing the log
+#[derive(Debug)]
+enum LogCommand {
+    Append(Vec<u8>),
+
+    /// Replace the log with a snapshot of the session
+    Compact(StoredSession),
+
+    /// Tell once every earlier command is on disk
+    Sync(std::sync::mpsc::Sender<()>),
+}
+
+/// A log below this many records is never compacted
+const MINIMUM_RECORDS_TO_COMPACT: usize = 1024;
+
+const STORE_OUTGOING: u8 = 1;
+const RELEASE_OUTGOING: u8 = 2;
+const STORE_INCOMING: u8 = 3;
+const RELEASE_INCOMING: u8 = 4;
+
+impl FileSessionStore {
+    /// Open the log at `path`, creating it if it does not exist yet
+    ///
+    /// A record that was only partially written, because the process stopped while writing it,
+    /// is discarded.
+    pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+
+        let mut log = Vec::new();
+        match std::fs::File::open(&path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut log)?;
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+
+        let session = replay(&log)?;
+        let file = write_snapshot(&path, &session)?;
+
+        let (commands, received) = std::sync::mpsc::channel();
+        let writer = std::thread::Builder::new()
+            .name("cloudmqtt-session-log".to_string())
+            .spawn({
+                let path = path.clone();
+                move || write_log(&path, file, received)
+            })?;
+
+        Ok(Self {
+            records: session.outgoing.len() + session.incoming.len(),
+            path,
+            session,
+            commands: Some(commands),
+            writer: Some(writer),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Wait until every change so far is on disk
+    pub fn sync(&self) -> std::io::Result<()> {
+        let (synced, done) = std::sync::mpsc::channel();
+        self.send(LogCommand::Sync(synced))?;
+
+        done.recv().map_err(|_| writer_stopped())
+    }
+
+    fn send(&self, command: LogCommand) -> std::io::Result<()> {
+        self.commands
+            .as_ref()
+            .and_then(|commands| commands.send(command).ok())
+            .ok_or_else(writer_stopped)
+    }
+
+    fn append(&mut self, record: Vec<u8>) -> std::io::Result<()> {
+        self.send(LogCommand::Append(record))?;
+        self.records += 1;
+
+        let live = self.session.outgoing.len() + self.session.incoming.len();
+        if self.records >= MINIMUM_RECORDS_TO_COMPACT && self.records > 2 * live {
+            tracing::debug!(records = self.records, live, "Compacting session log");
+            self.send(LogCommand::Compact(self.session.clone()))?;
+            self.records = live;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for FileSessionStore {
+    fn drop(&mut self) {
+        // The writer thread stops once it wrote everything that was sent before
+        drop(self.commands.take());
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                tracing::error!("The session log writer panicked");
+            }
+        }
+    }
+}
+
+fn writer_stopped() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        "The session log writer stopped",
+    )
+}
+
+/// Write the log until the store is dropped
+///
+/// Everything that queued up while writing is synced at once.
+fn write_log(
+    path: &Path,
+    mut file: std::fs::File,
+    commands: std::sync::mpsc::Receiver<LogCommand>,
+) {
+    while let Ok(command) = commands.recv() {
+        let mut synced = Vec::new();
+
+        let queued = std::iter::from_fn(|| commands.try_recv().ok());
+        for command in std::iter::once(command).chain(queued) {
+            match command {
+                LogCommand::Append(record) => {
+                    if let Err(error) = file.write_all(&record) {
+                        tracing::error!(%error, "Could not append to the session log");
+                    }
+                }
+                LogCommand::Compact(session) => match write_snapshot(path, &session) {
+                    Ok(snapshot) => file = snapshot,
+                    Err(error) => tracing::error!(%error, "Could not compact the session log"),
+                },
+                LogCommand::Sync(done) => synced.push(done),
+            }
+        }
+
+        if let Err(error) = file.sync_data() {
+            tracing::error!(%error, "Could not sync the session log");
+        }
+        for done in synced {
+            let _ = done.send(());
+        }
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&mut self) -> std::io::Result<StoredSession> {
+        Ok(self.session.clone())
+    }
+
+    fn store_outgoing(&mut self, id: NonZeroU16, packet: &[u8]) -> std::io::Result<()> {
+        self.session.outgoing.insert(id, packet.to_vec());
+        self.append(outgoing_record(id, packet))
+    }
+
+    fn release_outgoing(&mut self, id: NonZeroU16) -> std::io::Result<()> {
+        if self.session.outgoing.remove(&id).is_none() {
+            return Ok(());
+        }
+
+        self.append(record(RELEASE_OUTGOING, id))
+    }
+
+    fn store_incoming(&mut self, id: NonZeroU16) -> std::io::Result<()> {
+        if !self.session.incoming.insert(id) {
+            return Ok(());
+        }
+
+        self.append(record(STORE_INCOMING, id))
+    }
+
+    fn release_incoming(&mut self, id: NonZeroU16) -> std::io::Result<()> {
+        if !self.session.incoming.remove(&id) {
+            return Ok(());
+        }
+
+        self.append(record(RELEASE_INCOMING, id))
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        FileSessionStore::sync(self)
+    }
+}
+
+fn record(kind: u8, id: NonZeroU16) -> Vec<u8> {
+    let mut record = vec![kind];
+    record.extend_from_slice(&id.get().to_be_bytes());
+    record
+}
+
+fn outgoing_record(id: NonZeroU16, packet: &[u8]) -> Vec<u8> {
+    let length = u32::try_from(packet.len()).expect("MQTT packets are smaller than 4 GiB");
+
+    let mut record = record(STORE_OUTGOING, id);
+    record.extend_from_slice(&length.to_be_bytes());
+    record.extend_from_slice(packet);
+    record
+}
+
+/// Rebuild the session from a log, ignoring a partially written last record
+fn replay(mut log: &[u8]) -> std::io::Result<StoredSession> {
+    fn take<'l>(log: &mut &'l [u8], length: usize) -> Option<&'l [u8]> {
+        let (taken, rest) = log.split_at_checked(length)?;
+        *log = rest;
+        Some(taken)
+    }
+
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid session log");
+    let mut session = StoredSession::default();
+
+    while let Some(header) = take(&mut log, 3) {
+        let id = NonZeroU16::new(u16::from_be_bytes([header[1], header[2]])).ok_or_else(invalid)?;
+
+        match header[0] {
+            STORE_OUTGOING => {
+                let Some(length) = take(&mut log, 4) else {
+                    break;
+                };
+                let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+                let Some(packet) = take(&mut log, length) else {
+                    break;
+                };
+                session.outgoing.insert(id, packet.to_vec());
+            }
+            RELEASE_OUTGOING => {
+                session.outgoing.remove(&id);
+            }
+            STORE_INCOMING => {
+                session.incoming.insert(id);
+            }
+            RELEASE_INCOMING => {
+                session.incoming.remove(&id);
+            }
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok(session)
+}
+
+/// Replace the log at `path` with one holding just the session, returning it opened for appending
+fn write_snapshot(path: &Path, session: &StoredSession) -> std::io::Result<std::fs::File> {
+    let mut snapshot = Vec::new();
+    for (id, packet) in session.outgoing.iter() {
+        snapshot.extend_from_slice(&outgoing_record(*id, packet));
+    }
+    for id in session.incoming.iter() {
+        snapshot.extend_from_slice(&record(STORE_INCOMING, *id));
+    }
+
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".compacting");
+    let temporary = PathBuf::from(temporary);
+
+    let mut file = std::fs::File::create(&temporary)?;
+    file.write_all(&snapshot)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&temporary, path)?;
+    sync_directory(path)?;
+
+    std::fs::OpenOptions::new().append(true).open(path)
+}
+
+/// Sync the directory holding `path`, so that a rename into it survives a crash
+#[cfg(unix)]
+fn sync_directory(path: &Path) -> std::io::Result<()> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    std::fs::File::open(directory)?.sync_all()
+}
+
+/// Directories cannot be opened to sync them here, the rename has to do
+#[cfg(not(unix))]
+fn sync_directory(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// A session store shared by every client created from the same options
+#[derive(Clone)]
+pub(crate) struct SharedSessionStore(Arc<Mutex<dyn SessionStore>>);
+
+impl SharedSessionStore {
+    pub(crate) fn new(store: impl SessionStore + 'static) -> Self {
+        Self(Arc::new(Mutex::new(store)))
+    }
+
+    /// Run an operation on the store, logging if it failed
+    ///
+    /// A failing store must not take the connection down, the session is still kept in memory.
+    pub(crate) fn update(
+        &self,
+        operation: impl FnOnce(&mut dyn SessionStore) -> std::io::Result<()>,
+    ) {
+        let mut store = self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if let Err(error) = operation(&mut *store) {
+            tracing::error!(%error, "Could not update the session store");
+        }
+    }
+
+    /// Wait until the store persisted every change so far, without holding up the runtime
+    pub(crate) async fn sync(&self) {
+        let store = self.clone();
+        let synced = tokio::task::spawn_blocking(move || store.update(|store| store.sync())).await;
+
+        if synced.is_err() {
+            tracing::error!("Syncing the session store panicked");
+        }
+    }
+
+    pub(crate) fn load(&self) -> StoredSession {
+        let mut store = self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        store.load().unwrap_or_else(|error| {
+            tracing::error!(%error, "Could not load the session store, starting without a session");
+            StoredSession::default()
+        })
+    }
+}
+
+impl std::fmt::Debug for SharedSessionStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedSessionStore").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU16;
+
+    use super::FileSessionStore;
+    use super::MemorySessionStore;
+    use super::SessionStore;
+    use super::StoredSession;
+
+    fn id(id: u16) -> NonZeroU16 {
+        NonZeroU16::new(id).unwrap()
+    }
+
+    struct TemporaryPath(std::path::PathBuf);
+
+    impl TemporaryPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("cloudmqtt-{name}-{}.session", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TemporaryPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn exercise(store: &mut dyn SessionStore) {
+        store.store_outgoing(id(1), b"publish 1").unwrap();
+        store.store_outgoing(id(2), b"publish 2").unwrap();
+        store.store_outgoing(id(1), b"pubrel 1").unwrap();
+        store.release_outgoing(id(2)).unwrap();
+        store.store_incoming(id(7)).unwrap();
+        store.store_incoming(id(8)).unwrap();
+        store.release_incoming(id(7)).unwrap();
+    }
+
+    fn exercised() -> StoredSession {
+        StoredSession {
+            outgoing: [(id(1), b"pubrel 1".to_vec())].into(),
+            incoming: [id(8)].into(),
+        }
+    }
+
+    #[test]
+    fn check_memory_store() {
+        let mut store = MemorySessionStore::default();
+        exercise(&mut store);
+
+        assert_eq!(store.load().unwrap(), exercised());
+    }
+
+    #[test]
+    fn check_file_store_survives_reopening() {
+        let path = TemporaryPath::new("reopen");
+
+        let mut store = FileSessionStore::open(&path.0).unwrap();
+        assert!(store.load().unwrap().is_empty());
+        exercise(&mut store);
+        drop(store);
+
+        let mut store = FileSessionStore::open(&path.0).unwrap();
+        assert_eq!(store.load().unwrap(), exercised());
+    }
+
+    #[test]
+    fn check_file_store_sync() {
+        let path = TemporaryPath::new("sync");
+
+        let mut store = FileSessionStore::open(&path.0).unwrap();
+        exercise(&mut store);
+        store.sync().unwrap();
+
+        // Everything is on disk while the store is still open
+        let log = std::fs::read(&path.0).unwrap();
+        assert_eq!(super::replay(&log).unwrap(), exercised());
+    }
+
+    #[test]
+    fn check_file_store_discards_partial_record() {
+        let path = TemporaryPath::new("partial");
+
+        let mut store = FileSessionStore::open(&path.0).unwrap();
+        exercise(&mut store);
+        drop(store);
+
+        // The process stopped halfway through appending a packet
+        let mut log = std::fs::read(&path.0).unwrap();
+        log.extend_from_slice(&super::outgoing_record(id(3), b"publish 3")[..8]);
+        std::fs::write(&path.0, log).unwrap();
+
+        let mut store = FileSessionStore::open(&path.0).unwrap();
+        assert_eq!(store.load().unwrap(), exercised());
+
+        store.store_outgoing(id(3), b"publish 3").unwrap();
+        drop(store);
+
+        let mut store = FileSessionStore::open(&path.0).unwrap();
+        assert_eq!(store.load().unwrap().outgoing.len(), 2);
+    }
+
+    #[test]
+    fn check_file_store_compacts() {
+        let path = TemporaryPath::new("compact");
+
+        let mut store = FileSessionStore::open(&path.0).unwrap();
+        store.store_incoming(id(9)).unwrap();
+        for _ in 0..super::MINIMUM_RECORDS_TO_COMPACT {
+            store.store_outgoing(id(1), b"publish").unwrap();
+            store.release_outgoing(id(1)).unwrap();
+        }
+
+        store.sync().unwrap();
+        let length = std::fs::metadata(&path.0).unwrap().len();
+        assert!(
+            length < 1024,
+            "The log was not compacted, it is {length} bytes"
+        );
+
+        drop(store);
+        let mut store = FileSessionStore::open(&path.0).unwrap();
+        assert_eq!(
+            store.load().unwrap(),
+            StoredSession {
+                outgoing: Default::default(),
+                incoming: [id(9)].into(),
+            }
+        );
+    }
+}
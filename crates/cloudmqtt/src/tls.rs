@@ -0,0 +1,295 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Connecting to servers over TLS
+
+use std::sync::Arc;
+
+pub use tokio_rustls::client::TlsStream;
+pub use tokio_rustls::rustls;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::rustls::RootCertStore;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+/// The port servers usually accept MQTT over TLS on
+pub const DEFAULT_TLS_PORT: u16 = 8883;
+
+/// How a client verifies the server, and authenticates itself, when connecting over TLS
+///
+/// The ALPN protocol `mqtt` is offered by default.
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    root_store: Arc<RootCertStore>,
+    client_certificate: Option<(Vec<CertificateDer<'static>>, Arc<PrivateKeyDer<'static>>)>,
+    server_name: Option<String>,
+    alpn_protocols: Vec<Vec<u8>>,
+}
+
+/// Trust the root certificates Mozilla trusts, as bundled by `webpki-roots`
+impl Default for TlsOptions {
+    fn default() -> Self {
+        Self::new(RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        })
+    }
+}
+
+impl TlsOptions {
+    /// Trust servers whose certificate chains up to one of the given roots
+    pub fn new(root_store: RootCertStore) -> Self {
+        Self {
+            root_store: Arc::new(root_store),
+            client_certificate: None,
+            server_name: None,
+            alpn_protocols: vec![b"mqtt".to_vec()],
+        }
+    }
+
+    /// Authenticate with a client certificate (mutual TLS)
+    ///
+    /// The chain starts with the certificate of the client, followed by its intermediates.
+    pub fn with_client_certificate(
+        mut self,
+        certificate_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+    ) -> Self {
+        self.client_certificate = Some((certificate_chain, Arc::new(private_key)));
+        self
+    }
+
+    /// The name to send through SNI and to verify the certificate of the server against
+    ///
+    /// By default the host of the address connected to is used.
+    pub fn with_server_name(mut self, server_name: impl Into<String>) -> Self {
+        self.server_name = Some(server_name.into());
+        self
+    }
+
+    /// The protocols to offer through ALPN, in order of preference
+    ///
+    /// Passing an empty list disables ALPN.
+    pub fn with_alpn_protocols(mut self, alpn_protocols: Vec<Vec<u8>>) -> Self {
+        self.alpn_protocols = alpn_protocols;
+        self
+    }
+
+    fn client_config(&self) -> Result<ClientConfig, rustls::Error> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(self.root_store.clone());
+
+        let mut config = match &self.client_certificate {
+            Some((certificate_chain, private_key)) => {
+                builder.with_client_auth_cert(certificate_chain.clone(), private_key.clone_key())?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.alpn_protocols.clone();
+
+        Ok(config)
+    }
+
+    /// Open a TCP connection to `address` and perform the TLS handshake over it
+    ///
+    /// The address is a `host:port` pair. This can be used as the connection factory of
+    /// [`CloudmqttClient::new_with_reconnect`](crate::CloudmqttClient::new_with_reconnect).
+    pub async fn connect(
+        &self,
+        address: &str,
+    ) -> std::io::Result<TlsStream<tokio::net::TcpStream>> {
+        let config = self
+            .client_config()
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidInput, error))?;
+
+        let server_name = self
+            .server_name
+            .as_deref()
+            .unwrap_or_else(|| host(address))
+            .to_string();
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidInput, error))?;
+
+        let stream = crate::connect_tcp(address).await?;
+
+        tracing::debug!(?server_name, "Performing TLS handshake");
+        tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await
+    }
+}
+
+/// The host part of a `host:port` address, without the brackets of an IPv6 address
+fn host(address: &str) -> &str {
+    let host = match address.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => host,
+        _ => address,
+    };
+
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio_rustls::rustls;
+    use tokio_rustls::rustls::RootCertStore;
+    use tokio_rustls::rustls::pki_types::CertificateDer;
+    use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+    use tokio_rustls::rustls::pki_types::PrivatePkcs8KeyDer;
+    use tokio_util::codec::Framed;
+
+    use super::TlsOptions;
+    use crate::CloudmqttClient;
+    use crate::codec::MqttPacketCodec;
+
+    struct Identity {
+        certificate: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+    }
+
+    fn certificate_authority() -> (rcgen::Certificate, rcgen::KeyPair) {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        (params.self_signed(&key).unwrap(), key)
+    }
+
+    fn issue(name: &str, ca: &(rcgen::Certificate, rcgen::KeyPair)) -> Identity {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let certificate = rcgen::CertificateParams::new(vec![name.to_string()])
+            .unwrap()
+            .signed_by(&key, &ca.0, &ca.1)
+            .unwrap();
+
+        Identity {
+            certificate: certificate.der().clone(),
+            key: PrivatePkcs8KeyDer::from(key.serialize_der()).into(),
+        }
+    }
+
+    fn roots(ca: &(rcgen::Certificate, rcgen::KeyPair)) -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.0.der().clone()).unwrap();
+        roots
+    }
+
+    #[test]
+    fn check_host() {
+        assert_eq!(super::host("broker.example.com:8883"), "broker.example.com");
+        assert_eq!(super::host("[::1]:8883"), "::1");
+        assert_eq!(super::host("broker.example.com"), "broker.example.com");
+    }
+
+    #[tokio::test]
+    async fn check_mutual_tls() {
+        let ca = certificate_authority();
+        let server_identity = issue("broker.internal", &ca);
+        let client_identity = issue("client", &ca);
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client_verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+            Arc::new(roots(&ca)),
+            provider.clone(),
+        )
+        .build()
+        .unwrap();
+        let mut server_config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(vec![server_identity.certificate], server_identity.key)
+            .unwrap();
+        server_config.alpn_protocols = vec![b"mqtt".to_vec()];
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let broker = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"mqtt"[..]));
+            assert_eq!(stream.get_ref().1.server_name(), Some("broker.internal"));
+            assert!(stream.get_ref().1.peer_certificates().is_some());
+
+            let mut server = Framed::new(stream, MqttPacketCodec);
+            let packet = server.next().await.unwrap().unwrap();
+            assert!(matches!(packet.get_packet(), FormatMqttPacket::Connect(..)));
+
+            server
+                .send(FormatMqttPacket::Connack(
+                    mqtt_format::v5::packets::connack::MConnack {
+                        session_present: false,
+                        reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                        properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                    },
+                ))
+                .await
+                .unwrap();
+            server
+        };
+
+        let options = TlsOptions::new(roots(&ca))
+            .with_server_name("broker.internal")
+            .with_client_certificate(vec![client_identity.certificate], client_identity.key);
+        let client = async {
+            let client = CloudmqttClient::new_with_tls_address(&address, options)
+                .await
+                .unwrap();
+            let mut events = client.connection_events();
+            loop {
+                match events.recv().await.unwrap() {
+                    crate::event::ConnectionEvent::Connecting => {}
+                    crate::event::ConnectionEvent::Connected(..) => break,
+                    event => panic!("Expected to be connected, got: {event:?}"),
+                }
+            }
+            client
+        };
+
+        let (_client, _server) = tokio::join!(client, broker);
+    }
+
+    #[tokio::test]
+    async fn check_untrusted_server() {
+        let ca = certificate_authority();
+        let other_ca = certificate_authority();
+        let server_identity = issue("localhost", &ca);
+
+        let server_config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![server_identity.certificate], server_identity.key)
+        .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("localhost:{}", listener.local_addr().unwrap().port());
+
+        let broker = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            assert!(acceptor.accept(stream).await.is_err());
+        };
+
+        let client =
+            CloudmqttClient::new_with_tls_address(&address, TlsOptions::new(roots(&other_ca)));
+
+        let (client, ()) = tokio::join!(client, broker);
+        assert!(matches!(client, Err(crate::error::Error::Connect(..))));
+    }
+}
@@ -183,6 +183,10 @@ pub fn len(&self) -> usize {
         self.levels.len() + self.levels.iter().map(|l| l.as_str().len()).sum::<usize>()
     }
 
+    pub fn levels(&self) -> &[TopicFilterLevel] {
+        &self.levels
+    }
+
     pub fn first(&self) -> &TopicFilterLevel {
         self.levels.first().unwrap()
     }
@@ -192,6 +196,19 @@ pub fn last(&self) -> &TopicFilterLevel {
     }
 }
 
+impl std::fmt::Display for TopicFilterBuf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, level) in self.levels.iter().enumerate() {
+            if index > 0 {
+                write!(f, "{TOPIC_LEVEL_SEPERATOR}")?;
+            }
+            f.write_str(level.as_str())?;
+        }
+
+        Ok(())
+    }
+}
+
 /// An owned MQTT Topic Name
 ///
 /// A topic name is denoted as a string like `"sport/tennis/player1/score"`. They are commonly used
@@ -241,6 +258,10 @@ pub fn len(&self) -> usize {
         self.levels.len() + self.levels.iter().map(|l| l.as_str().len()).sum::<usize>()
     }
 
+    pub fn levels(&self) -> &[TopicPath] {
+        &self.levels
+    }
+
     pub fn matches(&self, filter: &TopicFilterBuf) -> bool {
         if filter.first().is_multi_level_seperator() {
             return true;
@@ -281,6 +302,19 @@ pub fn matches(&self, filter: &TopicFilterBuf) -> bool {
     }
 }
 
+impl std::fmt::Display for TopicNameBuf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, level) in self.levels.iter().enumerate() {
+            if index > 0 {
+                write!(f, "{TOPIC_LEVEL_SEPERATOR}")?;
+            }
+            f.write_str(level.as_str())?;
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
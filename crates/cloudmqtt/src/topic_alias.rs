@@ -0,0 +1,182 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Topic aliases of a connection
+//!
+//! A topic alias stands in for the topic name of a publish, once it was sent together with it.
+//! Aliases only hold for the connection they were set on, and each direction has its own.
+
+use std::collections::HashMap;
+use std::num::NonZeroU16;
+
+use cloudmqtt_core::client::ProtocolViolation;
+use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+use mqtt_format::v5::packets::publish::MPublish;
+use mqtt_format::v5::variable_header::TopicAlias;
+
+use crate::codec::MqttPacket;
+
+#[derive(Debug, Default)]
+pub(crate) struct TopicAliases {
+    /// The highest alias the server may use, as announced in the CONNECT
+    inbound_maximum: u16,
+    inbound: HashMap<NonZeroU16, String>,
+
+    /// The highest alias the server accepts, as announced in its CONNACK
+    outbound_maximum: u16,
+    outbound: HashMap<String, NonZeroU16>,
+}
+
+impl TopicAliases {
+    pub(crate) fn new(inbound_maximum: u16) -> Self {
+        Self {
+            inbound_maximum,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn set_outbound_maximum(&mut self, outbound_maximum: u16) {
+        self.outbound_maximum = outbound_maximum;
+    }
+
+    /// The publish with its topic name, if the server left it out in favour of a topic alias
+    ///
+    /// Returns the violation to disconnect with if the server used an alias it must not use.
+    pub(crate) fn resolve(
+        &mut self,
+        publish: &MPublish<'_>,
+    ) -> Result<Option<MqttPacket>, ProtocolViolation> {
+        let Some(TopicAlias(alias)) = publish.properties.topic_alias().cloned() else {
+            return Ok(None);
+        };
+
+        if alias.get() > self.inbound_maximum {
+            return Err(ProtocolViolation::InvalidTopicAlias);
+        }
+
+        if !publish.topic_name.is_empty() {
+            self.inbound.insert(alias, publish.topic_name.to_string());
+            return Ok(None);
+        }
+
+        let Some(topic_name) = self.inbound.get(&alias) else {
+            return Err(ProtocolViolation::UnknownTopicAlias);
+        };
+
+        Ok(Some(MqttPacket::new(FormatMqttPacket::Publish(MPublish {
+            topic_name,
+            ..publish.clone()
+        }))))
+    }
+
+    /// Send the publish with a topic alias, if the server accepts one
+    ///
+    /// Topics get aliases in the order they are published to, until the server accepts no more.
+    /// Once a topic has an alias, its topic name is left out.
+    pub(crate) fn alias<'p>(&mut self, publish: MPublish<'p>) -> MPublish<'p> {
+        if publish.properties.topic_alias.is_some() {
+            return publish;
+        }
+
+        if let Some(alias) = self.outbound.get(publish.topic_name) {
+            return MPublish {
+                topic_name: "",
+                properties: mqtt_format::v5::packets::publish::PublishProperties {
+                    topic_alias: Some(TopicAlias(*alias)),
+                    ..publish.properties
+                },
+                ..publish
+            };
+        }
+
+        let next_alias = u16::try_from(self.outbound.len() + 1)
+            .ok()
+            .filter(|alias| *alias <= self.outbound_maximum)
+            .and_then(NonZeroU16::new);
+        let Some(alias) = next_alias else {
+            return publish;
+        };
+
+        self.outbound.insert(publish.topic_name.to_string(), alias);
+
+        MPublish {
+            properties: mqtt_format::v5::packets::publish::PublishProperties {
+                topic_alias: Some(TopicAlias(alias)),
+                ..publish.properties
+            },
+            ..publish
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cloudmqtt_core::client::ProtocolViolation;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use mqtt_format::v5::packets::publish::MPublish;
+    use mqtt_format::v5::packets::publish::PublishProperties;
+    use mqtt_format::v5::qos::QualityOfService;
+    use mqtt_format::v5::variable_header::TopicAlias;
+
+    use super::TopicAliases;
+
+    fn publish(topic_name: &str, alias: Option<u16>) -> MPublish<'_> {
+        MPublish {
+            duplicate: false,
+            quality_of_service: QualityOfService::AtMostOnce,
+            retain: false,
+            topic_name,
+            packet_identifier: None,
+            properties: PublishProperties {
+                topic_alias: alias.map(|alias| TopicAlias(alias.try_into().unwrap())),
+                ..PublishProperties::new()
+            },
+            payload: b"payload",
+        }
+    }
+
+    #[test]
+    fn check_resolve_inbound_aliases() {
+        let mut aliases = TopicAliases::new(2);
+
+        assert!(aliases.resolve(&publish("a/b", None)).unwrap().is_none());
+        assert!(aliases.resolve(&publish("a/b", Some(1))).unwrap().is_none());
+
+        let resolved = aliases.resolve(&publish("", Some(1))).unwrap().unwrap();
+        let FormatMqttPacket::Publish(resolved) = resolved.get_packet() else {
+            panic!("Expected a publish, got: {resolved:?}");
+        };
+        assert_eq!(resolved.topic_name, "a/b");
+        assert_eq!(
+            resolved.properties.topic_alias,
+            Some(TopicAlias(1.try_into().unwrap()))
+        );
+
+        assert_eq!(
+            aliases.resolve(&publish("", Some(2))).err(),
+            Some(ProtocolViolation::UnknownTopicAlias)
+        );
+        assert_eq!(
+            aliases.resolve(&publish("a/c", Some(3))).err(),
+            Some(ProtocolViolation::InvalidTopicAlias)
+        );
+    }
+
+    #[test]
+    fn check_alias_outbound_topics() {
+        let mut aliases = TopicAliases::new(0);
+
+        // Nothing is aliased before the server accepts aliases
+        assert_eq!(aliases.alias(publish("a/b", None)), publish("a/b", None));
+
+        aliases.set_outbound_maximum(1);
+        assert_eq!(aliases.alias(publish("a/b", None)), publish("a/b", Some(1)));
+        assert_eq!(aliases.alias(publish("a/b", None)), publish("", Some(1)));
+
+        // No alias is left for another topic
+        assert_eq!(aliases.alias(publish("a/c", None)), publish("a/c", None));
+    }
+}
@@ -0,0 +1,320 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Speaking MQTT through WebSockets
+//!
+//! MQTT packets are carried in binary WebSocket messages, negotiated through the `mqtt`
+//! subprotocol. A packet may be split across messages, or several packets sent in one.
+
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use std::task::ready;
+
+use futures::Sink;
+use futures::Stream;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_util::bytes::Buf;
+use tokio_util::bytes::Bytes;
+
+/// The WebSocket subprotocol of MQTT
+pub const SUBPROTOCOL: &str = "mqtt";
+
+/// A WebSocket connection, read and written as a plain byte stream
+///
+/// This can be given to [`CloudmqttClient::new_from_connection`](crate::CloudmqttClient::new_from_connection)
+/// and friends like any other connection.
+pub struct WebSocketTransport<S> {
+    inner: tokio_tungstenite::WebSocketStream<S>,
+
+    /// What is left of the last binary message received
+    read_buffer: Bytes,
+}
+
+impl<S> WebSocketTransport<S>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    /// Use a WebSocket connection that is already established
+    pub fn new(inner: tokio_tungstenite::WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read_buffer: Bytes::new(),
+        }
+    }
+
+    /// Perform the WebSocket handshake for `url` over an already open connection
+    pub async fn handshake(url: &str, stream: S) -> std::io::Result<Self> {
+        let mut request = url.into_client_request().map_err(into_io_error)?;
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(SUBPROTOCOL),
+        );
+
+        let (inner, response) = tokio_tungstenite::client_async(request, stream)
+            .await
+            .map_err(into_io_error)?;
+
+        if response.headers().get("Sec-WebSocket-Protocol")
+            != Some(&HeaderValue::from_static(SUBPROTOCOL))
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "The server did not accept the mqtt subprotocol",
+            ));
+        }
+
+        Ok(Self::new(inner))
+    }
+}
+
+impl WebSocketTransport<tokio::net::TcpStream> {
+    /// Connect to a `ws://` URL
+    pub async fn connect(url: &str) -> std::io::Result<Self> {
+        let address = address(url, "ws", 80)?;
+        let stream = crate::connect_tcp(&address).await?;
+
+        Self::handshake(url, stream).await
+    }
+}
+
+#[cfg(feature = "tls")]
+impl WebSocketTransport<crate::tls::TlsStream<tokio::net::TcpStream>> {
+    /// Connect to a `wss://` URL
+    pub async fn connect_tls(url: &str, tls: &crate::tls::TlsOptions) -> std::io::Result<Self> {
+        let address = address(url, "wss", 443)?;
+        let stream = tls.connect(&address).await?;
+
+        Self::handshake(url, stream).await
+    }
+}
+
+/// The `host:port` to open a connection to for the URL
+fn address(url: &str, scheme: &str, default_port: u16) -> std::io::Result<String> {
+    let request = url.into_client_request().map_err(into_io_error)?;
+    let uri = request.uri();
+
+    if uri.scheme_str() != Some(scheme) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Expected a {scheme}:// URL"),
+        ));
+    }
+
+    let host = uri.host().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "The URL has no host")
+    })?;
+
+    Ok(format!("{host}:{}", uri.port_u16().unwrap_or(default_port)))
+}
+
+fn into_io_error(error: tungstenite::Error) -> std::io::Error {
+    match error {
+        tungstenite::Error::Io(error) => error,
+        error => std::io::Error::other(error),
+    }
+}
+
+impl<S> tokio::io::AsyncRead for WebSocketTransport<S>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.read_buffer.is_empty() {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => this.read_buffer = data,
+                // Answering pings is taken care of by the WebSocket stream itself
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Received a text message, MQTT is only sent in binary messages",
+                    )));
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(error)) => return Poll::Ready(Err(into_io_error(error))),
+            }
+        }
+
+        let length = buf.remaining().min(this.read_buffer.len());
+        buf.put_slice(&this.read_buffer[..length]);
+        this.read_buffer.advance(length);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> tokio::io::AsyncWrite for WebSocketTransport<S>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+
+        ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(into_io_error)?;
+        Pin::new(&mut this.inner)
+            .start_send(Message::Binary(Bytes::copy_from_slice(buf)))
+            .map_err(into_io_error)?;
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(into_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_close(cx)
+            .map_err(into_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::tungstenite::handshake::server::Request;
+    use tokio_tungstenite::tungstenite::handshake::server::Response;
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+    use tokio_util::bytes::Bytes;
+
+    use super::WebSocketTransport;
+
+    #[test]
+    fn check_address() {
+        assert_eq!(
+            super::address("ws://broker.example.com/mqtt", "ws", 80).unwrap(),
+            "broker.example.com:80"
+        );
+        assert_eq!(
+            super::address("wss://broker.example.com:8084/mqtt", "wss", 443).unwrap(),
+            "broker.example.com:8084"
+        );
+        assert!(super::address("wss://broker.example.com/mqtt", "ws", 80).is_err());
+    }
+
+    fn encode(packet: FormatMqttPacket<'_>) -> Vec<u8> {
+        let mut buffer = tokio_util::bytes::BytesMut::new();
+        tokio_util::codec::Encoder::encode(&mut crate::codec::MqttPacketCodec, packet, &mut buffer)
+            .unwrap();
+        buffer.to_vec()
+    }
+
+    #[tokio::test]
+    async fn check_websocket_transport() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/mqtt", listener.local_addr().unwrap());
+
+        let broker = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server = tokio_tungstenite::accept_hdr_async(
+                stream,
+                |request: &Request, mut response: Response| {
+                    assert_eq!(request.uri().path(), "/mqtt");
+                    assert_eq!(
+                        request.headers().get("Sec-WebSocket-Protocol"),
+                        Some(&HeaderValue::from_static("mqtt"))
+                    );
+                    response
+                        .headers_mut()
+                        .insert("Sec-WebSocket-Protocol", HeaderValue::from_static("mqtt"));
+                    Ok(response)
+                },
+            )
+            .await
+            .unwrap();
+
+            // The CONNECT may arrive in any number of messages
+            let mut received = Vec::new();
+            let connect = loop {
+                let Message::Binary(data) = server.next().await.unwrap().unwrap() else {
+                    panic!("Expected a binary message");
+                };
+                received.extend_from_slice(&data);
+
+                let mut buffer = tokio_util::bytes::BytesMut::from(&received[..]);
+                if let Some(packet) = tokio_util::codec::Decoder::decode(
+                    &mut crate::codec::MqttPacketCodec,
+                    &mut buffer,
+                )
+                .unwrap()
+                {
+                    break packet;
+                }
+            };
+            assert!(matches!(
+                connect.get_packet(),
+                FormatMqttPacket::Connect(..)
+            ));
+
+            // Send the CONNACK split across two messages, and batched with a publish
+            let connack = encode(FormatMqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+                },
+            ));
+            let publish = encode(FormatMqttPacket::Publish(
+                mqtt_format::v5::packets::publish::MPublish {
+                    duplicate: false,
+                    quality_of_service: mqtt_format::v5::qos::QualityOfService::AtMostOnce,
+                    retain: false,
+                    topic_name: "a/b",
+                    packet_identifier: None,
+                    properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                    payload: b"hello",
+                },
+            ));
+
+            let (first, second) = connack.split_at(2);
+            server
+                .send(Message::Binary(Bytes::copy_from_slice(first)))
+                .await
+                .unwrap();
+            server
+                .send(Message::Binary(Bytes::from([second, &publish].concat())))
+                .await
+                .unwrap();
+
+            server
+        };
+
+        let client = async {
+            let transport = WebSocketTransport::connect(&url).await.unwrap();
+            let (incoming_sender, mut incoming_receiver) = tokio::sync::mpsc::channel(16);
+            let client = crate::client::CoreClient::new_and_connect(
+                transport,
+                incoming_sender,
+                crate::connect::ConnectOptions::default(),
+            );
+
+            let message: crate::message::Message = incoming_receiver.recv().await.unwrap();
+            assert_eq!(&message.payload()[..], b"hello");
+            client
+        };
+
+        let (_client, _server) = tokio::join!(client, broker);
+    }
+}
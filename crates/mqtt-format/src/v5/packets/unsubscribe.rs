@@ -7,6 +7,8 @@
 use winnow::Bytes;
 use winnow::Parser;
 use winnow::combinator::repeat_till;
+use winnow::error::ContextError;
+use winnow::error::ErrMode;
 
 use crate::v5::MResult;
 use crate::v5::properties::define_properties;
@@ -63,6 +65,10 @@ fn parse(input: &mut &'i Bytes) -> MResult<Unsubscriptions<'i>> {
         .parse_next(input)
     }
 
+    pub fn parse_complete(input: &[u8]) -> Result<Unsubscriptions<'_>, ErrMode<ContextError>> {
+        Unsubscriptions::parse(&mut Bytes::new(input))
+    }
+
     pub fn binary_size(&self) -> u32 {
         self.start.len() as u32
     }